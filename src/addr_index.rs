@@ -0,0 +1,265 @@
+extern crate serde_json;
+
+use super::block::Block;
+use super::db::DBBatch;
+use super::transaction::{self, TXOutput};
+use super::util;
+use super::utxo_set::SpentOutput;
+use super::wallet::Wallet;
+
+use std::collections::BTreeMap;
+
+// pub_key_hash|txid|vout => AddressOutput, the unspent outputs of every address
+pub const ADDR_UTXO_PREFIX: &'static str = "addr-utxo-";
+// pub_key_hash|height|txid => AddressTx, the confirmed transactions paying to or spending from
+// every address
+pub const ADDR_TX_PREFIX: &'static str = "addr-tx-";
+// at most ADDR_PAGE_SIZE entries are returned by one page
+pub const ADDR_PAGE_SIZE: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddressOutput {
+    pub txid: Vec<u8>,
+    pub vout: isize,
+    pub output: TXOutput,
+    // height of the block creating the output
+    pub height: isize,
+    pub is_coinbase: bool,
+}
+
+impl AddressOutput {
+    pub fn is_mature(&self, spend_height: isize) -> bool {
+        transaction::is_mature(self.height, self.is_coinbase, spend_height)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn deserialize(data: &[u8]) -> AddressOutput {
+        serde_json::from_slice(data).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressTx {
+    pub txid: String,
+    pub height: isize,
+}
+
+impl AddressTx {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn deserialize(data: &[u8]) -> AddressTx {
+        serde_json::from_slice(data).unwrap()
+    }
+}
+
+pub fn utxo_key(pub_key_hash: &[u8], txid: &[u8], vout: isize) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend_from_slice(txid);
+    key.extend(util::write_i64(vout as i64));
+    key
+}
+
+// the history of an address is ordered by height
+pub fn tx_key(pub_key_hash: &[u8], height: isize, txid: &[u8]) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend(util::write_i64(height as i64));
+    key.extend_from_slice(txid);
+    key
+}
+
+// the changes a block makes to the index, puts are written before deletes
+#[derive(Default)]
+pub struct IndexUpdate {
+    pub utxo_puts: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub utxo_deletes: Vec<Vec<u8>>,
+    pub tx_puts: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub tx_deletes: Vec<Vec<u8>>,
+}
+
+impl IndexUpdate {
+    // connecting block, which spends the outputs in spent
+    pub fn connect(block: &Block, spent: &[SpentOutput]) -> IndexUpdate {
+        let mut update = IndexUpdate::default();
+        for tx in &block.transactions {
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let output = AddressOutput {
+                    txid: tx.id.clone(),
+                    vout: vout as isize,
+                    output: out.clone(),
+                    height: block.height,
+                    is_coinbase: tx.is_coinbase(),
+                };
+                update.utxo_puts.insert(
+                    utxo_key(&out.pub_key_hash, &tx.id, vout as isize),
+                    output.serialize(),
+                );
+            }
+        }
+        // outputs created and spent in the block are deleted, puts go first
+        update.utxo_deletes = spent
+            .iter()
+            .map(|spent| utxo_key(&spent.output.pub_key_hash, &spent.txid, spent.vout))
+            .collect();
+        update.tx_puts = history(block);
+        update
+    }
+
+    // the reverse of connect
+    pub fn disconnect(block: &Block, spent: &[SpentOutput]) -> IndexUpdate {
+        let mut update = IndexUpdate::default();
+        for spent in spent {
+            let output = AddressOutput {
+                txid: spent.txid.clone(),
+                vout: spent.vout,
+                output: spent.output.clone(),
+                height: spent.height,
+                is_coinbase: spent.is_coinbase,
+            };
+            update.utxo_puts.insert(
+                utxo_key(&spent.output.pub_key_hash, &spent.txid, spent.vout),
+                output.serialize(),
+            );
+        }
+        for tx in &block.transactions {
+            for (vout, out) in tx.vout.iter().enumerate() {
+                update.utxo_deletes.push(utxo_key(&out.pub_key_hash, &tx.id, vout as isize));
+            }
+        }
+        update.tx_deletes = history(block).into_iter().map(|(key, _)| key).collect();
+        update
+    }
+
+    pub fn write(&self, batch: &mut DBBatch) {
+        for (key, value) in &self.utxo_puts {
+            batch.put_with_prefix(key, value, ADDR_UTXO_PREFIX);
+        }
+        self.utxo_deletes.iter().for_each(|key| batch.delete(key, ADDR_UTXO_PREFIX));
+        for (key, value) in &self.tx_puts {
+            batch.put_with_prefix(key, value, ADDR_TX_PREFIX);
+        }
+        self.tx_deletes.iter().for_each(|key| batch.delete(key, ADDR_TX_PREFIX));
+    }
+}
+
+// history entries of the block's transactions, for the addresses they pay and the ones they
+// spend from
+fn history(block: &Block) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    for tx in &block.transactions {
+        let entry = AddressTx {
+            txid: util::encode_hex(&tx.id),
+            height: block.height,
+        };
+        let mut pub_key_hashes: Vec<Vec<u8>> =
+            tx.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+        if !tx.is_coinbase() {
+            pub_key_hashes.extend(tx.vin.iter().map(|vin| Wallet::hash_pubkey(&vin.pub_key)));
+        }
+        for pub_key_hash in pub_key_hashes {
+            entries.insert(tx_key(&pub_key_hash, block.height, &tx.id), entry.serialize());
+        }
+    }
+    entries
+}
+
+// the entries before the page-th page, the first page is 0
+pub fn page_offset(page: usize) -> usize {
+    page.saturating_mul(ADDR_PAGE_SIZE)
+}
+
+// one page of the utxos or the history of an address
+#[derive(Serialize, Debug)]
+pub struct AddressPage<T> {
+    pub address: String,
+    pub page: usize,
+    pub page_size: usize,
+    // entries of every page
+    pub total: usize,
+    pub items: Vec<T>,
+}
+
+impl<T> AddressPage<T> {
+    pub fn new(address: &str, total: usize, items: Vec<T>, page_idx: usize) -> AddressPage<T> {
+        AddressPage {
+            address: address.to_owned(),
+            page: page_idx,
+            page_size: ADDR_PAGE_SIZE,
+            total: total,
+            items: items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::{Transaction, TXInput};
+
+    fn new_block(height: isize, transactions: Vec<Transaction>) -> Block {
+        Block {
+            header: Default::default(),
+            transactions: transactions,
+            hash: vec![height as u8; 32],
+            height: height,
+        }
+    }
+
+    fn new_tx(vin: Vec<TXInput>, pub_key_hash: u8) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vin,
+            vout: vec![TXOutput { value: 10, pub_key_hash: vec![pub_key_hash; 20] }],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    #[test]
+    fn spent_in_same_block() {
+        let funding = new_tx(vec![TXInput::new(vec![9; 32], 0, vec![], vec![5])], 1);
+        let spending = new_tx(vec![TXInput::new(funding.id.clone(), 0, vec![], vec![5])], 2);
+        let spent = vec![
+            SpentOutput {
+                txid: funding.id.clone(),
+                vout: 0,
+                output: funding.vout[0].clone(),
+                height: 1,
+                is_coinbase: false,
+            },
+        ];
+        let block = new_block(1, vec![funding.clone(), spending.clone()]);
+
+        let update = IndexUpdate::connect(&block, &spent);
+        let funding_key = utxo_key(&[1; 20], &funding.id, 0);
+        assert!(update.utxo_puts.contains_key(&funding_key));
+        assert!(update.utxo_deletes.contains(&funding_key));
+        assert!(update.utxo_puts.contains_key(&utxo_key(&[2; 20], &spending.id, 0)));
+        // both pay and the spender of the funding's input
+        assert!(update.tx_puts.contains_key(&tx_key(&[1; 20], 1, &funding.id)));
+        assert!(update.tx_puts.contains_key(&tx_key(&[2; 20], 1, &spending.id)));
+        let spender = Wallet::hash_pubkey(&[5]);
+        assert!(update.tx_puts.contains_key(&tx_key(&spender, 1, &spending.id)));
+
+        let undo = IndexUpdate::disconnect(&block, &spent);
+        assert!(undo.utxo_puts.contains_key(&funding_key));
+        assert!(undo.utxo_deletes.contains(&funding_key));
+        let mut history: Vec<Vec<u8>> = update.tx_puts.keys().cloned().collect();
+        history.sort();
+        let mut deletes = undo.tx_deletes.clone();
+        deletes.sort();
+        assert_eq!(history, deletes);
+    }
+
+    #[test]
+    fn history_ordered_by_height() {
+        let pub_key_hash = [3; 20];
+        assert!(tx_key(&pub_key_hash, 2, &[0xff; 32]) < tx_key(&pub_key_hash, 10, &[0; 32]));
+        assert_eq!(page_offset(2), 2 * ADDR_PAGE_SIZE);
+        assert_eq!(page_offset(usize::max_value()), usize::max_value());
+    }
+}
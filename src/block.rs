@@ -2,43 +2,110 @@ extern crate serde;
 extern crate serde_json;
 extern crate hex;
 
-extern crate sha2;
-extern crate time;
-
+use super::consensus;
+use super::mine::Miner;
 use super::proof_of_work;
 use super::transaction::*;
 use super::merkle_tree::MerkleTree;
+use super::timedata;
+use super::util;
+
+pub const BLOCK_VERSION: i32 = 1;
 
+// BlockHeader is hashed on its own, the transactions are committed by merkle_root
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct Block {
-    pub timestamp: i32,
+pub struct BlockHeader {
+    pub version: i32,
     pub prev_block_hash: Vec<u8>,
-    pub transactions: Vec<Transaction>,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: i32,
+    // compact target, see proof_of_work::compact_to_target
+    pub bits: u32,
     pub nonce: isize,
+    // the authority's signature of the hash, not hashed itself, see consensus
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl BlockHeader {
+    // |version|prev_block_hash|merkle_root|timestamp|bits|nonce|
+    pub fn prepare_data(&self, nonce: isize) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            4 + self.prev_block_hash.len() + self.merkle_root.len() + 4 + 4 + 8,
+        );
+        buf.extend(util::write_i32(self.version));
+        buf.extend(&self.prev_block_hash);
+        buf.extend(&self.merkle_root);
+        buf.extend(util::write_i32(self.timestamp));
+        buf.extend(util::write_u32(self.bits));
+        buf.extend(util::write_i64(nonce as i64));
+        buf
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        util::sha256(&self.prepare_data(self.nonce))
+    }
+
+    pub fn serialize(header: &BlockHeader) -> Vec<u8> {
+        serde_json::to_vec(header).unwrap()
+    }
+
+    pub fn deserialize_header(data: &[u8]) -> Self {
+        serde_json::from_slice(data).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
 
-    // hash = hash_fn(timestamp|prev_block|transactions|nonce), no include height
+    // hash = header.hash(), no include height
     pub hash: Vec<u8>,
     pub height: isize,
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Vec<u8>, height: isize) -> Block {
-        let block = Block {
-            timestamp: time::get_time().sec as i32,
-            prev_block_hash: prev_block_hash,
+    pub fn new(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Vec<u8>,
+        height: isize,
+        bits: u32,
+    ) -> Block {
+        let mut block = Self::new_template(transactions, prev_block_hash, height, bits);
+        // a genesis block sealed by nobody is fine, it is never validated
+        if !consensus::engine().seal(&mut block, &Miner::new(1)) {
+            block.hash = block.header.hash();
+        }
+        block
+    }
+
+    // an unsealed block, the seal and hash are filled in by the consensus engine
+    pub fn new_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Vec<u8>,
+        height: isize,
+        bits: u32,
+    ) -> Block {
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash: prev_block_hash,
+                timestamp: timedata::adjusted_time() as i32,
+                bits: bits,
+                ..Default::default()
+            },
             transactions: transactions,
             height: height,
             ..Default::default()
         };
-        let pow = proof_of_work::ProofOfWork::new_proof_of_work(&block);
-
-        let (nonce, hash) = pow.run();
-        return {
-            let mut block = pow.block.clone();
-            block.hash = hash;
-            block.nonce = nonce;
-            block
-        };
+        block.header.merkle_root = block.hash_transactions();
+        block
+    }
+
+    // every block has a coinbase, the transactions of a pruned block are gone
+    pub fn is_pruned(&self) -> bool {
+        self.transactions.is_empty()
     }
 
     pub fn serialize(block: &Block) -> Vec<u8> {
@@ -54,7 +121,7 @@ impl Block {
     }
 
     pub fn new_genesis_block(coinbase: Transaction) -> Self {
-        let block: Block = Block::new(vec![coinbase], vec![], 0);
+        let block: Block = Block::new(vec![coinbase], vec![], 0, proof_of_work::pow_limit_bits());
         block
     }
 
@@ -0,0 +1,444 @@
+use super::block::Block;
+use super::transaction::{Transaction, TXOutput};
+use super::util;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// room left in a block for the header and the coinbase
+pub const COINBASE_RESERVED_SIZE: usize = 4 * 1024;
+
+// the mempool transactions picked for a new block, the coinbase is not included
+#[derive(Debug, Default)]
+pub struct BlockTemplate {
+    // parents always come before their children
+    pub transactions: Vec<Transaction>,
+    pub fees: isize,
+    pub size: usize,
+}
+
+// the work handed to external miners, see MiningTemplate::new_block
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MiningTemplate {
+    pub version: i32,
+    pub height: isize,
+    pub prev_block_hash: String,
+    // the block's time must not be before min_timestamp
+    pub timestamp: i32,
+    pub min_timestamp: i32,
+    pub bits: u32,
+    pub target: String,
+    pub coinbase_address: String,
+    // subsidy and fees
+    pub coinbase_value: isize,
+    // the transactions after the coinbase, in block order
+    pub transactions: Vec<Transaction>,
+}
+
+impl MiningTemplate {
+    // a new unsealed block for every extranonce, it changes the coinbase and so the merkle
+    // root, miners move to the next extranonce once the nonces of the header are exhausted
+    pub fn new_block(&self, extranonce: u64) -> Block {
+        let coinbase = Transaction::new_coinbase_tx(
+            self.coinbase_address.clone(),
+            coinbase_data(self.height, extranonce),
+            self.coinbase_value,
+        );
+        let mut transactions = vec![coinbase];
+        transactions.extend(self.transactions.iter().cloned());
+        let mut block = Block::new_template(
+            transactions,
+            util::decode_hex(&self.prev_block_hash),
+            self.height,
+            self.bits,
+        );
+        block.header.version = self.version;
+        block.header.timestamp = cmp::max(block.header.timestamp, self.min_timestamp);
+        block
+    }
+}
+
+// the height keeps the coinbases of different blocks unique
+pub fn coinbase_data(height: isize, extranonce: u64) -> String {
+    format!("height:{} extranonce:{}", height, extranonce)
+}
+
+struct Entry {
+    tx: Transaction,
+    fee: isize,
+    size: usize,
+    // txids of the mempool transactions it spends
+    parents: Vec<String>,
+    // txids of the mempool transactions spending it
+    children: Vec<String>,
+    // fee and size of the transaction with its ancestors not in the block yet, updated as the
+    // ancestors are selected
+    package_fee: isize,
+    package_size: usize,
+}
+
+// build_template picks the ancestor package with the highest fee rate until the block is full.
+// lookup resolves an output outside the mempool, None if it is missing or can't be spent yet.
+// The signatures of the mempool transactions are checked by the caller. Equal fee rates are
+// ordered by txid, the same mempool always gives the same template.
+pub fn build_template<F>(
+    mem_pool: &HashMap<String, Transaction>,
+    max_size: usize,
+    lookup: F,
+) -> BlockTemplate
+where
+    F: Fn(&[u8], isize) -> Option<TXOutput>,
+{
+    let mut entries = new_entries(mem_pool, lookup);
+    let mut template = BlockTemplate::default();
+    let mut selected: HashSet<String> = HashSet::new();
+    let mut spent: HashSet<(Vec<u8>, isize)> = HashSet::new();
+
+    loop {
+        // (txid, fee, size) of the best package
+        let mut best: Option<(String, isize, usize)> = None;
+        for (txid, entry) in &entries {
+            if selected.contains(txid) {
+                continue;
+            }
+            let (fee, size) = (entry.package_fee, entry.package_size);
+            let better = match best {
+                Some((_, best_fee, best_size)) => {
+                    fee as i64 * best_size as i64 > best_fee as i64 * size as i64
+                }
+                None => true,
+            };
+            if better {
+                best = Some((txid.clone(), fee, size));
+            }
+        }
+
+        let (txid, fee, size) = match best {
+            Some(best) => best,
+            None => break,
+        };
+        let package = package(&entries, &selected, &txid);
+        if template.size + size > max_size || conflicts(&entries, &package, &spent) {
+            entries.remove(&txid);
+            drop_orphans(&mut entries);
+            continue;
+        }
+
+        for id in &package {
+            let entry = &entries[id];
+            entry.tx.vin.iter().for_each(|vin| {
+                spent.insert((vin.txid.clone(), vin.vout));
+            });
+            template.transactions.push(entry.tx.clone());
+            selected.insert(id.clone());
+        }
+        for id in &package {
+            remove_ancestor(&mut entries, &selected, id);
+        }
+        template.fees += fee;
+        template.size += size;
+    }
+    template
+}
+
+// resolves the inputs of every mempool transaction, drops the ones spending missing outputs
+fn new_entries<F>(mem_pool: &HashMap<String, Transaction>, lookup: F) -> BTreeMap<String, Entry>
+where
+    F: Fn(&[u8], isize) -> Option<TXOutput>,
+{
+    let mut entries = BTreeMap::new();
+    'txs: for (txid, tx) in mem_pool {
+        if tx.is_coinbase() {
+            continue;
+        }
+        let (mut input, mut parents) = (0, vec![]);
+        for vin in &tx.vin {
+            let parent_id = util::encode_hex(&vin.txid);
+            let prev_out = match mem_pool.get(&parent_id) {
+                Some(parent) => {
+                    parents.push(parent_id);
+                    parent.vout.get(vin.vout as usize).cloned()
+                }
+                None => lookup(&vin.txid, vin.vout),
+            };
+            match prev_out {
+                Some(out) => input += out.value,
+                None => continue 'txs,
+            }
+        }
+        let fee = input - tx.vout.iter().map(|out| out.value).sum::<isize>();
+        if fee < 0 {
+            continue;
+        }
+        parents.sort();
+        parents.dedup();
+        entries.insert(
+            txid.clone(),
+            Entry {
+                tx: tx.clone(),
+                fee: fee,
+                // a comma between the transactions of the block
+                size: tx.serialize().len() + 1,
+                parents: parents,
+                children: vec![],
+                package_fee: 0,
+                package_size: 0,
+            },
+        );
+    }
+
+    drop_orphans(&mut entries);
+    let txids: Vec<String> = entries.keys().cloned().collect();
+    for txid in &txids {
+        for parent in entries[txid].parents.clone() {
+            entries.get_mut(&parent).unwrap().children.push(txid.clone());
+        }
+        let package = package(&entries, &HashSet::new(), txid);
+        let fee = package.iter().map(|id| entries[id].fee).sum();
+        let size = package.iter().map(|id| entries[id].size).sum();
+        let entry = entries.get_mut(txid).unwrap();
+        entry.package_fee = fee;
+        entry.package_size = size;
+    }
+    entries
+}
+
+// the transaction is in the block, its descendants don't pay for it any more
+fn remove_ancestor(
+    entries: &mut BTreeMap<String, Entry>,
+    selected: &HashSet<String>,
+    txid: &str,
+) {
+    let (fee, size) = (entries[txid].fee, entries[txid].size);
+    let mut visited = HashSet::new();
+    let mut descendants = entries[txid].children.clone();
+    while let Some(descendant) = descendants.pop() {
+        if !visited.insert(descendant.clone()) {
+            continue;
+        }
+        // dropped with an ancestor
+        let entry = match entries.get_mut(&descendant) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if !selected.contains(&descendant) {
+            entry.package_fee -= fee;
+            entry.package_size -= size;
+        }
+        descendants.extend(entry.children.iter().cloned());
+    }
+}
+
+// children of dropped transactions can't be mined either
+fn drop_orphans(entries: &mut BTreeMap<String, Entry>) {
+    loop {
+        let orphans: Vec<String> = entries
+            .iter()
+            .filter(|&(_, entry)| {
+                entry.parents.iter().any(|parent| !entries.contains_key(parent))
+            })
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        if orphans.is_empty() {
+            break;
+        }
+        orphans.iter().for_each(|txid| { entries.remove(txid); });
+    }
+}
+
+// txid and its ancestors not in the block yet, parents before children
+fn package(
+    entries: &BTreeMap<String, Entry>,
+    selected: &HashSet<String>,
+    txid: &str,
+) -> Vec<String> {
+    let mut package = vec![];
+    let mut visited = HashSet::new();
+    visit(entries, selected, txid, &mut visited, &mut package);
+    package
+}
+
+fn visit(
+    entries: &BTreeMap<String, Entry>,
+    selected: &HashSet<String>,
+    txid: &str,
+    visited: &mut HashSet<String>,
+    package: &mut Vec<String>,
+) {
+    if selected.contains(txid) || !visited.insert(txid.to_owned()) {
+        return;
+    }
+    for parent in &entries[txid].parents {
+        visit(entries, selected, parent, visited, package);
+    }
+    package.push(txid.to_owned());
+}
+
+// whether the package spends an output twice or an output spent by the block
+fn conflicts(
+    entries: &BTreeMap<String, Entry>,
+    package: &[String],
+    spent: &HashSet<(Vec<u8>, isize)>,
+) -> bool {
+    let mut package_spent = HashSet::new();
+    package.iter().any(|txid| {
+        entries[txid].tx.vin.iter().any(|vin| {
+            let outpoint = (vin.txid.clone(), vin.vout);
+            spent.contains(&outpoint) || !package_spent.insert(outpoint)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::TXInput;
+    use wallet::Wallet;
+
+    fn new_tx(inputs: Vec<(&Transaction, isize)>, outputs: Vec<isize>) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: inputs
+                .into_iter()
+                .map(|(prev, vout)| TXInput::new(prev.id.clone(), vout, vec![], vec![]))
+                .collect(),
+            vout: outputs
+                .into_iter()
+                .map(|value| TXOutput { value: value, pub_key_hash: vec![1; 20] })
+                .collect(),
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    // a confirmed transaction with outputs of 100
+    fn confirmed(outputs: usize) -> Transaction {
+        new_tx(vec![], vec![100; outputs])
+    }
+
+    fn pool(txs: &[&Transaction]) -> HashMap<String, Transaction> {
+        txs.iter()
+            .map(|tx| (util::encode_hex(&tx.id), (*tx).clone()))
+            .collect()
+    }
+
+    fn build(
+        mem_pool: &HashMap<String, Transaction>,
+        max_size: usize,
+        utxo: &Transaction,
+    ) -> BlockTemplate {
+        build_template(mem_pool, max_size, |txid, vout| {
+            if util::compare_slice_u8(txid, &utxo.id) {
+                utxo.vout.get(vout as usize).cloned()
+            } else {
+                None
+            }
+        })
+    }
+
+    fn ids(template: &BlockTemplate) -> Vec<Vec<u8>> {
+        template.transactions.iter().map(|tx| tx.id.clone()).collect()
+    }
+
+    #[test]
+    fn higher_fee_rate_first() {
+        let utxo = confirmed(3);
+        let low = new_tx(vec![(&utxo, 0)], vec![99]);
+        let high = new_tx(vec![(&utxo, 1)], vec![90]);
+        let mid = new_tx(vec![(&utxo, 2)], vec![95]);
+        let template = build(&pool(&[&low, &high, &mid]), 1 << 20, &utxo);
+        assert_eq!(ids(&template), vec![high.id, mid.id, low.id]);
+        assert_eq!(template.fees, 16);
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let utxo = confirmed(2);
+        let parent = new_tx(vec![(&utxo, 0)], vec![100]);
+        let child = new_tx(vec![(&parent, 0)], vec![80]);
+        let other = new_tx(vec![(&utxo, 1)], vec![95]);
+        let template = build(&pool(&[&child, &other, &parent]), 1 << 20, &utxo);
+        assert_eq!(ids(&template), vec![parent.id, child.id, other.id]);
+        assert_eq!(template.fees, 25);
+    }
+
+    #[test]
+    fn stops_at_size_limit() {
+        let utxo = confirmed(2);
+        let low = new_tx(vec![(&utxo, 0)], vec![99]);
+        let high = new_tx(vec![(&utxo, 1)], vec![90]);
+        let max_size = high.serialize().len() + 1;
+        let template = build(&pool(&[&low, &high]), max_size, &utxo);
+        assert_eq!(ids(&template), vec![high.id]);
+        assert_eq!(template.size, max_size);
+    }
+
+    #[test]
+    fn package_after_parent_selected() {
+        let utxo = confirmed(2);
+        let parent = new_tx(vec![(&utxo, 0)], vec![50, 49]);
+        let high = new_tx(vec![(&parent, 0)], vec![20]);
+        // pays less than other with the parent, more once the parent is in the block
+        let low = new_tx(vec![(&parent, 1)], vec![44]);
+        let other = new_tx(vec![(&utxo, 1)], vec![96]);
+        let template = build(&pool(&[&parent, &high, &low, &other]), 1 << 20, &utxo);
+        assert_eq!(ids(&template), vec![parent.id, high.id, low.id, other.id]);
+        assert_eq!(template.fees, 40);
+    }
+
+    #[test]
+    fn skips_conflicts_and_missing_inputs() {
+        let utxo = confirmed(1);
+        let winner = new_tx(vec![(&utxo, 0)], vec![50]);
+        let loser = new_tx(vec![(&utxo, 0)], vec![90]);
+        let loser_child = new_tx(vec![(&loser, 0)], vec![10]);
+        let missing = new_tx(vec![(&confirmed(2), 0)], vec![1]);
+        let template = build(
+            &pool(&[&winner, &loser, &loser_child, &missing]),
+            1 << 20,
+            &utxo,
+        );
+        assert_eq!(ids(&template), vec![winner.id]);
+    }
+
+    #[test]
+    fn new_block_per_extranonce() {
+        let utxo = confirmed(1);
+        let tx = new_tx(vec![(&utxo, 0)], vec![90]);
+        let template = MiningTemplate {
+            height: 5,
+            prev_block_hash: util::encode_hex(&[7; 32]),
+            min_timestamp: i32::max_value() - 1,
+            bits: 0x207f_ffff,
+            coinbase_address: Wallet::new().get_address(),
+            coinbase_value: 20,
+            transactions: vec![tx.clone()],
+            ..MiningTemplate::default()
+        };
+        let first = template.new_block(0);
+        let second = template.new_block(1);
+        assert_ne!(first.header.merkle_root, second.header.merkle_root);
+        assert_ne!(first.transactions[0].id, second.transactions[0].id);
+        for block in &[&first, &second] {
+            assert_eq!(block.height, 5);
+            assert_eq!(block.header.prev_block_hash, vec![7; 32]);
+            assert_eq!(block.header.timestamp, template.min_timestamp);
+            assert_eq!(block.transactions[0].vout[0].value, 20);
+            assert_eq!(block.transactions[1].id, tx.id);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let utxo = confirmed(8);
+        let txs: Vec<Transaction> =
+            (0..8).map(|idx| new_tx(vec![(&utxo, idx)], vec![99])).collect();
+        let mut refs: Vec<&Transaction> = txs.iter().collect();
+        let first = ids(&build(&pool(&refs), 1 << 20, &utxo));
+        assert_eq!(first.len(), 8);
+        for _ in 0..4 {
+            refs.reverse();
+            assert_eq!(first, ids(&build(&pool(&refs), 1 << 20, &utxo)));
+        }
+    }
+}
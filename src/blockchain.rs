@@ -1,26 +1,102 @@
 extern crate secp256k1;
+extern crate bigint;
 
 use self::secp256k1::key::SecretKey;
+use self::bigint::U256;
 
+use super::addr_index;
 use super::block::*;
+use super::chain_params;
+use super::consensus;
+use super::proof_of_work;
+use super::prune::{self, PruneTarget};
 use super::transaction::*;
-use super::db::DBStore;
+use super::db::{DBBatch, DBStore};
+use super::mine::Miner;
+use super::timedata;
+use super::tx_index::{self, TxLocation, TX_INDEX_PREFIX};
 use super::util;
 use super::utxo_set;
+use super::validation::{self, RejectReason};
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 
 lazy_static! {
     static ref LAST_BLOCK_HASH_KEY:&'static [u8]  = b"last_block".as_ref();
-    static ref LAST_BLOCK_HASH_PREFIX:&'static str = "l-";
+    // the chain's metadata
+    pub static ref LAST_BLOCK_HASH_PREFIX:&'static str = "l-";
+    // set in the "l-" store once the transaction index is enabled
+    static ref TX_INDEX_KEY:&'static [u8] = b"txindex".as_ref();
+    // in the "l-" store, the best chain's blocks below the height have no transactions
+    static ref PRUNED_HEIGHT_KEY:&'static [u8] = b"pruned_height".as_ref();
     pub static ref BLOCK_PREFIX:&'static str  = "blocks";
-    static ref GENESIS_COINBASE_DATA:&'static str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+    // block hash => block header, light clients and headers first sync only need these
+    pub static ref HEADER_PREFIX:&'static str = "headers";
+    // block hash => cumulative work of the chain ending at the block
+    static ref CHAIN_WORK_PREFIX:&'static str = "work-";
+    // block hash => empty, the blocks without children, one for every branch
+    static ref TIP_PREFIX:&'static str = "tips-";
+    // block hash => BlockStatus, blocks on side branches that were never connected have none
+    pub static ref BLOCK_STATUS_PREFIX:&'static str = "status-";
+    // big endian height => hash of the best chain's block at the height
+    static ref HEIGHT_PREFIX:&'static str = "height-";
 }
 
 pub const DBFILE: &str = "{}/blockchain.db";
-pub const NEW_BLOCK_TIMEOUT: i32 = 60 * 20;
-pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+// at most MAX_HEADERS headers are returned by one headers request
+pub const MAX_HEADERS: usize = 2000;
+// at most MAX_BLOCKS blocks are returned by one blocks request
+pub const MAX_BLOCKS: usize = 100;
+
+// what add_block did with a valid block
+#[derive(Debug, PartialEq)]
+pub enum ChainUpdate {
+    // the block is already stored
+    Exists,
+    // stored on a branch with less work than the tip
+    SideBranch,
+    // the block extends the tip
+    Extended,
+    // stored on a branch with more work than the tip, the chain must be reorganized from
+    // the fork block to the new block
+    Reorganize { fork: Vec<u8> },
+}
+
+// what is known about the transactions of a stored block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockStatus {
+    // connected to the utxo set once, its transactions are valid
+    Connected = 1,
+    // its transactions failed to connect, the block and its descendants can't be best
+    Invalid = 2,
+}
+
+impl BlockStatus {
+    fn from_u8(status: u8) -> Option<BlockStatus> {
+        match status {
+            1 => Some(BlockStatus::Connected),
+            2 => Some(BlockStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+// the tip of a branch, see BlockChain::chain_tips
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainTip {
+    pub height: isize,
+    pub hash: String,
+    // blocks after the fork with the best chain, zero for the best tip
+    pub branch_len: isize,
+    // active: the best tip
+    // valid-fork: every block of the branch was connected once
+    // valid-headers: stored but not connected yet, the transactions are unchecked
+    // invalid: a block of the branch has invalid transactions
+    pub status: String,
+}
 
 // TODO add locker locks blockchain update
 pub struct BlockChain {
@@ -28,28 +104,59 @@ pub struct BlockChain {
     pub db: Arc<DBStore>,
 }
 
+// every store of the chain
+fn db_prefixes() -> Vec<String> {
+    vec![
+        (*LAST_BLOCK_HASH_PREFIX).to_string(),
+        (*BLOCK_PREFIX).to_string(),
+        (*HEADER_PREFIX).to_string(),
+        utxo_set::UTXO_BLOCK_PREFIX.to_string(),
+        utxo_set::UNDO_PREFIX.to_string(),
+        (*CHAIN_WORK_PREFIX).to_string(),
+        (*TIP_PREFIX).to_string(),
+        (*BLOCK_STATUS_PREFIX).to_string(),
+        (*HEIGHT_PREFIX).to_string(),
+        TX_INDEX_PREFIX.to_string(),
+        addr_index::ADDR_UTXO_PREFIX.to_string(),
+        addr_index::ADDR_TX_PREFIX.to_string(),
+    ]
+}
+
 impl BlockChain {
     // build a new block chain from genesis block
     pub fn create_blockchain(address: String, node: String) -> BlockChain {
-        let cbtx = Transaction::new_coinbase_tx(address, (*GENESIS_COINBASE_DATA).to_string());
+        let cbtx = Transaction::new_coinbase_tx(
+            address,
+            chain_params::params().genesis_coinbase_data,
+            block_subsidy(0),
+        );
         let genesis_block = Block::new_genesis_block(cbtx);
+        BlockChain::from_genesis(genesis_block, node)
+    }
 
+    // build a new block chain from a given genesis block, e.g. the first block of a bootstrap
+    // file
+    pub fn from_genesis(genesis_block: Block, node: String) -> BlockChain {
         let db_file = rt_format!(DBFILE, &node).unwrap();
-        let mut prefixs = Vec::<String>::new();
-        {
-            prefixs.push((*LAST_BLOCK_HASH_PREFIX).to_string());
-            prefixs.push((*BLOCK_PREFIX).to_string());
-            prefixs.push(utxo_set::UTXO_BLOCK_PREFIX.to_string());
-        }
+        let prefixs = db_prefixes();
         let db = DBStore::new(&db_file, prefixs);
 
         // store genesis_block into db
+        let mut batch = DBBatch::default();
         let value = Block::serialize(&genesis_block);
         let key = genesis_block.hash;
-        db.put_with_prefix(&key, &value, *BLOCK_PREFIX);
+        batch.put_with_prefix(&key, &value, *BLOCK_PREFIX);
+        let header = BlockHeader::serialize(&genesis_block.header);
+        batch.put_with_prefix(&key, &header, *HEADER_PREFIX);
+        let work = consensus::engine().block_weight(&genesis_block.header);
+        batch.put_with_prefix(&key, &util::u256_to_vec(&work), *CHAIN_WORK_PREFIX);
+        batch.put_with_prefix(&key, &[], *TIP_PREFIX);
+        batch.put_with_prefix(&key, &[BlockStatus::Connected as u8], *BLOCK_STATUS_PREFIX);
+        batch.put_with_prefix(&height_key(0), &key, *HEIGHT_PREFIX);
 
         // store last block hash into db
-        db.put_with_prefix(*LAST_BLOCK_HASH_KEY, &key, *LAST_BLOCK_HASH_PREFIX);
+        batch.put_with_prefix(*LAST_BLOCK_HASH_KEY, &key, *LAST_BLOCK_HASH_PREFIX);
+        db.write(batch);
 
         BlockChain {
             tip: Arc::new(Mutex::new(key)),
@@ -57,22 +164,40 @@ impl BlockChain {
         }
     }
 
+    // whether a block chain was created in the store
+    pub fn has_blockchain(node: &str) -> bool {
+        let db_file = rt_format!(DBFILE, node).unwrap();
+        let db = DBStore::new(&db_file, db_prefixes());
+        db.get_with_prefix(*LAST_BLOCK_HASH_KEY, *LAST_BLOCK_HASH_PREFIX).is_some()
+    }
+
     pub fn new_blockchain(node: String) -> BlockChain {
         let db_file = rt_format!(DBFILE, node).unwrap();
-        let mut prefixs = Vec::<String>::new();
-        {
-            prefixs.push((*LAST_BLOCK_HASH_PREFIX).to_string());
-            prefixs.push((*BLOCK_PREFIX).to_string());
-            prefixs.push(utxo_set::UTXO_BLOCK_PREFIX.to_string());
-        }
+        let prefixs = db_prefixes();
 
         let db = DBStore::new(&db_file, prefixs);
         let tip = db.get_with_prefix(*LAST_BLOCK_HASH_KEY, *LAST_BLOCK_HASH_PREFIX)
             .unwrap();
-        BlockChain {
+        let bc = BlockChain {
             tip: Arc::new(Mutex::new(tip)),
             db: Arc::new(db),
+        };
+        if bc.db.get_all_with_prefix(*TIP_PREFIX).is_empty() {
+            bc.reindex_tips();
+        }
+        // stores older than the height index
+        if bc.block_hash_at(bc.get_best_height()).is_none() {
+            let tip = bc.get_block(&bc.get_tip()).unwrap();
+            let mut batch = DBBatch::default();
+            bc.update_height_index(&tip, &mut batch);
+            bc.db.write(batch);
         }
+        bc
+    }
+
+    // the tip pointer in the store, the tip is loaded from it on startup
+    pub fn stored_tip(&self) -> Option<Vec<u8>> {
+        self.db.get_with_prefix(*LAST_BLOCK_HASH_KEY, *LAST_BLOCK_HASH_PREFIX)
     }
 
     pub fn last_block_hash(&self) -> String {
@@ -83,149 +208,541 @@ impl BlockChain {
         util::encode_hex(&last_hash)
     }
 
-    // TODO check block all transaction valid
-    pub fn add_block(&self, block: &Block) -> Result<(), String> {
+    // add_block stores a valid block whose parent is known, the tip doesn't move. The caller
+    // connects a block extending the tip with connect_block and reorganizes to a branch with
+    // more work. The transactions are checked against the utxo set by the caller, see
+    // validation::check_block_transactions
+    pub fn add_block(&self, block: &Block) -> Result<ChainUpdate, RejectReason> {
         if self.db
             .clone()
             .get_with_prefix(&block.hash, *BLOCK_PREFIX)
             .is_some()
         {
-            return Ok(());
+            return Ok(ChainUpdate::Exists);
         }
 
-        let last_hash = &self.db
-            .get_with_prefix(*LAST_BLOCK_HASH_KEY, *LAST_BLOCK_HASH_PREFIX)
-            .unwrap();
-        let last_block_data = &self.db.get_with_prefix(&last_hash, *BLOCK_PREFIX).unwrap();
-        let last_block = Block::deserialize_block(&last_block_data);
+        validation::check_block(block)?;
 
-        if block.height < last_block.height {
-            return Err(format!(
-                "block's height:'{} < {}' too small",
-                block.height,
-                last_block.height
-            ));
-        }
-        if block.height == last_block.height {
-            return Err("generate hard fork".to_string());
-        }
-        if block.height != last_block.height + 1 {
-            return Err(format!(
-                "block's height:'{} != {}' too big",
-                block.height,
-                last_block.height
-            ));
-        }
-        if block.timestamp <= last_block.timestamp {
-            return Err("block's time is less than last block's time".to_string());
-        }
-        if block.timestamp > last_block.timestamp + NEW_BLOCK_TIMEOUT {
-            return Err(
-                "block's time is more than new block generate's time".to_string(),
-            );
+        let parent = match self.get_block(&block.header.prev_block_hash) {
+            Some(parent) => parent,
+            None => {
+                return Err(RejectReason::UnknownParent {
+                    hash: util::encode_hex(&block.header.prev_block_hash),
+                })
+            }
+        };
+        if self.block_status(&parent.hash) == Some(BlockStatus::Invalid) {
+            return Err(RejectReason::InvalidParent { hash: util::encode_hex(&parent.hash) });
         }
 
-        if !util::compare_slice_u8(last_hash, &block.prev_block_hash) {
-            return Err(format!(
-                "{} not equal {}",
-                util::encode_hex(last_hash),
-                util::encode_hex(&block.prev_block_hash)
-            ));
+        if block.height != parent.height + 1 {
+            return Err(RejectReason::BadHeight {
+                height: block.height,
+                expected: parent.height + 1,
+            });
+        }
+        validation::check_checkpoint(block.height, &block.hash)?;
+        // the ancestors of a reached checkpoint are all stored, a new block at or below it
+        // forks before it
+        if let Some(checkpoint) = self.last_checkpoint() {
+            if block.height <= checkpoint {
+                return Err(RejectReason::ForkBeforeCheckpoint {
+                    height: block.height,
+                    checkpoint: checkpoint,
+                });
+            }
         }
+        validation::check_block_time(
+            block.header.timestamp,
+            &self.prev_timestamps(&parent),
+            timedata::adjusted_time(),
+        )?;
 
-        let block_data = Block::serialize(&block);
-        if block_data.len() > MAX_BLOCK_SIZE {
-            return Err(format!(
-                "block size tool big, more than max size, {} > {}",
-                block_data.len(),
-                MAX_BLOCK_SIZE
-            ));
+        let expected_bits = self.next_bits(&parent);
+        if block.header.bits != expected_bits {
+            return Err(RejectReason::BadBits {
+                bits: block.header.bits,
+                expected: expected_bits,
+            });
         }
 
+        let work = self.chain_work(&parent.hash) +
+            consensus::engine().block_weight(&block.header);
+        let tip = self.get_tip();
+        let update = if util::compare_slice_u8(&tip, &block.header.prev_block_hash) {
+            ChainUpdate::Extended
+        } else if work > self.chain_work(&tip) {
+            // blocks stored before the checkpoints were configured may still fork before them
+            let fork = self.find_fork(&tip, &parent.hash).unwrap();
+            self.check_fork(&fork)?;
+            ChainUpdate::Reorganize { fork: fork }
+        } else {
+            ChainUpdate::SideBranch
+        };
 
-        self.db.put_with_prefix(
+        let mut batch = DBBatch::default();
+        let block_data = Block::serialize(&block);
+        batch.put_with_prefix(
             &block.hash,
             &block_data,
             *BLOCK_PREFIX,
         );
-
-        &self.db.put_with_prefix(
-            *LAST_BLOCK_HASH_KEY,
+        batch.put_with_prefix(
             &block.hash,
-            *LAST_BLOCK_HASH_PREFIX,
+            &BlockHeader::serialize(&block.header),
+            *HEADER_PREFIX,
         );
-        let tip = self.tip.clone();
-        {
-            let mut tip = tip.lock().unwrap();
-            *tip = block.hash.clone();
-        }
+        batch.put_with_prefix(
+            &block.hash,
+            &util::u256_to_vec(&work),
+            *CHAIN_WORK_PREFIX,
+        );
+        batch.delete(&parent.hash, *TIP_PREFIX);
+        batch.put_with_prefix(&block.hash, &[], *TIP_PREFIX);
+        self.db.write(batch);
+        Ok(update)
+    }
 
-        Ok(())
+    pub fn block_status(&self, hash: &[u8]) -> Option<BlockStatus> {
+        self.db
+            .get_with_prefix(hash, *BLOCK_STATUS_PREFIX)
+            .and_then(|status| status.first().and_then(|status| BlockStatus::from_u8(*status)))
+    }
+
+    pub fn set_block_status(&self, hash: &[u8], status: BlockStatus) {
+        self.db.put_with_prefix(hash, &[status as u8], *BLOCK_STATUS_PREFIX);
+    }
+
+    // every branch, the best one first
+    pub fn chain_tips(&self) -> Vec<ChainTip> {
+        let best = self.get_tip();
+        let mut tips: Vec<ChainTip> = self.db
+            .get_all_with_prefix(*TIP_PREFIX)
+            .into_iter()
+            .filter_map(|(hash, _)| self.get_block(&hash))
+            .map(|tip| {
+                let fork_height = self.find_fork(&best, &tip.hash)
+                    .and_then(|fork| self.get_block(&fork))
+                    .map_or(0, |fork| fork.height);
+                let status = if util::compare_slice_u8(&tip.hash, &best) {
+                    "active"
+                } else {
+                    self.branch_status(&tip, fork_height)
+                };
+                ChainTip {
+                    height: tip.height,
+                    hash: util::encode_hex(&tip.hash),
+                    branch_len: tip.height - fork_height,
+                    status: status.to_owned(),
+                }
+            })
+            .collect();
+        tips.sort_by_key(|tip| (tip.status != "active", -tip.height));
+        tips
+    }
+
+    fn branch_status(&self, tip: &Block, fork_height: isize) -> &'static str {
+        let mut status = "valid-fork";
+        let mut block = tip.clone();
+        while block.height > fork_height {
+            match self.block_status(&block.hash) {
+                Some(BlockStatus::Invalid) => return "invalid",
+                Some(BlockStatus::Connected) => {}
+                None => status = "valid-headers",
+            }
+            block = match self.get_block(&block.header.prev_block_hash) {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        status
     }
 
-    // Notic: reindex utxos
-    // delete conflict block, including height's block
-    pub fn delete_conflict(&self, height: isize, prev_hash: Vec<u8>) {
-        let delete_hashes: Vec<Vec<u8>> = self.iter()
-            .filter(|block| block.height >= height)
-            .map(|block| block.hash)
+    // the tips are the stored blocks no other block builds on
+    pub fn reindex_tips(&self) {
+        let headers = self.db.get_all_with_prefix(*HEADER_PREFIX);
+        let parents: HashSet<Vec<u8>> = headers
+            .iter()
+            .map(|&(_, ref header)| BlockHeader::deserialize_header(header).prev_block_hash)
             .collect();
-        delete_hashes.into_iter().for_each(|hash| {
-            self.db.delete(&hash, *BLOCK_PREFIX)
+        headers
+            .iter()
+            .filter(|&&(ref hash, _)| !parents.contains(hash))
+            .for_each(|&(ref hash, _)| self.db.put_with_prefix(hash, &[], *TIP_PREFIX));
+    }
+
+    // height of the highest checkpoint whose block is stored
+    pub fn last_checkpoint(&self) -> Option<isize> {
+        chain_params::params()
+            .checkpoints
+            .iter()
+            .filter(|checkpoint| {
+                self.get_header(&util::decode_hex(&checkpoint.hash)).is_some()
+            })
+            .map(|checkpoint| checkpoint.height)
+            .max()
+    }
+
+    // the best chain can't switch to a branch forking before the last checkpoint, or below
+    // the pruned blocks which have no undo data
+    pub fn check_fork(&self, fork: &[u8]) -> Result<(), RejectReason> {
+        let height = self.get_block(fork).map_or(0, |block| block.height);
+        let pruned_height = self.pruned_height();
+        if height < pruned_height {
+            return Err(RejectReason::ForkBeforePruned {
+                height: height,
+                pruned_height: pruned_height,
+            });
+        }
+        match self.last_checkpoint() {
+            Some(checkpoint) if height < checkpoint => {
+                Err(RejectReason::ForkBeforeCheckpoint {
+                    height: height,
+                    checkpoint: checkpoint,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // the best chain's blocks below the height were pruned, 0 if none was
+    pub fn pruned_height(&self) -> isize {
+        self.db
+            .get_with_prefix(*PRUNED_HEIGHT_KEY, *LAST_BLOCK_HASH_PREFIX)
+            .map_or(0, |height| util::read_i64(&height) as isize)
+    }
+
+    // drops the transactions and the undo data of the old blocks the target doesn't keep,
+    // the headers stay. The new pruned height if blocks were pruned
+    pub fn prune(&self, target: &PruneTarget) -> Option<isize> {
+        let pruned_height = self.pruned_height();
+        let height = prune::prune_height(target, self.get_best_height(), pruned_height, |height| {
+            self.db
+                .get_with_prefix(&self.block_hash_at(height).unwrap_or_default(), *BLOCK_PREFIX)
+                .map_or(0, |data| data.len())
         });
-        let mut self_tip = self.tip.lock().unwrap();
-        *self_tip = prev_hash;
-        self.db.put_with_prefix(
-            *LAST_BLOCK_HASH_KEY,
-            &self_tip,
-            *LAST_BLOCK_HASH_PREFIX,
-        );
+        if height <= pruned_height {
+            return None;
+        }
+
+        let mut batch = DBBatch::default();
+        for block in self.blocks(pruned_height..height) {
+            let pruned = Block {
+                transactions: vec![],
+                ..block
+            };
+            batch.put_with_prefix(&pruned.hash, &Block::serialize(&pruned), *BLOCK_PREFIX);
+            batch.delete(&pruned.hash, utxo_set::UNDO_PREFIX);
+        }
+        batch.put_with_prefix(*PRUNED_HEIGHT_KEY, &height_key(height), *LAST_BLOCK_HASH_PREFIX);
+        self.db.write(batch);
+        Some(height)
     }
 
-    // Notic: reindex utxos
-    pub fn delete_blocks(&self, block_hash: &[u8], height: isize) -> Option<Vec<Vec<u8>>> {
-        let block_iter = self.iter();
-        let mut delete_hashes: Vec<Vec<u8>> = vec![];
-        for block in block_iter {
-            if block.height > height {
-                delete_hashes.push(block.hash.clone());
+    // moves the tip to any stored block, the utxo set is left to the caller
+    pub fn set_tip(&self, hash: &[u8]) {
+        let mut tip = self.tip.lock().unwrap();
+        let mut batch = DBBatch::default();
+        self.move_tip(&tip, hash, &mut batch);
+        self.db.write(batch);
+        *tip = hash.to_vec();
+    }
+
+    // connects block, a child of the tip. The tip, the utxo set, the undo data and the
+    // indexes are written in one batch, a crash leaves the block either connected or not
+    pub fn connect_block(&self, block: &Block, utxos: &utxo_set::UTXOSet) {
+        let mut tip = self.tip.lock().unwrap();
+        let mut batch = DBBatch::default();
+        self.move_tip(&tip, &block.hash, &mut batch);
+        utxos.update(block, &mut batch);
+        batch.put_with_prefix(&block.hash, &[BlockStatus::Connected as u8], *BLOCK_STATUS_PREFIX);
+        self.db.write(batch);
+        *tip = block.hash.clone();
+    }
+
+    // disconnects block, the tip, in one batch like connect_block
+    pub fn disconnect_block(&self, block: &Block, utxos: &utxo_set::UTXOSet) -> Result<(), String> {
+        let mut tip = self.tip.lock().unwrap();
+        let mut batch = DBBatch::default();
+        let parent = &block.header.prev_block_hash;
+        self.move_tip(&tip, parent, &mut batch);
+        utxos.disconnect(block, &mut batch)?;
+        self.db.write(batch);
+        *tip = parent.clone();
+        Ok(())
+    }
+
+    // the writes moving the tip from old_tip to new_tip, with the height and transaction
+    // indexes
+    fn move_tip(&self, old_tip: &[u8], new_tip: &[u8], batch: &mut DBBatch) {
+        batch.put_with_prefix(*LAST_BLOCK_HASH_KEY, new_tip, *LAST_BLOCK_HASH_PREFIX);
+        if let Some(block) = self.get_block(new_tip) {
+            self.update_height_index(&block, batch);
+        }
+        if self.tx_index_enabled() {
+            self.update_tx_index(old_tip, new_tip, batch);
+        }
+    }
+
+    // makes the height index follow the chain ending at tip. Connecting or disconnecting one
+    // block touches one entry
+    fn update_height_index(&self, tip: &Block, batch: &mut DBBatch) {
+        let indexed = |height: isize| {
+            batch.get_with_prefix(&self.db, &height_key(height), *HEIGHT_PREFIX)
+        };
+        let mut stale = vec![];
+        let mut height = tip.height + 1;
+        while indexed(height).is_some() {
+            stale.push(height);
+            height += 1;
+        }
+
+        let mut blocks = vec![];
+        let mut block = tip.clone();
+        loop {
+            if let Some(hash) = indexed(block.height) {
+                if util::compare_slice_u8(&hash, &block.hash) {
+                    break;
+                }
             }
-            if block.height == height {
-                if util::compare_slice_u8(block_hash, &block.hash) {
-                    delete_hashes = vec![];
-                } else {
-                    delete_hashes.push(block.hash.clone());
+            let parent = self.get_block(&block.header.prev_block_hash);
+            blocks.push(block);
+            block = match parent {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+
+        stale.iter().for_each(|height| batch.delete(&height_key(*height), *HEIGHT_PREFIX));
+        for block in blocks {
+            batch.put_with_prefix(&height_key(block.height), &block.hash, *HEIGHT_PREFIX);
+        }
+    }
+
+    pub fn tx_index_enabled(&self) -> bool {
+        self.db.get_with_prefix(*TX_INDEX_KEY, *LAST_BLOCK_HASH_PREFIX).is_some()
+    }
+
+    // turns the transaction index on and builds it, nothing to do if it's already on
+    pub fn enable_tx_index(&self) -> Result<(), String> {
+        if self.tx_index_enabled() {
+            return Ok(());
+        }
+        self.reindex_tx_index()
+    }
+
+    // rebuilds the transaction index from the best chain and turns it on
+    pub fn reindex_tx_index(&self) -> Result<(), String> {
+        // the transactions of the pruned blocks are gone
+        if self.pruned_height() > 0 {
+            return Err("can't index the transactions of a pruned chain".to_owned());
+        }
+        let tip = self.tip.lock().unwrap();
+        let mut batch = DBBatch::default();
+        for (txid, _) in self.db.get_all_with_prefix(TX_INDEX_PREFIX) {
+            batch.delete(&txid, TX_INDEX_PREFIX);
+        }
+        let height = self.get_block(&tip).map_or(-1, |block| block.height);
+        for block in self.blocks(0..height + 1) {
+            for (txid, location) in tx_index::block_entries(&block) {
+                batch.put_with_prefix(&txid, &location, TX_INDEX_PREFIX);
+            }
+        }
+        batch.put_with_prefix(*TX_INDEX_KEY, &[1], *LAST_BLOCK_HASH_PREFIX);
+        self.db.write(batch);
+        Ok(())
+    }
 
-                    let mut self_tip = self.tip.lock().unwrap();
-                    *self_tip = block.prev_block_hash.clone();
+    // moves the transaction index from the chain ending at old_tip to the one ending at
+    // new_tip
+    fn update_tx_index(&self, old_tip: &[u8], new_tip: &[u8], batch: &mut DBBatch) {
+        let fork = match self.find_fork(old_tip, new_tip) {
+            Some(fork) => fork,
+            None => return,
+        };
+        for block in self.branch(&fork, old_tip).iter().rev() {
+            block.transactions.iter().for_each(|tx| batch.delete(&tx.id, TX_INDEX_PREFIX));
+        }
+        for block in self.branch(&fork, new_tip) {
+            for (txid, location) in tx_index::block_entries(&block) {
+                batch.put_with_prefix(&txid, &location, TX_INDEX_PREFIX);
+            }
+        }
+    }
+
+    pub fn tx_location(&self, txid: &[u8]) -> Option<TxLocation> {
+        self.db
+            .get_with_prefix(txid, TX_INDEX_PREFIX)
+            .map(|location| TxLocation::deserialize(&location))
+    }
 
-                    &self.db.put_with_prefix(
-                        *LAST_BLOCK_HASH_KEY,
-                        &self_tip,
-                        *LAST_BLOCK_HASH_PREFIX,
-                    );
+    // hash of the best chain's block at height
+    pub fn block_hash_at(&self, height: isize) -> Option<Vec<u8>> {
+        if height < 0 {
+            return None;
+        }
+        self.db.get_with_prefix(&height_key(height), *HEIGHT_PREFIX)
+    }
+
+    pub fn block_at(&self, height: isize) -> Option<Block> {
+        self.block_hash_at(height).and_then(|hash| self.get_block(&hash))
+    }
+
+    // the best chain's blocks in the range, in height order
+    pub fn blocks(&self, range: Range<isize>) -> IterHeight {
+        IterHeight {
+            db: self.db.clone(),
+            next: cmp::max(range.start, 0),
+            end: range.end,
+        }
+    }
+
+    // cumulative work of the chain ending at the block
+    pub fn chain_work(&self, hash: &[u8]) -> U256 {
+        self.db
+            .get_with_prefix(hash, *CHAIN_WORK_PREFIX)
+            .map_or_else(U256::zero, |work| util::as_u256(&work))
+    }
+
+    pub fn get_best_work(&self) -> U256 {
+        self.chain_work(&self.get_tip())
+    }
+
+    // the common ancestor of two blocks
+    pub fn find_fork(&self, a: &[u8], b: &[u8]) -> Option<Vec<u8>> {
+        let (mut a, mut b) = (self.get_block(a)?, self.get_block(b)?);
+        while !util::compare_slice_u8(&a.hash, &b.hash) {
+            if a.height >= b.height {
+                a = self.get_block(&a.header.prev_block_hash)?;
+            } else {
+                b = self.get_block(&b.header.prev_block_hash)?;
+            }
+        }
+        Some(a.hash)
+    }
+
+    // blocks after fork up to and including tip, in height order
+    pub fn branch(&self, fork: &[u8], tip: &[u8]) -> Vec<Block> {
+        let mut blocks = vec![];
+        let mut hash = tip.to_vec();
+        while !util::compare_slice_u8(&hash, fork) {
+            match self.get_block(&hash) {
+                Some(block) => {
+                    hash = block.header.prev_block_hash.clone();
+                    blocks.push(block);
                 }
-                break;
+                None => break,
             }
         }
+        blocks.reverse();
+        blocks
+    }
 
-        delete_hashes.iter().for_each(|hash| {
-            self.db.delete(&hash, *BLOCK_PREFIX)
-        });
-        Some(delete_hashes)
+    // the bits of the block after parent, recalculated every retarget_interval blocks
+    // from the timestamps of the previous window
+    pub fn next_bits(&self, parent: &Block) -> u32 {
+        consensus::engine().next_bits(self, parent)
     }
 
-    // TODO optizme it
-    pub fn find_transaction(&self, id: &[u8]) -> Option<Transaction> {
-        let block_iter = self.iter();
-        for block in block_iter {
-            for transaction in &block.transactions {
-                if util::compare_slice_u8(&transaction.id, id) {
-                    return Some(transaction.clone());
+    // proof of work difficulty, see consensus::ProofOfWorkEngine
+    pub fn retarget_bits(&self, parent: &Block) -> u32 {
+        let params = chain_params::params();
+        if params.no_retargeting || (parent.height + 1) % params.retarget_interval != 0 {
+            return parent.header.bits;
+        }
+
+        // walk back to the first block of the window
+        let mut first = parent.clone();
+        for _ in 0..(params.retarget_interval - 1) {
+            match self.get_block(&first.header.prev_block_hash) {
+                Some(block) => first = block,
+                None => break,
+            }
+        }
+        proof_of_work::calculate_next_bits(
+            parent.header.bits,
+            first.header.timestamp,
+            parent.header.timestamp,
+        )
+    }
+
+    // timestamps of parent and its ancestors, newest first, enough for the median time past
+    pub fn prev_timestamps(&self, parent: &Block) -> Vec<i32> {
+        let mut timestamps = vec![parent.header.timestamp];
+        let mut hash = parent.header.prev_block_hash.clone();
+        while timestamps.len() < validation::MEDIAN_TIME_SPAN {
+            match self.get_header(&hash) {
+                Some(header) => {
+                    timestamps.push(header.timestamp);
+                    hash = header.prev_block_hash;
                 }
+                None => break,
             }
         }
-        None
+        timestamps
+    }
+
+    // a block after parent must be later than this
+    pub fn median_time_past(&self, parent: &Block) -> i32 {
+        validation::median_time_past(&self.prev_timestamps(parent))
+    }
+
+    // a clock behind the median time past must not produce invalid blocks
+    pub fn next_block_time(&self, parent: &Block, now: i32) -> i32 {
+        cmp::max(now, self.median_time_past(parent) + 1)
+    }
+
+    // the bits a block is expected to carry, genesis block uses the pow limit
+    pub fn expected_bits(&self, block: &Block) -> u32 {
+        if block.header.prev_block_hash.is_empty() {
+            return proof_of_work::pow_limit_bits();
+        }
+        self.get_block(&block.header.prev_block_hash).map_or(
+            proof_of_work::pow_limit_bits(),
+            |parent| self.next_bits(&parent),
+        )
+    }
+
+    pub fn find_transaction(&self, id: &[u8]) -> Option<Transaction> {
+        let found = self.find_transaction_block(id).ok().and_then(|found| found);
+        found.map(|(block, position)| block.transactions[position].clone())
+    }
+
+    // the best chain's block with the transaction and its position in the block, looked up in
+    // the transaction index if it's enabled. Err if the transaction may be in a pruned block
+    pub fn find_transaction_block(&self, id: &[u8]) -> Result<Option<(Block, usize)>, String> {
+        if self.tx_index_enabled() {
+            let location = match self.tx_location(id) {
+                Some(location) => location,
+                None => return Ok(None),
+            };
+            return match self.get_block(&location.block_hash) {
+                Some(ref block) if block.is_pruned() => Err(format!(
+                    "transaction {} is in the pruned block {}",
+                    util::encode_hex(id),
+                    util::encode_hex(&block.hash)
+                )),
+                Some(block) => Ok(Some((block, location.position))),
+                None => Ok(None),
+            };
+        }
+        for block in self.iter() {
+            // the older blocks are pruned too
+            if block.is_pruned() {
+                return Err(format!(
+                    "transaction {} isn't in the blocks from height {}, the ones below are pruned",
+                    util::encode_hex(id),
+                    block.height + 1
+                ));
+            }
+            let position = block.transactions.iter().position(|transaction| {
+                util::compare_slice_u8(&transaction.id, id)
+            });
+            if let Some(position) = position {
+                return Ok(Some((block, position)));
+            }
+        }
+        Ok(None)
     }
 
     // FindUTXO finds all unspent transaction outputs and returns transactions with spent outputs removed
@@ -234,7 +751,8 @@ impl BlockChain {
         let mut spent_txos: HashMap<String, Vec<isize>> = HashMap::new();
         let block_iter = self.iter();
         for block in block_iter {
-            for transaction in &block.transactions {
+            // backwards, later transactions may spend the outputs of earlier ones in the block
+            for transaction in block.transactions.iter().rev() {
                 let txid = &util::encode_hex(&transaction.id);
                 let mut out_idx = 0;
                 for vout in &transaction.vout {
@@ -252,7 +770,13 @@ impl BlockChain {
                     // Was the output spent
                     if !find {
                         utxo.entry(txid.clone())
-                            .or_insert(TXOutputs { outputs: Box::new(HashMap::new()) })
+                            .or_insert_with(|| {
+                                TXOutputs::new(
+                                    HashMap::new(),
+                                    block.height,
+                                    transaction.is_coinbase(),
+                                )
+                            })
                             .outputs
                             .insert(out_idx, vout.clone());
                     }
@@ -294,6 +818,30 @@ impl BlockChain {
         block_data.map(|v| Block::deserialize_block(&v))
     }
 
+    pub fn get_header(&self, block_hash: &[u8]) -> Option<BlockHeader> {
+        let header_data = self.db.get_with_prefix(block_hash, *HEADER_PREFIX);
+        header_data.map(|v| BlockHeader::deserialize_header(&v))
+    }
+
+    // headers of the best chain after locator, in height order, at most max headers.
+    // Starts from the genesis block if locator is not on the best chain
+    pub fn headers_after(&self, locator: &[u8], max: usize) -> Vec<BlockHeader> {
+        let mut headers = vec![];
+        let mut hash = self.get_tip();
+        while !util::compare_slice_u8(&hash, locator) {
+            match self.get_header(&hash) {
+                Some(header) => {
+                    hash = header.prev_block_hash.clone();
+                    headers.push(header);
+                }
+                None => break,
+            }
+        }
+        headers.reverse();
+        headers.truncate(max);
+        headers
+    }
+
     pub fn all_blocks(&self) -> Vec<Block> {
         let blocks_data = &self.db.get_all_with_prefix(*BLOCK_PREFIX);
         blocks_data
@@ -325,19 +873,24 @@ impl BlockChain {
             .unwrap();
         let last_block = Block::deserialize_block(&last_block_data);
         let last_height = last_block.height;
-        let new_block = Block::new(transactions.clone(), last_hash, last_height + 1);
-        let new_block_data = Block::serialize(&new_block);
-        self.add_block(&new_block).map(|_| new_block)
-    }
-
-    // mine_block2 not add new block, just generate new block
-    pub fn mine_block2(&self, transactions: &Vec<Transaction>) -> Result<Block, String> {
-        for tx in transactions {
-            if !self.verify_transaction(&tx) {
-                panic!("ERROR: Invalid transaction")
-            }
+        let bits = self.next_bits(&last_block);
+        let mut new_block = Block::new_template(transactions.clone(), last_hash, last_height + 1, bits);
+        new_block.header.timestamp = self.next_block_time(&last_block, new_block.header.timestamp);
+        if !consensus::engine().seal(&mut new_block, &Miner::new(1)) {
+            return Err(format!("can't seal block at height {}", new_block.height));
         }
+        self.add_block(&new_block).map(|_| new_block).map_err(
+            |e| e.to_string(),
+        )
+    }
 
+    // mine_block2 not add new block, just generate new block, the transactions come from a
+    // verified block template
+    pub fn mine_block2(
+        &self,
+        transactions: &Vec<Transaction>,
+        miner: &Miner,
+    ) -> Result<Block, String> {
         let last_hash = {
             self.tip.lock().unwrap().to_vec()
         };
@@ -347,7 +900,13 @@ impl BlockChain {
             .unwrap();
         let last_block = Block::deserialize_block(&last_block_data);
         let last_height = last_block.height;
-        Ok(Block::new(transactions.clone(), last_hash, last_height + 1))
+        let bits = self.next_bits(&last_block);
+        let mut block = Block::new_template(transactions.clone(), last_hash, last_height + 1, bits);
+        block.header.timestamp = self.next_block_time(&last_block, block.header.timestamp);
+        if !consensus::engine().seal(&mut block, miner) {
+            return Err(format!("mining block at height {} stopped", block.height));
+        }
+        Ok(block)
     }
 
     pub fn iter(&self) -> IterBlockchain {
@@ -368,12 +927,12 @@ impl BlockChain {
         tx: &mut Transaction,
         secret_key: &SecretKey,
     ) -> Result<(), String> {
-        let mut prev_txs: HashMap<isize, Transaction> = HashMap::new();
+        let mut prev_outs: HashMap<isize, TXOutput> = HashMap::new();
         let mut idx = 0;
 
         for vin in &tx.vin {
-            if let Some(prev_tx) = self.find_transaction(&vin.txid) {
-                prev_txs.insert(idx, prev_tx);
+            if let Some(prev_out) = self.prev_output(&vin.txid, vin.vout) {
+                prev_outs.insert(idx, prev_out);
             } else {
                 return Err(format!(
                     "not found the transation, txid:{}",
@@ -382,37 +941,60 @@ impl BlockChain {
             }
             idx += 1;
         }
-        tx.sign(&secret_key, &prev_txs);
+        tx.sign(&secret_key, &prev_outs);
         Ok(())
     }
 
+    // the output spent by an input, from the utxo set if it's unspent, the transactions of
+    // pruned blocks are gone
+    fn prev_output(&self, txid: &[u8], vout: isize) -> Option<TXOutput> {
+        let unspent = self.db
+            .get_with_prefix(txid, utxo_set::UTXO_BLOCK_PREFIX)
+            .and_then(|data| TXOutputs::deserialize_outputs(&data).outputs.get(&vout).cloned());
+        unspent.or_else(|| {
+            self.find_transaction(txid).and_then(|prev_tx| prev_tx.vout.get(vout as usize).cloned())
+        })
+    }
+
     // TODO why coinbase need not verify
     pub fn verify_transaction(&self, tx: &Transaction) -> bool {
         if tx.is_coinbase() {
             return true;
         }
 
-        let mut prevs_tx: HashMap<isize, Transaction> = HashMap::new();
-       /* let mut idx = 0;
-        for vin in &tx.vin {
-            let prev_tx = {
-                let res_pre_tx = self.find_transaction(&vin.txid);
-                if res_pre_tx.is_none() {
-                    return false;
-                }
-                res_pre_tx.unwrap()
-            };
-            prevs_tx.insert(idx, prev_tx);
-            idx += 1;
-        }*/
+        let mut prev_outs: HashMap<isize, TXOutput> = HashMap::new();
         for (idx, vin) in tx.vin.iter().enumerate() {
-            if let Some(prev_tx) = self.find_transaction(&vin.txid) {
-                prevs_tx.insert(idx as isize, prev_tx);
-            }else {
+            if let Some(prev_out) = self.prev_output(&vin.txid, vin.vout) {
+                prev_outs.insert(idx as isize, prev_out);
+            } else {
                 return false;
             }
         }
-        tx.verify(&prevs_tx)
+        tx.verify(&prev_outs)
+    }
+}
+
+fn height_key(height: isize) -> Vec<u8> {
+    util::write_i64(height as i64)
+}
+
+// iterates the best chain forward by height, see BlockChain::blocks
+pub struct IterHeight {
+    db: Arc<DBStore>,
+    next: isize,
+    end: isize,
+}
+
+impl Iterator for IterHeight {
+    type Item = Block;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let hash = self.db.get_with_prefix(&height_key(self.next), *HEIGHT_PREFIX)?;
+        let block_data = self.db.get_with_prefix(&hash, *BLOCK_PREFIX)?;
+        self.next += 1;
+        Some(Block::deserialize_block(&block_data))
     }
 }
 
@@ -439,7 +1021,7 @@ impl Iterator for IterBlockchain {
 
         let current_block = self.next.take().unwrap();
         let prev_block_data = self.db.get_with_prefix(
-            &current_block.prev_block_hash,
+            &current_block.header.prev_block_hash,
             *BLOCK_PREFIX,
         );
         if prev_block_data.is_some() {
@@ -450,3 +1032,162 @@ impl Iterator for IterBlockchain {
         Some(current_block)
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use self::tempdir::TempDir;
+
+    // a coinbase paying the subsidy of height to [tag; 20], the tag keeps branches apart
+    pub fn coinbase(height: isize, tag: u8) -> Transaction {
+        let data = format!("{}-{}", height, tag).into_bytes();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(vec![], -1, vec![], data)],
+            vout: vec![TXOutput { value: block_subsidy(height), pub_key_hash: vec![tag; 20] }],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    // a chain in dir from a genesis paying to [0; 20]
+    pub fn new_chain(dir: &TempDir) -> BlockChain {
+        let genesis = Block::new_genesis_block(coinbase(0, 0));
+        BlockChain::from_genesis(genesis, dir.path().to_str().unwrap().to_owned())
+    }
+
+    // a sealed child of parent, a second after it
+    pub fn child(parent: &Block, transactions: Vec<Transaction>) -> Block {
+        let height = parent.height + 1;
+        let mut block =
+            Block::new_template(transactions, parent.hash.clone(), height, parent.header.bits);
+        block.header.timestamp = parent.header.timestamp + 1;
+        assert!(consensus::engine().seal(&mut block, &Miner::new(1)));
+        block
+    }
+
+    #[test]
+    fn fork_choice_by_work() {
+        let dir = TempDir::new("fork_choice").unwrap();
+        let bc = new_chain(&dir);
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+
+        let b1 = child(&genesis, vec![coinbase(1, 1)]);
+        assert_eq!(bc.add_block(&b1), Ok(ChainUpdate::Extended));
+        assert_eq!(bc.add_block(&b1), Ok(ChainUpdate::Exists));
+        // stored, but the caller moves the tip
+        assert_eq!(bc.get_tip(), genesis.hash);
+        bc.set_tip(&b1.hash);
+
+        // the same work as the tip, the first seen block stays best
+        let a1 = child(&genesis, vec![coinbase(1, 2)]);
+        assert_eq!(bc.add_block(&a1), Ok(ChainUpdate::SideBranch));
+        let a2 = child(&a1, vec![coinbase(2, 2)]);
+        assert_eq!(
+            bc.add_block(&a2),
+            Ok(ChainUpdate::Reorganize { fork: genesis.hash.clone() })
+        );
+        bc.set_tip(&a2.hash);
+
+        // the old branch needs more work than the new tip
+        let b2 = child(&b1, vec![coinbase(2, 1)]);
+        assert_eq!(bc.add_block(&b2), Ok(ChainUpdate::SideBranch));
+        let b3 = child(&b2, vec![coinbase(3, 1)]);
+        assert_eq!(
+            bc.add_block(&b3),
+            Ok(ChainUpdate::Reorganize { fork: genesis.hash.clone() })
+        );
+
+        let orphan = child(&child(&a2, vec![coinbase(3, 3)]), vec![coinbase(4, 3)]);
+        assert!(match bc.add_block(&orphan) {
+            Err(RejectReason::UnknownParent { .. }) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn height_index_follows_tip() {
+        let dir = TempDir::new("height_index").unwrap();
+        let bc = new_chain(&dir);
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+        let hashes = |range: Range<isize>| -> Vec<Vec<u8>> {
+            bc.blocks(range).map(|block| block.hash).collect()
+        };
+
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        let a2 = child(&a1, vec![coinbase(2, 1)]);
+        let a3 = child(&a2, vec![coinbase(3, 1)]);
+        let b1 = child(&genesis, vec![coinbase(1, 2)]);
+        let b2 = child(&b1, vec![coinbase(2, 2)]);
+        for block in &[&a1, &a2, &a3, &b1, &b2] {
+            bc.add_block(block).unwrap();
+        }
+
+        // the tip jumps more than one block
+        bc.set_tip(&a3.hash);
+        let branch_a: Vec<Vec<u8>> =
+            [&genesis, &a1, &a2, &a3].iter().map(|block| block.hash.clone()).collect();
+        assert_eq!(hashes(0..10), branch_a);
+        assert_eq!(hashes(-5..2), branch_a[..2].to_vec());
+
+        // a shorter branch, the heights above it are stale
+        bc.set_tip(&b1.hash);
+        assert_eq!(hashes(0..10), vec![genesis.hash.clone(), b1.hash.clone()]);
+        assert_eq!(bc.block_hash_at(1), Some(b1.hash.clone()));
+        assert_eq!(bc.block_hash_at(2), None);
+        assert_eq!(bc.block_hash_at(3), None);
+
+        bc.set_tip(&b2.hash);
+        assert_eq!(hashes(1..10), vec![b1.hash.clone(), b2.hash.clone()]);
+        bc.set_tip(&a2.hash);
+        assert_eq!(hashes(0..10), branch_a[..3].to_vec());
+        assert_eq!(bc.block_hash_at(-1), None);
+    }
+
+    #[test]
+    fn tx_index_of_pruned_chain() {
+        let dir = TempDir::new("tx_index_pruned").unwrap();
+        let bc = new_chain(&dir);
+        assert_eq!(bc.enable_tx_index(), Ok(()));
+        assert!(bc.tx_index_enabled());
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+        assert!(bc.find_transaction(&genesis.transactions[0].id).is_some());
+
+        prune_block(&bc, &genesis);
+        assert!(bc.reindex_tx_index().is_err());
+        // already built
+        assert_eq!(bc.enable_tx_index(), Ok(()));
+        assert!(bc.find_transaction_block(&genesis.transactions[0].id).is_err());
+        assert!(bc.find_transaction_block(&[1; 32]).unwrap().is_none());
+    }
+
+    // drops the transactions of the block like prune, whatever the target
+    fn prune_block(bc: &BlockChain, block: &Block) {
+        let pruned = Block {
+            transactions: vec![],
+            ..block.clone()
+        };
+        bc.db.put_with_prefix(&pruned.hash, &Block::serialize(&pruned), *BLOCK_PREFIX);
+        let pruned_height = height_key(block.height + 1);
+        bc.db.put_with_prefix(*PRUNED_HEIGHT_KEY, &pruned_height, *LAST_BLOCK_HASH_PREFIX);
+    }
+
+    #[test]
+    fn transaction_of_pruned_block() {
+        let dir = TempDir::new("pruned_transaction").unwrap();
+        let bc = new_chain(&dir);
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+        let b1 = child(&genesis, vec![coinbase(1, 1)]);
+        assert_eq!(bc.add_block(&b1), Ok(ChainUpdate::Extended));
+        bc.set_tip(&b1.hash);
+        prune_block(&bc, &genesis);
+
+        let found = bc.find_transaction_block(&b1.transactions[0].id).unwrap();
+        assert_eq!(found.map(|(block, position)| (block.hash, position)), Some((b1.hash, 0)));
+        // not in the kept blocks, maybe in the pruned ones
+        assert!(bc.find_transaction_block(&genesis.transactions[0].id).is_err());
+        assert!(bc.find_transaction(&genesis.transactions[0].id).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+extern crate serde_json;
+
+use super::block::Block;
+use super::chain_params;
+use super::util;
+
+use std::io::{self, Read, Write};
+
+// a bootstrap file is a stream of records, one per block in chain order:
+// magic (4 bytes) | length (4 bytes) | serialized block (length bytes), big endian numbers.
+// The magic of the network keeps the files of other networks out
+pub const RECORD_HEADER_SIZE: usize = 8;
+
+pub fn write_block<W: Write>(writer: &mut W, block: &Block) -> io::Result<()> {
+    let data = Block::serialize(block);
+    writer.write_all(&util::write_u32(chain_params::params().magic))?;
+    writer.write_all(&util::write_u32(data.len() as u32))?;
+    writer.write_all(&data)
+}
+
+// reads the blocks of a bootstrap file one by one, it stops after the first error
+pub struct BlockReader<R: Read> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(reader: R) -> BlockReader<R> {
+        BlockReader {
+            reader: reader,
+            done: false,
+        }
+    }
+
+    // None at the end of the file
+    fn read_block(&mut self) -> Result<Option<Block>, String> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        let mut read = 0;
+        while read < RECORD_HEADER_SIZE {
+            match self.reader.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err("truncated record header".to_owned()),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let magic = util::read_u32(&header[..4]);
+        let expected = chain_params::params().magic;
+        if magic != expected {
+            return Err(format!("bad magic {:x}, expected: {:x}", magic, expected));
+        }
+        let len = util::read_u32(&header[4..]) as usize;
+        let max_block_size = chain_params::params().max_block_size;
+        if len > max_block_size {
+            return Err(format!("record of {} bytes, max: {}", len, max_block_size));
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data).map_err(|_| "truncated block".to_owned())?;
+        serde_json::from_slice(&data).map(Some).map_err(|e| format!("bad block, {}", e))
+    }
+}
+
+impl<R: Read> Iterator for BlockReader<R> {
+    type Item = Result<Block, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_block() {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_block(height: isize) -> Block {
+        Block {
+            header: Default::default(),
+            transactions: vec![],
+            hash: vec![height as u8; 32],
+            height: height,
+        }
+    }
+
+    #[test]
+    fn read_written_blocks() {
+        let mut file = vec![];
+        for height in 0..3 {
+            write_block(&mut file, &new_block(height)).unwrap();
+        }
+        let heights: Vec<isize> = BlockReader::new(&file[..])
+            .map(|block| block.unwrap().height)
+            .collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+
+        // a cut record is an error, and the last item
+        let cut = &file[..file.len() - 1];
+        let blocks: Vec<Result<Block, String>> = BlockReader::new(cut).collect();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[2].is_err());
+
+        let mut other_network = file.clone();
+        other_network[0] ^= 0xff;
+        let mut reader = BlockReader::new(&other_network[..]);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
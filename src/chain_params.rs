@@ -0,0 +1,300 @@
+extern crate bigint;
+extern crate hex;
+extern crate serde_json;
+
+use self::bigint::U256;
+
+use super::block_template::COINBASE_RESERVED_SIZE;
+use super::proof_of_work;
+use super::util;
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::RwLock;
+
+// the consensus engine of the chain, see consensus::engine
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Consensus {
+    ProofOfWork,
+    // hex encoded public keys of the authorities, they seal blocks in turn
+    ProofOfAuthority { authorities: Vec<String> },
+}
+
+// the block at height must have the hex encoded hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub height: isize,
+    pub hash: String,
+}
+
+impl Checkpoint {
+    pub fn matches(&self, hash: &[u8]) -> bool {
+        hex::decode(&self.hash).map_or(false, |checkpoint| checkpoint == hash)
+    }
+}
+
+// everything that defines a network, a chain-spec file holds the same fields in json, the
+// missing ones are taken from main
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChainParams {
+    pub name: String,
+    // sent in the version message, peers of other networks are refused
+    pub magic: u32,
+    pub consensus: Consensus,
+    // first byte of the addresses, addresses of other networks are invalid
+    pub address_version: u8,
+    // the data of the genesis coinbase
+    pub genesis_coinbase_data: String,
+    // the subsidy of the first blocks, halved every halving_interval blocks
+    pub initial_subsidy: isize,
+    pub halving_interval: isize,
+    // hard cap of the coins ever issued by coinbases
+    pub max_supply: isize,
+    // coinbase outputs can be spent coinbase_maturity blocks after the block creating them
+    pub coinbase_maturity: isize,
+    // compact form of the easiest allowed target, the genesis block uses it
+    pub pow_limit_bits: u32,
+    // retarget every retarget_interval blocks
+    pub retarget_interval: isize,
+    // expected seconds between two blocks
+    pub target_spacing: i32,
+    // keep the bits of the genesis block forever
+    pub no_retargeting: bool,
+    pub max_block_size: usize,
+    // blocks can be mined right away with generate
+    pub on_demand_mining: bool,
+    // the chain can't be reorganized before the last checkpoint it reached
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            name: "main".to_owned(),
+            magic: 0xd9b4_bef9,
+            consensus: Consensus::ProofOfWork,
+            address_version: 0x00,
+            genesis_coinbase_data: "The Times 03/Jan/2009 Chancellor on brink of second bailout \
+                                    for banks"
+                .to_owned(),
+            initial_subsidy: 10,
+            halving_interval: 1000,
+            max_supply: 18_000,
+            coinbase_maturity: 10,
+            // 1 << 240
+            pow_limit_bits: 0x1f01_0000,
+            retarget_interval: 10,
+            target_spacing: 60,
+            no_retargeting: false,
+            max_block_size: 1024 * 1024,
+            on_demand_mining: false,
+            checkpoints: vec![],
+        }
+    }
+
+    // a local chain for tests, about every second hash solves a block
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            name: "regtest".to_owned(),
+            magic: 0xdab5_bffa,
+            address_version: 0x6f,
+            pow_limit_bits: 0x207f_ffff,
+            no_retargeting: true,
+            on_demand_mining: true,
+            ..ChainParams::main()
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<ChainParams> {
+        match name {
+            "main" => Some(ChainParams::main()),
+            "regtest" => Some(ChainParams::regtest()),
+            _ => None,
+        }
+    }
+
+    // reads a chain-spec file
+    pub fn load(path: &str) -> Result<ChainParams, String> {
+        let mut data = vec![];
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("read chain spec {} fail, {}", path, e))?;
+        let params: ChainParams = serde_json::from_slice(&data).map_err(|e| {
+            format!("invalid chain spec {}, {}", path, e)
+        })?;
+        params.check()?;
+        Ok(params)
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.halving_interval <= 0 || self.retarget_interval <= 0 || self.target_spacing <= 0 {
+            return Err(
+                "halving_interval, retarget_interval and target_spacing must be positive"
+                    .to_owned(),
+            );
+        }
+        if self.initial_subsidy < 0 || self.max_supply < 0 || self.coinbase_maturity < 0 {
+            return Err(
+                "initial_subsidy, max_supply and coinbase_maturity can't be negative".to_owned(),
+            );
+        }
+        // a retarget clamps the actual timespan to timespan * 4, see
+        // proof_of_work::calculate_next_bits
+        let max_timespan = (self.retarget_interval as i64)
+            .checked_mul(i64::from(self.target_spacing))
+            .and_then(|timespan| timespan.checked_mul(4));
+        let max_timespan = match max_timespan {
+            Some(max_timespan) if max_timespan <= i64::from(i32::max_value()) => max_timespan,
+            _ => {
+                return Err(
+                    "target_spacing * retarget_interval * 4 must fit into an i32".to_owned(),
+                )
+            }
+        };
+        // the target is multiplied by the actual timespan before the division, the product must
+        // fit into 256 bits
+        let overflow =
+            Err(format!("pow_limit_bits {:#x} overflows a retarget", self.pow_limit_bits));
+        if self.pow_limit_bits >> 24 > 32 {
+            return overflow;
+        }
+        let pow_limit = proof_of_work::compact_to_target(self.pow_limit_bits);
+        let retargets = !self.no_retargeting && self.consensus == Consensus::ProofOfWork;
+        if retargets && pow_limit.bits() + U256::from(max_timespan as u64).bits() > 256 {
+            return overflow;
+        }
+        // the room of the mempool transactions is what the coinbase leaves
+        if self.max_block_size <= COINBASE_RESERVED_SIZE {
+            return Err(format!("max_block_size must be above {}", COINBASE_RESERVED_SIZE));
+        }
+        if let Consensus::ProofOfAuthority { ref authorities } = self.consensus {
+            if authorities.is_empty() {
+                return Err("proof of authority needs at least one authority".to_owned());
+            }
+            for authority in authorities {
+                let is_key = hex::decode(authority).map_or(false, |key| util::is_public_key(&key));
+                if !is_key {
+                    return Err(format!("authority {} is not a public key", authority));
+                }
+            }
+        }
+        for checkpoint in &self.checkpoints {
+            if checkpoint.height < 0 || hex::decode(&checkpoint.hash).is_err() {
+                return Err(format!("invalid checkpoint {:?}", checkpoint));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn checkpoint(&self, height: isize) -> Option<&Checkpoint> {
+        self.checkpoints.iter().find(|checkpoint| checkpoint.height == height)
+    }
+
+    // expected seconds of a retarget window
+    pub fn target_timespan(&self) -> i32 {
+        self.target_spacing * self.retarget_interval as i32
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::main()
+    }
+}
+
+lazy_static! {
+    static ref PARAMS: RwLock<ChainParams> = RwLock::new(ChainParams::main());
+}
+
+// the parameters of the chain the node runs, set once at startup
+pub fn params() -> ChainParams {
+    #[cfg(test)]
+    {
+        if let Some(params) = tests::thread_params() {
+            return params;
+        }
+    }
+    PARAMS.read().unwrap().clone()
+}
+
+pub fn set_params(params: ChainParams) {
+    *PARAMS.write().unwrap() = params;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        // the tests run in parallel, the ones needing other params set them for their thread
+        static THREAD_PARAMS: RefCell<Option<ChainParams>> = RefCell::new(None);
+    }
+
+    pub fn set_thread_params(params: ChainParams) {
+        THREAD_PARAMS.with(|thread_params| *thread_params.borrow_mut() = Some(params));
+    }
+
+    pub fn thread_params() -> Option<ChainParams> {
+        THREAD_PARAMS.with(|thread_params| thread_params.borrow().clone())
+    }
+
+    // the compressed generator point of secp256k1
+    const AUTHORITY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn chain_spec_defaults_to_main() {
+        let spec = r#"{"name": "private", "magic": 7, "initial_subsidy": 50,
+                       "consensus": {"ProofOfAuthority": {"authorities": ["AUTHORITY"]}}}"#;
+        let spec = spec.replace("AUTHORITY", AUTHORITY);
+        let params: ChainParams = serde_json::from_str(&spec).unwrap();
+        assert_eq!(params.name, "private");
+        assert_eq!(params.magic, 7);
+        assert_eq!(params.initial_subsidy, 50);
+        assert_eq!(
+            params.consensus,
+            Consensus::ProofOfAuthority { authorities: vec![AUTHORITY.to_owned()] }
+        );
+        assert_eq!(params.halving_interval, ChainParams::main().halving_interval);
+        assert_eq!(params.pow_limit_bits, ChainParams::main().pow_limit_bits);
+        assert!(params.check().is_ok());
+
+        let params: ChainParams = serde_json::from_str(r#"{"retarget_interval": 0}"#).unwrap();
+        assert!(params.check().is_err());
+        let params: ChainParams = serde_json::from_str(r#"{"max_block_size": 4096}"#).unwrap();
+        assert!(params.check().is_err());
+        // timespan * 4 overflows an i32
+        let spec = r#"{"retarget_interval": 10000000, "target_spacing": 60}"#;
+        let params: ChainParams = serde_json::from_str(spec).unwrap();
+        assert!(params.check().is_err());
+        // the target times the timespan overflows 256 bits, unless there's no retarget
+        let params: ChainParams = serde_json::from_str(r#"{"pow_limit_bits": 545259519}"#).unwrap();
+        assert_eq!(params.pow_limit_bits, 0x207f_ffff);
+        assert!(params.check().is_err());
+        assert!(ChainParams::regtest().check().is_ok());
+        let params: ChainParams = serde_json::from_str(r#"{"pow_limit_bits": 570425344}"#).unwrap();
+        assert!(params.check().is_err());
+        let spec = r#"{"checkpoints": [{"height": 1, "hash": "00ff"}]}"#;
+        let params: ChainParams = serde_json::from_str(spec).unwrap();
+        assert!(params.check().is_ok());
+        assert_eq!(params.checkpoint(1).map(|checkpoint| &checkpoint.hash[..]), Some("00ff"));
+        assert!(params.checkpoint(2).is_none());
+
+        let spec = r#"{"checkpoints": [{"height": 1, "hash": "not hex"}]}"#;
+        let params: ChainParams = serde_json::from_str(spec).unwrap();
+        assert!(params.check().is_err());
+    }
+
+    #[test]
+    fn authorities_are_public_keys() {
+        let mut params = ChainParams::main();
+        for authorities in &[vec![], vec!["04ab"], vec![AUTHORITY, "not hex"]] {
+            let authorities = authorities.iter().map(|key| key.to_string()).collect();
+            params.consensus = Consensus::ProofOfAuthority { authorities: authorities };
+            assert!(params.check().is_err());
+        }
+        params.consensus = Consensus::ProofOfAuthority { authorities: vec![AUTHORITY.to_owned()] };
+        assert!(params.check().is_ok());
+    }
+}
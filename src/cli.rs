@@ -3,6 +3,7 @@ extern crate slog_term;
 extern crate prettytable;
 extern crate typemap;
 extern crate chan;
+extern crate serde;
 extern crate serde_json;
 
 use self::prettytable::Table;
@@ -13,18 +14,29 @@ use super::util;
 use super::log::*;
 use super::wallets::Wallets;
 use super::wallet::Wallet;
-use super::blockchain::BlockChain;
+use super::blockchain::{BlockChain, ChainUpdate};
+use super::block::BlockHeader;
+use super::block_template::MiningTemplate;
+use super::mine::Miner;
 use super::utxo_set::UTXOSet;
-use super::proof_of_work::ProofOfWork;
+use super::chain_params::{self, ChainParams, Consensus};
+use super::consensus;
+use super::validation;
+use super::verify;
 use super::transaction;
+use super::tx_index;
+use super::bootstrap;
+use super::prune::PruneTarget;
 use super::router;
 use super::server;
 use super::pool;
 use super::command;
 
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
 use std::cell::RefCell;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::thread;
 use std::ops::Fn;
@@ -75,7 +87,7 @@ pub fn create_blockchain(address: &str, node: &str) -> Result<(), String> {
     let blockchain = BlockChain::create_blockchain(address.to_string(), node.to_string());
     info!(LOG, "block chain disk data create successfully.");
     let ref_bc = Arc::new(blockchain);
-    UTXOSet::new(Arc::clone(&ref_bc)).reindex();
+    UTXOSet::new(Arc::clone(&ref_bc)).reindex()?;
     let last_hash = ref_bc.last_block_hash();
     info!(LOG, "utxoset reindexs successfully.");
     info!(LOG, "genius block {}", last_hash);
@@ -94,12 +106,91 @@ pub fn list_address(node: &str) -> Result<Vec<String>, String> {
     Ok(wallets.list_address())
 }
 
-pub fn download_chain(node: &str) -> Result<(), String> {
+// writes the best chain's blocks from height from to to, both included, into a new
+// bootstrap file
+pub fn export_chain(node: &str, file: &str, from: isize, to: Option<isize>) -> Result<(), String> {
     let block_chain = BlockChain::new_blockchain(node.to_string());
-    let bcs = block_chain.all_blocks();
+    let to = to.unwrap_or_else(|| block_chain.get_best_height());
+    let pruned_height = block_chain.pruned_height();
+    if from < pruned_height {
+        return Err(format!("ERROR: the blocks below height {} are pruned", pruned_height));
+    }
+    if from < 0 || from > to || to > block_chain.get_best_height() {
+        return Err(format!("ERROR: invalid block range {}..{}", from, to));
+    }
+
+    let out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(file)
+        .map_err(|e| format!("ERROR: create {} fail, {}", file, e))?;
+    let mut writer = BufWriter::new(out);
+    let mut count = 0;
+    for block in block_chain.blocks(from..to + 1) {
+        bootstrap::write_block(&mut writer, &block).map_err(|e| e.to_string())?;
+        count += 1;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    info!(LOG, "export {} blocks into {}", count, file);
+    println!("Done! Exported the blocks {}..{} into {}.", from, to, file);
     Ok(())
 }
 
+// adds the blocks of a bootstrap file, each is fully validated like a block from a peer. A
+// new store starts from the genesis block of the file. The counts of the imported and the
+// already known blocks
+pub fn import_chain(node: &str, file: &str) -> Result<(usize, usize), String> {
+    let input = File::open(file).map_err(|e| format!("ERROR: open {} fail, {}", file, e))?;
+    let mut blocks = bootstrap::BlockReader::new(BufReader::new(input)).peekable();
+
+    if !BlockChain::has_blockchain(node) {
+        let genesis = match blocks.next() {
+            Some(Ok(ref genesis)) if genesis.height == 0 => genesis.clone(),
+            Some(Err(e)) => return Err(format!("ERROR: {}", e)),
+            _ => return Err("ERROR: the file doesn't start with a genesis block".to_owned()),
+        };
+        validation::check_block(&genesis).map_err(|e| format!("ERROR: {}", e))?;
+        let block_chain = Arc::new(BlockChain::from_genesis(genesis, node.to_string()));
+        UTXOSet::new(block_chain).reindex().map_err(|e| format!("ERROR: {}", e))?;
+    }
+    let bc_lock = router::BlockLock::load(BlockChain::new_blockchain(node.to_string()), None)
+        .map_err(|e| format!("ERROR: {}", e))?;
+    let genesis_hash = bc_lock.block_chain().block_hash_at(0);
+    if let Some(&Ok(ref first)) = blocks.peek() {
+        if first.height == 0 && Some(&first.hash) != genesis_hash.as_ref() {
+            return Err("ERROR: the file is of another chain".to_owned());
+        }
+    }
+
+    let (mut imported, mut known) = (0, 0);
+    for block in blocks {
+        let block = block.map_err(|e| format!("ERROR: {}", e))?;
+        match bc_lock.add_new_block(&block) {
+            Ok(ChainUpdate::Exists) => known += 1,
+            Ok(_) => imported += 1,
+            Err(e) => {
+                return Err(format!(
+                    "ERROR: block {} at height {} is invalid, {}",
+                    util::encode_hex(&block.hash),
+                    block.height,
+                    e
+                ))
+            }
+        }
+        if (imported + known) % 1000 == 0 {
+            info!(LOG, "import {} blocks, height {}", imported, block.height);
+        }
+    }
+    info!(LOG, "import {} blocks, {} known", imported, known);
+    println!(
+        "Done! Imported {} blocks, {} were known, the best height is {}.",
+        imported,
+        known,
+        bc_lock.best_height()
+    );
+    Ok((imported, known))
+}
+
 pub fn print_chain(node: &str) -> Result<(), String> {
     let block_chain = BlockChain::new_blockchain(node.to_string());
     let chain_iter = block_chain.iter();
@@ -110,20 +201,22 @@ pub fn print_chain(node: &str) -> Result<(), String> {
             Cell::new("Height"),
             Cell::new("Nonce"),
             Cell::new("PrevBlock"),
-            Cell::new("Pow"),
+            Cell::new("Seal"),
+            Cell::new("Bits"),
             Cell::new("timestamp"),
         ]));
         block_table.add_row(Row::new(vec![
             Cell::new(&util::encode_hex(&block.hash)),
             Cell::new(&format!("{}", &block.height)),
-            Cell::new(&format!("{}", &block.nonce)),
-            Cell::new(&util::encode_hex(&block.prev_block_hash)),
+            Cell::new(&format!("{}", &block.header.nonce)),
+            Cell::new(&util::encode_hex(&block.header.prev_block_hash)),
             Cell::new(&format!(
                 "{:?}",
-                ProofOfWork::new_proof_of_work(&block.clone())
-                    .validate()
+                block.header.bits == block_chain.expected_bits(&block) &&
+                    consensus::engine().verify_seal(&block).is_ok()
             )),
-            Cell::new(&format!("{}", &block.timestamp)),
+            Cell::new(&format!("{:x}", &block.header.bits)),
+            Cell::new(&format!("{}", &block.header.timestamp)),
         ]));
         (0..3).for_each(|_| println!());
         println!("Block");
@@ -148,7 +241,7 @@ pub fn print_chain(node: &str) -> Result<(), String> {
             out_table.printstd();
             *tx_number.borrow_mut() += 1;
         });
-        if block.prev_block_hash.is_empty() {
+        if block.header.prev_block_hash.is_empty() {
             break;
         }
     }
@@ -158,7 +251,7 @@ pub fn print_chain(node: &str) -> Result<(), String> {
 pub fn reindex_utxo(node: &str) -> Result<(), String> {
     let block_chain = Arc::new(BlockChain::new_blockchain(node.to_string()));
     let utxo = UTXOSet::new(block_chain);
-    utxo.reindex();
+    utxo.reindex().map_err(|e| format!("ERROR: {}", e))?;
     let count = utxo.count_transactions();
     info!(
         LOG,
@@ -169,6 +262,16 @@ pub fn reindex_utxo(node: &str) -> Result<(), String> {
     Ok(())
 }
 
+// rebuilds the transaction index, it's kept up to date from now on
+pub fn reindex_tx(node: &str) -> Result<(), String> {
+    let block_chain = BlockChain::new_blockchain(node.to_string());
+    block_chain.reindex_tx_index().map_err(|e| format!("ERROR: {}", e))?;
+    let count = block_chain.db.get_all_with_prefix(tx_index::TX_INDEX_PREFIX).len();
+    info!(LOG, "Done! There are {:?} transactions in the transaction index.", count);
+    println!("Done! There are {} transactions in the transaction index.", count);
+    Ok(())
+}
+
 pub fn get_utxo(txid: &str, node: &str) -> Result<(), String> {
     let block_chain = BlockChain::new_blockchain(node.to_string());
     let utxos = block_chain.db.get_all_with_prefix("utxo-");
@@ -195,14 +298,10 @@ pub fn get_balance(address: &str, node: &str) -> Result<(), String> {
     let block_chain = Arc::new(BlockChain::new_blockchain(node.to_string()));
     let utxo = UTXOSet::new(Arc::clone(&block_chain));
 
-    let mut balance = 0;
     let pub_key_hash = util::decode_base58(address.to_string());
     let pub_key_hash = &pub_key_hash[1..(pub_key_hash.len() - 4)];
-    let utxos = utxo.find_utxo(pub_key_hash);
-    for out in utxos {
-        balance += out.value;
-    }
-    info!(LOG, "Balance of {}: {}", address, balance);
+    let (balance, immature) = utxo.balance(pub_key_hash);
+    info!(LOG, "Balance of {}: {}, immature: {}", address, balance, immature);
     Ok(())
 }
 
@@ -225,6 +324,87 @@ pub fn get_balances(wallet_store: &str, node: &str) -> Result<(), String> {
     Ok(())
 }
 
+pub fn get_chain_tips(node: &str) -> Result<(), String> {
+    let block_chain = BlockChain::new_blockchain(node.to_string());
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Height"),
+        Cell::new("Hash"),
+        Cell::new("BranchLen"),
+        Cell::new("Status"),
+    ]));
+    for tip in block_chain.chain_tips() {
+        table.add_row(Row::new(vec![
+            Cell::new(&format!("{}", tip.height)),
+            Cell::new(&tip.hash),
+            Cell::new(&format!("{}", tip.branch_len)),
+            Cell::new(&tip.status),
+        ]));
+    }
+    table.printstd();
+    Ok(())
+}
+
+// prints the report of verify::verify_chain, false if something is wrong
+pub fn verify_chain(node: &str, level: usize, depth: isize) -> Result<bool, String> {
+    if level > verify::MAX_VERIFY_LEVEL {
+        return Err(format!(
+            "ERROR: level {} is not in 0..{}",
+            level,
+            verify::MAX_VERIFY_LEVEL
+        ));
+    }
+    let block_chain = Arc::new(BlockChain::new_blockchain(node.to_string()));
+    let utxo = UTXOSet::new(Arc::clone(&block_chain));
+    let report = verify::verify_chain(&block_chain, &utxo, level, depth);
+    if report.is_ok() {
+        println!("Done! Checked {} blocks at level {}, no problem.", report.checked_blocks, level);
+        return Ok(true);
+    }
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Height"),
+        Cell::new("Hash"),
+        Cell::new("Problem"),
+    ]));
+    for problem in &report.problems {
+        table.add_row(Row::new(vec![
+            Cell::new(&problem.height.map_or("".to_owned(), |height| format!("{}", height))),
+            Cell::new(problem.hash.as_ref().map_or("", |hash| hash.as_str())),
+            Cell::new(&problem.problem),
+        ]));
+    }
+    table.printstd();
+    println!(
+        "Checked {} blocks at level {}, found {} problems.",
+        report.checked_blocks,
+        level,
+        report.problems.len()
+    );
+    Ok(false)
+}
+
+pub fn get_supply(node: &str) -> Result<(), String> {
+    let block_chain = Arc::new(BlockChain::new_blockchain(node.to_string()));
+    let utxo = UTXOSet::new(Arc::clone(&block_chain));
+    let height = block_chain.get_best_height();
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Height"),
+        Cell::new("Issued"),
+        Cell::new("Circulating"),
+        Cell::new("MaxSupply"),
+    ]));
+    table.add_row(Row::new(vec![
+        Cell::new(&format!("{}", height)),
+        Cell::new(&format!("{}", transaction::issued_supply(height))),
+        Cell::new(&format!("{}", utxo.total_value())),
+        Cell::new(&format!("{}", chain_params::params().max_supply)),
+    ]));
+    table.printstd();
+    Ok(())
+}
+
 pub fn list_transactions(node: &str) -> Result<(), String> {
     let block_chain = BlockChain::new_blockchain(node.to_string());
     let block_iter = block_chain.iter();
@@ -245,6 +425,7 @@ pub fn send(
     from: &str,
     to: &str,
     amount: isize,
+    fee: isize,
     wallet_store: String,
     node: &str,
     central_node: &str,
@@ -266,6 +447,7 @@ pub fn send(
             from_wallet,
             to.to_string(),
             amount,
+            fee,
             &utxo,
             None,
         )?
@@ -288,10 +470,12 @@ pub fn send(
     }
 
     if mine_now {
-        let cbtx = transaction::Transaction::new_coinbase_tx(from.to_string(), "".to_owned());
+        let reward = transaction::block_subsidy(block_chain.get_best_height() + 1) + fee;
+        let cbtx =
+            transaction::Transaction::new_coinbase_tx(from.to_string(), "".to_owned(), reward);
         let txs = vec![cbtx, tx];
         let new_block = &block_chain.mine_block(&txs).unwrap();
-        utxo.update(new_block);
+        block_chain.connect_block(new_block, &utxo);
         info!(LOG, "{:?} send {} to {:?}", from, amount, to);
         return Ok(());
     }
@@ -302,22 +486,103 @@ pub fn send(
     Ok(())
 }
 
+// selects the network, a chain-spec file wins over a named network. engine overrides the
+// consensus of the network, authorities are the comma separated public keys of the proof of
+// authority signers
+pub fn init_chain(
+    network: &str,
+    chain_spec: Option<&str>,
+    engine: Option<&str>,
+    authorities: &str,
+) -> Result<(), String> {
+    let mut params = match chain_spec {
+        Some(path) => ChainParams::load(path).map_err(|e| format!("ERROR: {}", e))?,
+        None => {
+            ChainParams::by_name(network).ok_or_else(|| {
+                format!("ERROR: {} is not a network", network)
+            })?
+        }
+    };
+    params.consensus = match engine {
+        None => params.consensus,
+        Some("pow") => Consensus::ProofOfWork,
+        Some("poa") => {
+            let authorities: Vec<String> = authorities
+                .split(',')
+                .map(|key| key.trim().to_owned())
+                .filter(|key| !key.is_empty())
+                .collect();
+            if authorities.is_empty() {
+                return Err("ERROR: proof of authority needs at least one authority".to_owned());
+            }
+            Consensus::ProofOfAuthority { authorities: authorities }
+        }
+        Some(other) => return Err(format!("ERROR: {} is not a consensus engine", other)),
+    };
+    params.check().map_err(|e| format!("ERROR: {}", e))?;
+    chain_params::set_params(params);
+    Ok(())
+}
+
 pub fn start_server(
     node: String,
     node_role: &str,
     central_node: &str,
     mining_addr: &str,
+    mining_threads: usize,
+    authority_key: &str,
+    txindex: bool,
+    prune: &str,
     addr: String,
     port: u16,
 ) {
+    let prune = if prune.is_empty() {
+        None
+    } else {
+        match PruneTarget::parse(prune) {
+            Ok(target) => Some(target),
+            Err(e) => {
+                println!("ERROR: {}", e);
+                return;
+            }
+        }
+    };
+    if txindex && prune.is_some() {
+        println!("ERROR: the transaction index needs every block, it can't be pruned");
+        return;
+    }
+    if !authority_key.is_empty() {
+        let signer = util::try_decode_hex(authority_key)
+            .ok_or_else(|| "it is not hex encoded".to_owned())
+            .and_then(|key| consensus::set_authority_key(&key));
+        if let Err(e) = signer {
+            println!("ERROR: bad authority key, {}", e);
+            return;
+        }
+    }
     let block_chain = BlockChain::new_blockchain(node);
+    if txindex {
+        if let Err(e) = block_chain.enable_tx_index() {
+            println!("ERROR: {}", e);
+            return;
+        }
+    }
     let local_node = format!("{}:{}", &addr, port);
     let block_state = router::BlockState::new(
         block_chain,
         local_node.clone(),
         central_node,
         mining_addr.to_string(),
+        mining_threads,
+        prune,
     );
+    let block_state = match block_state {
+        Ok(block_state) => block_state,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return;
+        }
+    };
     let known_nodes = Arc::clone(&block_state.known_nodes);
     let bc = Arc::clone(&block_state.bc.lock().unwrap().block_chain());
     let join = thread::spawn(move || { router::init_router(&addr, port, block_state); });
@@ -352,6 +617,110 @@ pub fn start_server(
     join.join().unwrap();
 }
 
+// external miner, mines the templates of node and submits the solved blocks back
+pub fn run_miner(node: &str, address: &str, threads: usize) -> Result<(), String> {
+    if !Wallet::validate_address(address.to_owned()) {
+        return Err("ERROR: Address is not valid".to_owned());
+    }
+    let miner = Arc::new(Miner::new(threads));
+    let mut extranonce = 0;
+    loop {
+        let template: MiningTemplate =
+            match node_request(node, &format!("/mining/template/{}", address), "GET", &[]) {
+                Ok(template) => template,
+                Err(e) => {
+                    error!(LOG, "get mining template from {} fail, {}", node, e);
+                    thread::sleep(Duration::from_secs(3));
+                    continue;
+                }
+            };
+        info!(
+            LOG,
+            "mining block at height {} with {} transactions, coinbase value {}",
+            template.height,
+            template.transactions.len(),
+            template.coinbase_value
+        );
+        miner.new_job(template.height);
+
+        // a new tip makes the job stale
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let (node, prev, miner, done) = (
+                node.to_owned(),
+                template.prev_block_hash.clone(),
+                Arc::clone(&miner),
+                Arc::clone(&done),
+            );
+            thread::spawn(move || while !done.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(1));
+                let path = format!("/headers/{}", prev);
+                let headers: Result<Vec<BlockHeader>, String> =
+                    node_request(&node, &path, "GET", &[]);
+                if headers.map(|headers| !headers.is_empty()).unwrap_or(false) {
+                    miner.abort();
+                    break;
+                }
+            })
+        };
+
+        extranonce += 1;
+        let mut block = template.new_block(extranonce);
+        let res = miner.mine(&block);
+        done.store(true, Ordering::SeqCst);
+        watcher.join().unwrap();
+
+        let (nonce, hash) = match res {
+            Some(res) => res,
+            None => continue,
+        };
+        block.header.nonce = nonce;
+        block.hash = hash;
+        let data = serde_json::to_vec(&block).unwrap();
+        match node_request::<String>(node, "/mining/submit", "POST", &data) {
+            Ok(hash) => info!(LOG, "🔨 submit block {}", hash),
+            Err(e) => error!(LOG, "submit block {} fail, {}", util::encode_hex(&block.hash), e),
+        }
+    }
+}
+
+// asks a regtest node to mine n blocks, to its mining address without address
+pub fn generate(node: &str, n: usize, address: Option<&str>) -> Result<(), String> {
+    let path = match address {
+        Some(address) => {
+            if !Wallet::validate_address(address.to_owned()) {
+                return Err("ERROR: Address is not valid".to_owned());
+            }
+            format!("/mining/generate/{}/{}", n, address)
+        }
+        None => format!("/mining/generate/{}", n),
+    };
+    let hashes: Vec<String> = node_request(node, &path, "POST", &[])?;
+    hashes.iter().for_each(|hash| println!("{}", hash));
+    Ok(())
+}
+
+// the data of a {"status": "ok", "data": ...} reply
+fn node_request<T>(node: &str, path: &str, method: &str, data: &[u8]) -> Result<T, String>
+where
+    T: serde::de::DeserializeOwned,
+{
+    #[derive(Deserialize)]
+    struct Reply<T> {
+        status: String,
+        data: Option<T>,
+        #[serde(default)]
+        msg: String,
+    }
+    let headers = [("content-type".to_owned(), "application/json".to_owned())];
+    let body = pool::request(node, path, method, &headers, data)?;
+    let reply: Reply<T> = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+    if reply.status != "ok" {
+        return Err(reply.msg);
+    }
+    reply.data.ok_or_else(|| "empty reply".to_owned())
+}
+
 enum NodeRole {
     CentralNode,
     WalletNode,
@@ -418,3 +787,66 @@ fn sync_block_peer(known_nodes: Arc<Mutex<Vec<String>>>, addr: &str, path: &str)
     arg.set_call_back(f);
     pool::put_job(arg);
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use self::tempdir::TempDir;
+    use block::Block;
+    use blockchain::tests::{child, coinbase};
+
+    fn new_blocks() -> Vec<Block> {
+        let genesis = Block::new_genesis_block(coinbase(0, 0));
+        let b1 = child(&genesis, vec![coinbase(1, 1)]);
+        let b2 = child(&b1, vec![coinbase(2, 1)]);
+        vec![genesis, b1, b2]
+    }
+
+    // the store of the node is in dir, next to the bootstrap file
+    fn bootstrap_file(dir: &TempDir, blocks: &[Block]) -> (String, String) {
+        let path = dir.path().join("bootstrap.dat");
+        let mut file = File::create(&path).unwrap();
+        for block in blocks {
+            bootstrap::write_block(&mut file, block).unwrap();
+        }
+        let node = dir.path().to_str().unwrap().to_owned();
+        (node, path.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn import_again() {
+        let dir = TempDir::new("import_again").unwrap();
+        let blocks = new_blocks();
+        let (node, file) = bootstrap_file(&dir, &blocks);
+        // the genesis block starts the store
+        assert_eq!(import_chain(&node, &file), Ok((2, 0)));
+        assert_eq!(import_chain(&node, &file), Ok((0, 3)));
+        assert_eq!(BlockChain::new_blockchain(node).get_tip(), blocks[2].hash);
+    }
+
+    #[test]
+    fn import_another_chain() {
+        let dir = TempDir::new("import_another").unwrap();
+        let (node, file) = bootstrap_file(&dir, &new_blocks());
+        let genesis = Block::new_genesis_block(coinbase(0, 9));
+        drop(BlockChain::from_genesis(genesis.clone(), node.clone()));
+        assert!(import_chain(&node, &file).is_err());
+        assert_eq!(BlockChain::new_blockchain(node).get_tip(), genesis.hash);
+    }
+
+    #[test]
+    fn import_tampered_block() {
+        let dir = TempDir::new("import_tampered").unwrap();
+        let mut blocks = new_blocks();
+        let b3 = child(&blocks[2], vec![coinbase(3, 1)]);
+        blocks.push(b3);
+        blocks[2].transactions[0].vout[0].value += 1;
+        let (node, file) = bootstrap_file(&dir, &blocks);
+        let e = import_chain(&node, &file).unwrap_err();
+        assert!(e.contains(&util::encode_hex(&blocks[2].hash)), "{}", e);
+        // stopped at the last valid block
+        assert_eq!(BlockChain::new_blockchain(node).get_tip(), blocks[1].hash);
+    }
+}
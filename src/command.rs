@@ -1,9 +1,12 @@
 
 extern crate serde_json;
 extern crate rocket;
+extern crate time;
 
 use self::rocket::request::{Form, FromFormValue};
 
+use super::chain_params;
+
 pub const NODE_VERSION: isize = 1;
 
 #[derive(Serialize, Deserialize, FromForm, Debug, Default, Clone)]
@@ -12,6 +15,8 @@ pub struct Transfer {
     pub to: String,
     pub secret_key: String,
     pub amount: u32,
+    // paid to the miner, zero if missing
+    pub fee: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -60,16 +65,32 @@ pub struct TX {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Version {
     pub version: isize,
+    // magic of the sender's network
+    #[serde(default)]
+    pub magic: u32,
     pub best_height: isize,
+    // big endian cumulative work of the sender's best chain
+    #[serde(default)]
+    pub best_work: Vec<u8>,
+    // the sender's clock, used to adjust the local time
+    #[serde(default)]
+    pub timestamp: i64,
     pub addr_from: String, // stores the address of the sender
+    // the sender can't serve the blocks below the height
+    #[serde(default)]
+    pub pruned_height: isize,
 }
 
 impl Version {
-    pub fn new(ver: isize, best_height: isize, addr_from: String) -> Version {
+    pub fn new(ver: isize, best_height: isize, best_work: Vec<u8>, addr_from: String) -> Version {
         Version {
             version: ver,
+            magic: chain_params::params().magic,
             best_height: best_height,
+            best_work: best_work,
+            timestamp: time::get_time().sec,
             addr_from: addr_from,
+            pruned_height: 0,
         }
     }
 }
@@ -0,0 +1,234 @@
+extern crate bigint;
+extern crate secp256k1;
+
+use self::bigint::U256;
+use self::secp256k1::{ContextFlag, Message, Secp256k1};
+use self::secp256k1::key::PublicKey;
+
+use super::block::{Block, BlockHeader};
+use super::blockchain::BlockChain;
+use super::chain_params::{self, Consensus};
+use super::mine::Miner;
+use super::proof_of_work;
+use super::util;
+use super::validation::RejectReason;
+use super::wallet::Wallet;
+use super::log::*;
+
+use std::sync::RwLock;
+
+lazy_static! {
+    // wallet this node seals blocks with as an authority, recovered once from the secret key
+    static ref AUTHORITY_KEY: RwLock<Option<Wallet>> = RwLock::new(None);
+}
+
+// ConsensusEngine decides who may seal a block, how the seal is checked and which branch is
+// the best one
+pub trait ConsensusEngine {
+    fn name(&self) -> &'static str;
+
+    // the bits of the block after parent
+    fn next_bits(&self, chain: &BlockChain, parent: &Block) -> u32;
+
+    // checks the seal of a block whose hash matches its header
+    fn verify_seal(&self, block: &Block) -> Result<(), RejectReason>;
+
+    // what the block adds to the weight of its chain, the best chain has the most
+    fn block_weight(&self, header: &BlockHeader) -> U256;
+
+    // fills in the seal and the hash, false if the job was aborted or this node may not seal
+    // the block
+    fn seal(&self, block: &mut Block, miner: &Miner) -> bool;
+}
+
+// the engine selected by the chain parameters
+pub fn engine() -> Box<ConsensusEngine> {
+    match chain_params::params().consensus {
+        Consensus::ProofOfWork => Box::new(ProofOfWorkEngine),
+        Consensus::ProofOfAuthority { authorities } => {
+            let signer = AUTHORITY_KEY.read().unwrap().clone();
+            Box::new(ProofOfAuthorityEngine::new(authorities, signer))
+        }
+    }
+}
+
+pub fn set_authority_key(secret_key: &[u8]) -> Result<(), String> {
+    let signer = Wallet::recover_wallet(secret_key)?;
+    *AUTHORITY_KEY.write().unwrap() = Some(signer);
+    Ok(())
+}
+
+pub struct ProofOfWorkEngine;
+
+impl ConsensusEngine for ProofOfWorkEngine {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    fn next_bits(&self, chain: &BlockChain, parent: &Block) -> u32 {
+        chain.retarget_bits(parent)
+    }
+
+    fn verify_seal(&self, block: &Block) -> Result<(), RejectReason> {
+        let target = proof_of_work::compact_to_target(block.header.bits);
+        if util::as_u256(&block.hash) >= target {
+            return Err(RejectReason::BadProofOfWork);
+        }
+        Ok(())
+    }
+
+    fn block_weight(&self, header: &BlockHeader) -> U256 {
+        proof_of_work::block_work(header.bits)
+    }
+
+    fn seal(&self, block: &mut Block, miner: &Miner) -> bool {
+        match miner.mine(block) {
+            Some((nonce, hash)) => {
+                block.header.nonce = nonce;
+                block.hash = hash;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// the authorities sign the blocks in turn, the block at height is signed by
+// authorities[height % authorities.len()]
+pub struct ProofOfAuthorityEngine {
+    authorities: Vec<String>,
+    signer: Option<Wallet>,
+}
+
+impl ProofOfAuthorityEngine {
+    pub fn new(authorities: Vec<String>, signer: Option<Wallet>) -> ProofOfAuthorityEngine {
+        ProofOfAuthorityEngine {
+            authorities: authorities,
+            signer: signer,
+        }
+    }
+
+    // hex encoded public key of the authority sealing the block at height
+    pub fn authority(&self, height: isize) -> Option<&String> {
+        if self.authorities.is_empty() {
+            return None;
+        }
+        self.authorities.get(height as usize % self.authorities.len())
+    }
+}
+
+// the authorities may be configured with compressed or uncompressed keys, compare the keys and
+// not their encoding
+fn is_authority(authority: &str, public_key: &PublicKey) -> bool {
+    let secp = Secp256k1::with_caps(ContextFlag::None);
+    match util::try_decode_hex(authority) {
+        Some(authority) => PublicKey::from_slice(&secp, &authority).ok() == Some(*public_key),
+        None => false,
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthorityEngine {
+    fn name(&self) -> &'static str {
+        "poa"
+    }
+
+    // no difficulty, the bits of the genesis block are kept
+    fn next_bits(&self, _: &BlockChain, parent: &Block) -> u32 {
+        parent.header.bits
+    }
+
+    fn verify_seal(&self, block: &Block) -> Result<(), RejectReason> {
+        let authority = match self.authority(block.height).and_then(util::try_decode_hex) {
+            Some(authority) => authority,
+            None => return Err(RejectReason::BadSeal { height: block.height }),
+        };
+        let signed = util::encode_hex(&block.hash);
+        if !util::verify(&authority, &block.header.signature, signed) {
+            return Err(RejectReason::BadSeal { height: block.height });
+        }
+        Ok(())
+    }
+
+    // every block weighs the same, the longest chain wins
+    fn block_weight(&self, _: &BlockHeader) -> U256 {
+        U256::one()
+    }
+
+    fn seal(&self, block: &mut Block, _: &Miner) -> bool {
+        let signer = match self.signer {
+            Some(ref signer) => signer,
+            None => {
+                warn!(LOG, "no authority key, can't seal block {}", block.height);
+                return false;
+            }
+        };
+        let in_turn = self.authority(block.height).map_or(false, |authority| {
+            is_authority(authority, &signer.public_key)
+        });
+        if !in_turn {
+            info!(LOG, "not the turn of this authority to seal block {}", block.height);
+            return false;
+        }
+
+        block.hash = block.header.hash();
+        let data_to_sign = util::double_sha256(util::encode_hex(&block.hash));
+        let msg = Message::from_slice(&data_to_sign).unwrap();
+        block.header.signature = util::sign(&msg, &signer.secret_key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::{Transaction, TXInput, TXOutput};
+
+    fn new_block(height: isize) -> Block {
+        let mut coinbase = Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(vec![], -1, vec![], b"coinbase".to_vec())],
+            vout: vec![TXOutput { value: 10, pub_key_hash: vec![1; 20] }],
+        };
+        coinbase.id = coinbase.hash();
+        Block::new_template(vec![coinbase], vec![0; 32], height, proof_of_work::pow_limit_bits())
+    }
+
+    fn public_key(wallet: &Wallet) -> String {
+        util::encode_hex(util::public_key_to_vec(&wallet.public_key, false))
+    }
+
+    #[test]
+    fn authorities_seal_in_turn() {
+        let (first, second) = (Wallet::new(), Wallet::new());
+        let authorities = vec![public_key(&first), public_key(&second)];
+        let first_engine = ProofOfAuthorityEngine::new(authorities.clone(), Some(first));
+        let second_engine = ProofOfAuthorityEngine::new(authorities, Some(second));
+
+        let mut block = new_block(1);
+        assert!(!first_engine.seal(&mut block, &Miner::new(1)));
+        assert!(second_engine.seal(&mut block, &Miner::new(1)));
+        assert_eq!(first_engine.verify_seal(&block), Ok(()));
+
+        // signed by the first authority but it's the turn of the second one
+        let mut block = new_block(2);
+        assert!(first_engine.seal(&mut block, &Miner::new(1)));
+        block.height = 3;
+        assert_eq!(
+            second_engine.verify_seal(&block),
+            Err(RejectReason::BadSeal { height: 3 })
+        );
+    }
+
+    #[test]
+    fn compressed_authority_seals() {
+        let wallet = Wallet::new();
+        let authority = util::encode_hex(util::public_key_to_vec(&wallet.public_key, true));
+        let other = public_key(&Wallet::new());
+        let engine = ProofOfAuthorityEngine::new(vec![authority, other], Some(wallet));
+
+        let mut block = new_block(2);
+        assert!(engine.seal(&mut block, &Miner::new(1)));
+        assert_eq!(engine.verify_seal(&block), Ok(()));
+        assert!(!engine.seal(&mut new_block(3), &Miner::new(1)));
+    }
+}
@@ -1,34 +1,83 @@
 extern crate tempdir;
 extern crate rocksdb;
 
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::path::Path;
 
-use util::encode_hex;
 use log::*;
 
+// a page of key value pairs and the number of entries of every page
+pub type KeyValuePage = (usize, Vec<(Vec<u8>, Vec<u8>)>);
+
+// one rocksdb for the whole chain, every prefix is a column family so one batch can write
+// to all of them at once
 #[derive(Clone)]
 pub struct DBStore {
-    pub db: Arc<HashMap<String, Mutex<rocksdb::DB>>>,
+    pub db: Arc<rocksdb::DB>,
 }
 
 impl DBStore {
     pub fn new(path: &str, prefixs: Vec<String>) -> DBStore {
-        let mut db_map = HashMap::new();
-        for prefix in prefixs {
-            let db_path = format!("{}/{}", path, prefix);
-            let db = rocksdb::DB::open_default(db_path).unwrap();
-            debug!(LOG, "db init {}", &prefix);
-            db_map.insert(prefix, Mutex::new(db));
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let cfs: Vec<&str> = prefixs.iter().map(|prefix| prefix.as_str()).collect();
+        let db_path = format!("{}/chain", path);
+        let db = rocksdb::DB::open_cf(&opts, db_path, &cfs).unwrap();
+        debug!(LOG, "db init {:?}", &cfs);
+        let store = DBStore { db: Arc::new(db) };
+        store.migrate(path, &prefixs);
+        store
+    }
+
+    // older stores kept one rocksdb per prefix at {path}/{prefix}, they are copied into the
+    // column families in one write. The copy is all or nothing, an empty chain db with old
+    // stores next to it is copied again
+    fn migrate(&self, path: &str, prefixs: &[String]) {
+        let old_paths: Vec<(&String, String)> = prefixs
+            .iter()
+            .map(|prefix| (prefix, format!("{}/{}", path, prefix)))
+            .filter(|&(_, ref old_path)| Path::new(old_path).exists())
+            .collect();
+        if old_paths.is_empty() || prefixs.iter().any(|prefix| self.has_entries(prefix)) {
+            return;
+        }
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for &(prefix, ref old_path) in &old_paths {
+            let old = rocksdb::DB::open_default(old_path).unwrap();
+            let mut iter = old.raw_iterator();
+            iter.seek_to_first();
+            while iter.valid() {
+                let (key, value) = (iter.key().unwrap(), iter.value().unwrap());
+                write_batch.put_cf(self.cf(prefix), &key, &value).unwrap();
+                iter.next();
+            }
         }
-        DBStore { db: Arc::new(db_map) }
+        let count = write_batch.len();
+        self.db.write(write_batch).unwrap();
+        info!(
+            LOG,
+            "migrated {} entries of the stores under {} into {}/chain, the old stores can be \
+             removed",
+            count,
+            path,
+            path
+        );
+    }
+
+    fn has_entries(&self, prefix: &str) -> bool {
+        let mut iter = self.db.raw_iterator_cf(self.cf(prefix)).unwrap();
+        iter.seek_to_first();
+        iter.valid()
+    }
+
+    fn cf(&self, prefix: &str) -> rocksdb::ColumnFamily {
+        self.db.cf_handle(prefix).unwrap()
     }
 
     pub fn get_with_prefix(&self, key: &[u8], prefix: &str) -> Option<Vec<u8>> {
-        //info!(LOG, "get prefix {}, key {}", prefix, encode_hex(key));
-        let db = self.db.get(prefix).unwrap();
-        let db = db.lock().unwrap();
-        match db.get(key) {
+        match self.db.get_cf(self.cf(prefix), key) {
             Ok(Some(value)) => {
                 let v = value.to_vec();
                 Some(v)
@@ -39,21 +88,12 @@ impl DBStore {
     }
 
     pub fn put_with_prefix(&self, key: &[u8], value: &[u8], prefix: &str) {
-        //info!(LOG, "put prefix {}, key {}", prefix, encode_hex(key));
-//        if prefix.starts_with("blocks") {
-//            info!(LOG, "prefix {}", encode_hex(&key));
-//        }
-        let db = self.db.get(prefix).unwrap();
-        let db = db.lock().unwrap();
-        db.put(key, value).unwrap();
+        self.db.put_cf(self.cf(prefix), key, value).unwrap();
     }
 
     // return value not included prefix
     pub fn get_all_with_prefix(&self, prefix: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
-        //info!(LOG, "getall prefix {}", prefix);
-        let db = self.db.get(prefix).unwrap();
-        let db = db.lock().unwrap();
-        let mut iter = db.raw_iterator();
+        let mut iter = self.db.raw_iterator_cf(self.cf(prefix)).unwrap();
         iter.seek_to_first();
         let mut kvs = Vec::new();
         while iter.valid() {
@@ -63,10 +103,195 @@ impl DBStore {
         kvs
     }
 
+    // the entries whose key starts with key_prefix, in key order
+    pub fn get_all_with_key_prefix(
+        &self,
+        key_prefix: &[u8],
+        prefix: &str,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = self.db.raw_iterator_cf(self.cf(prefix)).unwrap();
+        iter.seek(key_prefix);
+        let mut kvs = Vec::new();
+        while iter.valid() {
+            let key = iter.key().unwrap();
+            if !key.starts_with(key_prefix) {
+                break;
+            }
+            kvs.push((key, iter.value().unwrap()));
+            iter.next();
+        }
+        kvs
+    }
+
+    // the number of entries whose key starts with key_prefix, and take of them after skipping
+    // skip, in key order or from the last key when reverse. Only the values of the page are read
+    pub fn page_with_key_prefix(
+        &self,
+        key_prefix: &[u8],
+        prefix: &str,
+        skip: usize,
+        take: usize,
+        reverse: bool,
+    ) -> KeyValuePage {
+        let mut iter = self.db.raw_iterator_cf(self.cf(prefix)).unwrap();
+        if !reverse {
+            iter.seek(key_prefix);
+        } else if let Some(end) = key_prefix_end(key_prefix) {
+            // the first key after the range, or the end of the store
+            iter.seek(&end);
+            if iter.valid() {
+                iter.prev();
+            } else {
+                iter.seek_to_last();
+            }
+        } else {
+            iter.seek_to_last();
+        }
+        let (mut total, mut kvs) = (0, Vec::new());
+        while iter.valid() {
+            let key = iter.key().unwrap();
+            if !key.starts_with(key_prefix) {
+                break;
+            }
+            if total >= skip && kvs.len() < take {
+                kvs.push((key, iter.value().unwrap()));
+            }
+            total += 1;
+            if reverse {
+                iter.prev();
+            } else {
+                iter.next();
+            }
+        }
+        (total, kvs)
+    }
+
     pub fn delete(&self, key: &[u8], prefix: &str) {
-        //info!(LOG, "delete prefix {}, key {}", prefix, encode_hex(key));
-        let db = self.db.get(prefix).unwrap();
-        let db = db.lock().unwrap();
-        db.delete(key).unwrap()
+        self.db.delete_cf(self.cf(prefix), key).unwrap()
+    }
+
+    // the writes of the batch land all together or not at all
+    pub fn write(&self, batch: DBBatch) {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for ((prefix, key), value) in batch.writes {
+            match value {
+                Some(value) => write_batch.put_cf(self.cf(&prefix), &key, &value).unwrap(),
+                None => write_batch.delete_cf(self.cf(&prefix), &key).unwrap(),
+            }
+        }
+        self.db.write(write_batch).unwrap()
+    }
+}
+
+// writes collected for DBStore::write, the last write of a key wins. Reads through the batch
+// see its writes before the store
+#[derive(Default)]
+pub struct DBBatch {
+    // (prefix, key) => value, None deletes the key
+    writes: BTreeMap<(String, Vec<u8>), Option<Vec<u8>>>,
+}
+
+impl DBBatch {
+    pub fn put_with_prefix(&mut self, key: &[u8], value: &[u8], prefix: &str) {
+        self.writes.insert((prefix.to_owned(), key.to_vec()), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8], prefix: &str) {
+        self.writes.insert((prefix.to_owned(), key.to_vec()), None);
+    }
+
+    pub fn get_with_prefix(&self, db: &DBStore, key: &[u8], prefix: &str) -> Option<Vec<u8>> {
+        match self.writes.get(&(prefix.to_owned(), key.to_vec())) {
+            Some(value) => value.clone(),
+            None => db.get_with_prefix(key, prefix),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+// the smallest key after every key starting with key_prefix, None if there is none
+fn key_prefix_end(key_prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = key_prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn open_old_layout() {
+        let dir = TempDir::new("old_layout").unwrap();
+        let path = dir.path().to_str().unwrap();
+        {
+            let blocks = rocksdb::DB::open_default(format!("{}/blocks", path)).unwrap();
+            blocks.put(b"hash", b"block").unwrap();
+            let last = rocksdb::DB::open_default(format!("{}/l-", path)).unwrap();
+            last.put(b"last_block", b"hash").unwrap();
+        }
+
+        let prefixs = vec!["blocks".to_owned(), "l-".to_owned(), "utxo-".to_owned()];
+        let store = DBStore::new(path, prefixs.clone());
+        assert_eq!(store.get_with_prefix(b"hash", "blocks"), Some(b"block".to_vec()));
+        assert_eq!(store.get_with_prefix(b"last_block", "l-"), Some(b"hash".to_vec()));
+        assert!(store.get_all_with_prefix("utxo-").is_empty());
+
+        // not copied again over newer writes
+        store.put_with_prefix(b"last_block", b"new_hash", "l-");
+        drop(store);
+        let store = DBStore::new(path, prefixs);
+        assert_eq!(store.get_with_prefix(b"last_block", "l-"), Some(b"new_hash".to_vec()));
+    }
+
+    #[test]
+    fn page_of_key_prefix() {
+        let dir = TempDir::new("key_prefix_page").unwrap();
+        let store = DBStore::new(dir.path().to_str().unwrap(), vec!["addr-".to_owned()]);
+        for key in &[[0x01, 0xff], [0x02, 0x00], [0x02, 0x01], [0x02, 0x02], [0x03, 0x00]] {
+            store.put_with_prefix(key, &key[1..], "addr-");
+        }
+        // pages of two values
+        let page = |key_prefix: u8, skip: usize, reverse: bool| -> (usize, Vec<u8>) {
+            let (total, kvs) = store.page_with_key_prefix(&[key_prefix], "addr-", skip, 2, reverse);
+            (total, kvs.into_iter().map(|kv| kv.1[0]).collect())
+        };
+        assert_eq!(page(0x02, 0, false), (3, vec![0, 1]));
+        assert_eq!(page(0x02, 2, false), (3, vec![2]));
+        assert_eq!(page(0x02, 0, true), (3, vec![2, 1]));
+        assert_eq!(page(0x03, 0, true), (1, vec![0]));
+        assert_eq!(page(0x04, 0, true), (0, vec![]));
+        assert_eq!(key_prefix_end(&[0x01, 0xff]), Some(vec![0x02]));
+        assert_eq!(key_prefix_end(&[0xff]), None);
+    }
+
+    #[test]
+    fn last_batch_write_wins() {
+        let mut batch = DBBatch::default();
+        batch.put_with_prefix(b"key", b"first", "utxo-");
+        batch.put_with_prefix(b"key", b"second", "undo-");
+        batch.delete(b"key", "utxo-");
+        assert_eq!(batch.len(), 2);
+        batch.put_with_prefix(b"key", b"third", "utxo-");
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch.writes.get(&("utxo-".to_owned(), b"key".to_vec())),
+            Some(&Some(b"third".to_vec()))
+        );
+        batch.delete(b"key", "undo-");
+        assert_eq!(batch.writes.get(&("undo-".to_owned(), b"key".to_vec())), Some(&None));
     }
 }
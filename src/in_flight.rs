@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// at most MAX_BLOCKS_IN_FLIGHT blocks are requested at once, the rest of an inventory is
+// requested after them. A request not answered in BLOCK_REQUEST_TIMEOUT seconds can be sent again
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+pub const BLOCK_REQUEST_TIMEOUT: u64 = 60;
+
+// InFlight holds the blocks requested from peers and not received yet
+pub struct InFlight {
+    // block hash => when it was requested
+    requested: HashMap<Vec<u8>, Instant>,
+    max_blocks: usize,
+    timeout: Duration,
+}
+
+impl InFlight {
+    pub fn new(max_blocks: usize, timeout: Duration) -> InFlight {
+        InFlight {
+            requested: HashMap::new(),
+            max_blocks: max_blocks,
+            timeout: timeout,
+        }
+    }
+
+    // false if the block is already requested or no more blocks can be
+    pub fn request(&mut self, hash: &[u8]) -> bool {
+        if self.is_full() || self.requested.contains_key(hash) {
+            return false;
+        }
+        self.requested.insert(hash.to_vec(), Instant::now());
+        true
+    }
+
+    // false if the block wasn't requested
+    pub fn received(&mut self, hash: &[u8]) -> bool {
+        self.requested.remove(hash).is_some()
+    }
+
+    // the timed out requests are dropped first, they don't hold a place forever
+    pub fn is_full(&mut self) -> bool {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        self.requested.retain(|_, at| now.duration_since(*at) < timeout);
+        self.requested.len() >= self.max_blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.requested.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requested.is_empty()
+    }
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight::new(MAX_BLOCKS_IN_FLIGHT, Duration::from_secs(BLOCK_REQUEST_TIMEOUT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_requests() {
+        let mut in_flight = InFlight::new(2, Duration::from_secs(60));
+        assert!(in_flight.request(&[1]));
+        assert!(!in_flight.request(&[1]));
+        assert!(in_flight.request(&[2]));
+        assert!(in_flight.is_full());
+        assert!(!in_flight.request(&[3]));
+
+        assert!(in_flight.received(&[1]));
+        assert!(!in_flight.received(&[1]));
+        assert!(in_flight.request(&[3]));
+        assert_eq!(in_flight.len(), 2);
+    }
+
+    #[test]
+    fn timed_out_requests() {
+        let mut in_flight = InFlight::new(1, Duration::from_secs(0));
+        assert!(in_flight.request(&[1]));
+        // asked again, from another peer maybe
+        assert!(in_flight.request(&[1]));
+        assert!(in_flight.request(&[2]));
+        assert_eq!(in_flight.len(), 1);
+
+        // filled by requests nobody answers
+        in_flight.requested.insert(vec![3], Instant::now());
+        assert_eq!(in_flight.len(), 2);
+        assert!(!in_flight.is_full());
+        assert!(in_flight.request(&[4]));
+    }
+}
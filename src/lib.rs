@@ -55,6 +55,18 @@ mod command;
 mod router;
 mod pool;
 mod mine;
+mod validation;
+mod block_template;
+mod timedata;
+mod chain_params;
+mod consensus;
+mod orphan;
+mod in_flight;
+mod tx_index;
+mod addr_index;
+mod prune;
+mod bootstrap;
+mod verify;
 
 pub mod cli;
 pub mod log;
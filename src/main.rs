@@ -5,6 +5,7 @@ extern crate clap;
 extern crate simple_bitcoin_rs;
 
 use clap::{Arg, App, SubCommand, ArgMatches};
+use std::process;
 use simple_bitcoin_rs::log::*;
 use simple_bitcoin_rs::cli;
 
@@ -19,6 +20,28 @@ fn main() {
         .arg(Arg::with_name("wallets").long("config").default_value(
             "default_wallet.json",
         ))
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .possible_values(&["main", "regtest"])
+                .default_value("main"),
+        )
+        .arg(
+            Arg::with_name("chain")
+                .long("chain")
+                .value_name("CHAIN_SPEC"),
+        )
+        .arg(
+            Arg::with_name("consensus")
+                .long("consensus")
+                .possible_values(&["pow", "poa"]),
+        )
+        .arg(
+            Arg::with_name("authorities")
+                .long("authorities")
+                .value_name("PUBKEYS")
+                .default_value(""),
+        )
         .subcommand(
             SubCommand::with_name("new")
                 .about("new a bitcoin wallet")
@@ -152,6 +175,30 @@ fn main() {
                         .value_name("MINING_ADDR")
                         .default_value(""),
                 )
+                .arg(
+                    Arg::with_name("mining_threads")
+                        .long("mining_threads")
+                        .value_name("MINING_THREADS")
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("authority_key")
+                        .long("authority_key")
+                        .value_name("SECRET_KEY")
+                        .default_value(""),
+                )
+                .arg(
+                    Arg::with_name("txindex")
+                        .long("txindex")
+                        .value_name("TXINDEX")
+                        .default_value("false"),
+                )
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .value_name("PRUNE")
+                        .default_value(""),
+                )
                 .arg(
                     Arg::with_name("node_role")
                         .long("node_role")
@@ -172,6 +219,12 @@ fn main() {
                 .arg(Arg::with_name("from").long("from").value_name("FROM"))
                 .arg(Arg::with_name("to").long("to").value_name("TO"))
                 .arg(Arg::with_name("amount").long("amount").value_name("amount"))
+                .arg(
+                    Arg::with_name("fee")
+                        .long("fee")
+                        .default_value("0")
+                        .value_name("fee"),
+                )
                 .arg(
                     Arg::with_name("mine")
                         .long("mine")
@@ -197,6 +250,76 @@ fn main() {
                         .default_value(""),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("miner")
+                .about("mine the block templates of a node")
+                .arg(
+                    Arg::with_name("node")
+                        .long("node")
+                        .value_name("NODE")
+                        .default_value(CENTRAL_NODE),
+                )
+                .arg(
+                    Arg::with_name("address")
+                        .long("address")
+                        .short("addr")
+                        .value_name("ADDRESS"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .value_name("THREADS")
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("generate")
+                .about("mine blocks right away on a regtest node")
+                .arg(Arg::with_name("blocks").required(true).index(1))
+                .arg(Arg::with_name("address").index(2))
+                .arg(
+                    Arg::with_name("node")
+                        .long("node")
+                        .value_name("NODE")
+                        .default_value(CENTRAL_NODE),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reindex_tx")
+                .about("rebuild the transaction index")
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
+        .subcommand(
+            SubCommand::with_name("export_chain")
+                .about("write the best chain's blocks into a bootstrap file")
+                .arg(Arg::with_name("file").required(true).index(1))
+                .arg(Arg::with_name("from").long("from").default_value("0"))
+                .arg(Arg::with_name("to").long("to").value_name("HEIGHT"))
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
+        .subcommand(
+            SubCommand::with_name("import_chain")
+                .about("validate and add the blocks of a bootstrap file")
+                .arg(Arg::with_name("file").required(true).index(1))
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify_chain")
+                .about("check the newest blocks and the indexes of the chain")
+                .arg(Arg::with_name("depth").long("depth").default_value("6"))
+                .arg(Arg::with_name("level").long("level").default_value("3"))
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
+        .subcommand(
+            SubCommand::with_name("chain_tips")
+                .about("list the tip of every branch")
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
+        .subcommand(
+            SubCommand::with_name("supply")
+                .about("circulating supply at the tip")
+                .arg(Arg::with_name("store").long("store").default_value(STORE)),
+        )
         .subcommand(
             SubCommand::with_name("list_transactions")
                 .about("list all transactions")
@@ -210,6 +333,12 @@ fn main() {
 
 fn run(matches: ArgMatches) -> Result<(), String> {
     let config = matches.value_of("wallets").unwrap();
+    cli::init_chain(
+        matches.value_of("network").unwrap(),
+        matches.value_of("chain"),
+        matches.value_of("consensus"),
+        matches.value_of("authorities").unwrap(),
+    )?;
     match matches.subcommand() {
         ("new", Some(m)) => {
             info!(LOG, "wallet store {:?}", config);
@@ -224,11 +353,19 @@ fn run(matches: ArgMatches) -> Result<(), String> {
         ("address_check", Some(m)) => Ok(run_address_check(m)),
         ("print", Some(m)) => Ok(run_print(m)),
         ("reindex", Some(m)) => Ok(run_reindex(m)),
+        ("reindex_tx", Some(m)) => Ok(run_reindex_tx(m)),
+        ("export_chain", Some(m)) => run_export_chain(m),
+        ("import_chain", Some(m)) => run_import_chain(m),
+        ("verify_chain", Some(m)) => run_verify_chain(m),
         ("balance", Some(m)) => Ok(run_get_balance(m)),
         ("balances", Some(m)) => Ok(run_get_balances(m)),
         ("utxo", Some(m)) => Ok(run_get_utxo(m)),
         ("utxos", Some(m)) => Ok(run_get_utxos(m)),
         ("list_transactions", Some(m)) => Ok(run_list_transactions(m)),
+        ("supply", Some(m)) => Ok(run_supply(m)),
+        ("chain_tips", Some(m)) => Ok(run_chain_tips(m)),
+        ("miner", Some(m)) => Ok(run_miner(m)),
+        ("generate", Some(m)) => run_generate(m),
         ("send", Some(m)) => Ok(run_send(m)),
         ("server", Some(m)) => Ok(run_server(m)),
         _ => Ok(()),
@@ -281,6 +418,13 @@ fn run_reindex(matches: &ArgMatches) {
     }
 }
 
+fn run_reindex_tx(matches: &ArgMatches) {
+    let store = matches.value_of("store").unwrap();
+    if let Err(e) = cli::reindex_tx(store) {
+        println!("{}", e);
+    }
+}
+
 fn run_get_balance(matches: &ArgMatches) {
     let store = matches.value_of("store").unwrap();
     let address = matches.value_of("address").unwrap();
@@ -311,11 +455,13 @@ fn run_send(matches: &ArgMatches) {
         .unwrap()
         .parse::<isize>()
         .unwrap();
+    let fee = matches.value_of("fee").unwrap().parse::<isize>().unwrap();
     let mine = matches.value_of("mine").unwrap().parse::<bool>().unwrap();
     match cli::send(
         from,
         to,
         amount,
+        fee,
         wallet_store.to_owned(),
         store,
         central_node,
@@ -343,6 +489,81 @@ fn run_list_transactions(matches: &ArgMatches) {
     cli::list_transactions(store).unwrap();
 }
 
+fn run_miner(matches: &ArgMatches) {
+    let node = matches.value_of("node").unwrap();
+    let address = matches.value_of("address").unwrap();
+    let threads = matches
+        .value_of("threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    if let Err(e) = cli::run_miner(node, address, threads) {
+        println!("{}", e);
+    }
+}
+
+fn run_generate(matches: &ArgMatches) -> Result<(), String> {
+    let blocks = matches
+        .value_of("blocks")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| e.to_string())?;
+    let node = matches.value_of("node").unwrap();
+    cli::generate(node, blocks, matches.value_of("address"))
+}
+
+fn run_export_chain(matches: &ArgMatches) -> Result<(), String> {
+    let store = matches.value_of("store").unwrap();
+    let file = matches.value_of("file").unwrap();
+    let from = matches
+        .value_of("from")
+        .unwrap()
+        .parse::<isize>()
+        .map_err(|e| e.to_string())?;
+    let to = match matches.value_of("to") {
+        Some(to) => Some(to.parse::<isize>().map_err(|e| e.to_string())?),
+        None => None,
+    };
+    cli::export_chain(store, file, from, to)
+}
+
+fn run_import_chain(matches: &ArgMatches) -> Result<(), String> {
+    let store = matches.value_of("store").unwrap();
+    cli::import_chain(store, matches.value_of("file").unwrap()).map(|_| ())
+}
+
+fn run_verify_chain(matches: &ArgMatches) -> Result<(), String> {
+    let store = matches.value_of("store").unwrap();
+    let depth = matches
+        .value_of("depth")
+        .unwrap()
+        .parse::<isize>()
+        .map_err(|e| e.to_string())?;
+    let level = matches
+        .value_of("level")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| e.to_string())?;
+    if !cli::verify_chain(store, level, depth)? {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_chain_tips(matches: &ArgMatches) {
+    let store = matches.value_of("store").unwrap();
+    if let Err(e) = cli::get_chain_tips(store) {
+        println!("{}", e);
+    }
+}
+
+fn run_supply(matches: &ArgMatches) {
+    let store = matches.value_of("store").unwrap();
+    if let Err(e) = cli::get_supply(store) {
+        println!("{}", e);
+    }
+}
+
 fn run_server(mathes: &ArgMatches) {
     let store = mathes.value_of("store").unwrap().to_owned();
     let addr = mathes.value_of("addr").unwrap().to_owned();
@@ -355,5 +576,24 @@ fn run_server(mathes: &ArgMatches) {
     let central_node = mathes.value_of("central_node").unwrap().to_owned();
     let node_role = mathes.value_of("node_role").unwrap().to_owned();
     let mining_addr = mathes.value_of("mining_addr").unwrap().to_owned();
-    cli::start_server(store, &node_role, &central_node, &mining_addr, addr, port);
+    let mining_threads = mathes
+        .value_of("mining_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let authority_key = mathes.value_of("authority_key").unwrap().to_owned();
+    let txindex = mathes.value_of("txindex").unwrap().parse::<bool>().unwrap();
+    let prune = mathes.value_of("prune").unwrap().to_owned();
+    cli::start_server(
+        store,
+        &node_role,
+        &central_node,
+        &mining_addr,
+        mining_threads,
+        &authority_key,
+        txindex,
+        &prune,
+        addr,
+        port,
+    );
 }
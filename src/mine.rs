@@ -1,46 +1,200 @@
-extern crate chan;
 extern crate bigint;
 
 use std::thread;
-use std::time::Duration;
+use std::time::Instant;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 
-use chan::{Sender, Receiver, tick_ms};
-use bigint::U256;
-
-use super::proof_of_work::ProofOfWork;
+use super::proof_of_work::{ProofOfWork, MAX_NONCE};
 use super::util;
 use super::block::Block;
+use super::log::*;
+
+// flush the local hash counter every HASH_COUNT_STEP nonces
+const HASH_COUNT_STEP: usize = 1000;
+
+// Miner splits the nonce space across `threads` workers, all of them stop as soon as
+// one finds a solution or the job is aborted.
+pub struct Miner {
+    threads: usize,
+    abort: Arc<AtomicBool>,
+    // height of the block being mined, -1 means idle
+    height: AtomicIsize,
+    // hashes per second of the last job
+    hash_rate: AtomicUsize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: if threads == 0 { 1 } else { threads },
+            abort: Arc::new(AtomicBool::new(false)),
+            height: AtomicIsize::new(-1),
+            hash_rate: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn hash_rate(&self) -> usize {
+        self.hash_rate.load(Ordering::SeqCst)
+    }
+
+    pub fn mining_height(&self) -> isize {
+        self.height.load(Ordering::SeqCst)
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining_height() >= 0
+    }
+
+    // a template for height was created, an abort before it doesn't stop the job but one after
+    // it does, even before mine starts
+    pub fn new_job(&self, height: isize) {
+        self.abort.store(false, Ordering::SeqCst);
+        self.height.store(height, Ordering::SeqCst);
+    }
+
+    // stop the running job, if any
+    pub fn abort(&self) {
+        self.abort.store(true, Ordering::SeqCst);
+    }
+
+    // a block at `height` was connected, the running job can't win any more
+    pub fn abort_stale(&self, height: isize) -> bool {
+        let mining_height = self.mining_height();
+        if mining_height >= 0 && mining_height <= height {
+            info!(
+                LOG,
+                "abort mining block at height {}, a block at height {} was connected",
+                mining_height,
+                height
+            );
+            self.abort();
+            return true;
+        }
+        false
+    }
 
-const INTERNAL_MINE_TICK: u32 = 100;
-
-/// return a sender channel for stop the work
-pub fn async_mine(max_nonce: isize, proof_block: ProofOfWork, mine_recv: Sender<(isize, Vec<u8>, Block)>) -> Sender<()> {
-    let (send, recv) = chan::sync(1);
-    let b = proof_block.block.clone();
-    let target = proof_block.target;
-    thread::spawn(move||{
-        let mut nonce: isize = -1;
-        let b = b.clone();
-        let proof_block = ProofOfWork{block: &b.clone(), target: target};
-        assert_eq!(nonce, max_nonce);
-        loop {
-            nonce+=1;
-            chan_select! {
-                default => {
-                    // start to mine
-                    let data = proof_block.prepare_data(nonce);
-                    let hash = util::sha256(&data);
-                    let hash_int: U256 = util::as_u256(&hash);
-                    if hash_int < proof_block.target {
-                        mine_recv.send((nonce, hash, b.clone()));
+    // return (nonce, hash), None if the job was aborted or the nonce space is exhausted
+    pub fn mine(&self, block: &Block) -> Option<(isize, Vec<u8>)> {
+        self.height.store(block.height, Ordering::SeqCst);
+
+        let found = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicUsize::new(0));
+        let (send, recv) = channel();
+        let start = Instant::now();
+
+        let workers: Vec<thread::JoinHandle<()>> = (0..self.threads)
+            .map(|idx| {
+                let (block, send) = (block.clone(), send.clone());
+                let (found, abort, hashes) =
+                    (Arc::clone(&found), Arc::clone(&self.abort), Arc::clone(&hashes));
+                let (from, to) = nonce_range(idx, self.threads);
+                thread::spawn(move || {
+                    let pow = ProofOfWork::new_proof_of_work(&block.header);
+                    let mut count = 0;
+                    for nonce in from..to {
+                        if found.load(Ordering::Relaxed) || abort.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let hash = util::sha256(&pow.prepare_data(nonce));
+                        count += 1;
+                        if count == HASH_COUNT_STEP {
+                            hashes.fetch_add(count, Ordering::Relaxed);
+                            count = 0;
+                        }
+                        if util::as_u256(&hash) < pow.target {
+                            found.store(true, Ordering::SeqCst);
+                            let _ = send.send((nonce, hash));
+                            break;
+                        }
                     }
-                },
-                recv.recv() => {
-                    // receive stop mine
-                    return;
-                },
-            };
+                    hashes.fetch_add(count, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        drop(send);
+
+        // Err means all workers exited without a solution
+        let res = recv.recv().ok();
+        found.store(true, Ordering::SeqCst);
+        workers.into_iter().for_each(|worker| { worker.join().unwrap(); });
+
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let hashes = hashes.load(Ordering::SeqCst);
+        let hash_rate = if secs > 0.0 {
+            (hashes as f64 / secs) as usize
+        } else {
+            hashes
+        };
+        self.hash_rate.store(hash_rate, Ordering::SeqCst);
+        self.height.store(-1, Ordering::SeqCst);
+
+        if self.abort.load(Ordering::SeqCst) {
+            info!(LOG, "mining aborted after {} hashes, {} H/s", hashes, hash_rate);
+            return None;
+        }
+        info!(
+            LOG,
+            "mining with {} threads finished, {} hashes in {:.2}s, {} H/s",
+            self.threads,
+            hashes,
+            secs,
+            hash_rate
+        );
+        res
+    }
+}
+
+// the nonces of worker idx of threads, the last one takes the remainder
+fn nonce_range(idx: usize, threads: usize) -> (isize, isize) {
+    let chunk = *MAX_NONCE / threads as isize;
+    let from = chunk * idx as isize;
+    if idx + 1 == threads {
+        (from, *MAX_NONCE)
+    } else {
+        (from, from + chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::tests::coinbase;
+
+    // a block about every other hash solves
+    fn easy_block() -> Block {
+        Block::new_template(vec![coinbase(1, 1)], vec![0; 32], 1, 0x207f_ffff)
+    }
+
+    #[test]
+    fn abort_before_mine() {
+        let miner = Miner::new(2);
+        miner.new_job(1);
+        assert!(miner.abort_stale(1));
+        assert_eq!(miner.mine(&easy_block()), None);
+        assert!(!miner.is_mining());
+
+        // the next template isn't stopped by the old abort
+        miner.new_job(1);
+        assert!(miner.mine(&easy_block()).is_some());
+    }
+
+    #[test]
+    fn nonce_ranges_cover_all() {
+        for threads in 1..8 {
+            let mut next = 0;
+            for idx in 0..threads {
+                let (from, to) = nonce_range(idx, threads);
+                assert_eq!(from, next);
+                next = to;
+            }
+            assert_eq!(next, *MAX_NONCE);
         }
-    });
-    return send;
-}
\ No newline at end of file
+    }
+}
@@ -0,0 +1,188 @@
+use super::block::Block;
+use super::util;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+// at most MAX_ORPHAN_BLOCKS orphans taking MAX_ORPHAN_BYTES are kept, the oldest go first
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+pub const MAX_ORPHAN_BYTES: usize = 16 * 1024 * 1024;
+
+pub struct OrphanBlock {
+    pub block: Block,
+    // the address of the peer that sent the block
+    pub from: IpAddr,
+    size: usize,
+}
+
+// OrphanPool holds the blocks whose parent is unknown yet, they are connected once the parent
+// arrives, see take_children
+pub struct OrphanPool {
+    blocks: HashMap<Vec<u8>, OrphanBlock>,
+    // parent hash => hashes of the orphans building on it
+    children: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    // insertion order, the front is evicted first
+    order: VecDeque<Vec<u8>>,
+    bytes: usize,
+    max_blocks: usize,
+    max_bytes: usize,
+}
+
+impl OrphanPool {
+    pub fn new(max_blocks: usize, max_bytes: usize) -> OrphanPool {
+        OrphanPool {
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_blocks: max_blocks,
+            max_bytes: max_bytes,
+        }
+    }
+
+    // false if the block is already in the pool or alone exceeds the memory bound
+    pub fn add(&mut self, block: Block, from: IpAddr) -> bool {
+        let size = Block::serialize(&block).len();
+        if self.blocks.contains_key(&block.hash) || size > self.max_bytes {
+            return false;
+        }
+        while !self.order.is_empty() &&
+            (self.blocks.len() >= self.max_blocks || self.bytes + size > self.max_bytes)
+        {
+            let oldest = self.order[0].clone();
+            self.remove(&oldest);
+        }
+
+        let hash = block.hash.clone();
+        self.children
+            .entry(block.header.prev_block_hash.clone())
+            .or_insert_with(Vec::new)
+            .push(hash.clone());
+        self.order.push_back(hash.clone());
+        self.bytes += size;
+        self.blocks.insert(
+            hash,
+            OrphanBlock {
+                block: block,
+                from: from,
+                size: size,
+            },
+        );
+        true
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    // the parent of the oldest orphan ancestor of hash, the block to request next
+    pub fn missing_parent(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        let mut orphan = self.blocks.get(hash)?;
+        while let Some(parent) = self.blocks.get(&orphan.block.header.prev_block_hash) {
+            orphan = parent;
+        }
+        Some(orphan.block.header.prev_block_hash.clone())
+    }
+
+    // removes and returns the orphans whose parent is the block with hash
+    pub fn take_children(&mut self, parent: &[u8]) -> Vec<OrphanBlock> {
+        let hashes = self.children.get(parent).cloned().unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn remove(&mut self, hash: &[u8]) -> Option<OrphanBlock> {
+        let orphan = self.blocks.remove(hash)?;
+        self.bytes -= orphan.size;
+        self.order.retain(|elem| !util::compare_slice_u8(elem, hash));
+        let parent = &orphan.block.header.prev_block_hash;
+        let empty = match self.children.get_mut(parent) {
+            Some(siblings) => {
+                siblings.retain(|elem| !util::compare_slice_u8(elem, hash));
+                siblings.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.children.remove(parent);
+        }
+        Some(orphan)
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new(MAX_ORPHAN_BLOCKS, MAX_ORPHAN_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::BlockHeader;
+    use std::net::Ipv4Addr;
+
+    fn peer() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    fn new_block(hash: u8, parent: u8) -> Block {
+        Block {
+            header: BlockHeader {
+                prev_block_hash: vec![parent],
+                ..BlockHeader::default()
+            },
+            transactions: vec![],
+            hash: vec![hash],
+            height: 0,
+        }
+    }
+
+    #[test]
+    fn children_of_parent() {
+        let mut pool = OrphanPool::default();
+        assert!(pool.add(new_block(2, 1), peer()));
+        assert!(pool.add(new_block(3, 2), peer()));
+        assert!(pool.add(new_block(4, 2), peer()));
+        assert!(!pool.add(new_block(4, 2), peer()));
+        // 3 and 4 wait for 2, 2 waits for 1
+        assert_eq!(pool.missing_parent(&[4]), Some(vec![1]));
+
+        let children = pool.take_children(&[1]);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].block.hash, vec![2]);
+        let mut hashes: Vec<Vec<u8>> =
+            pool.take_children(&[2]).into_iter().map(|orphan| orphan.block.hash).collect();
+        hashes.sort();
+        assert_eq!(hashes, vec![vec![3], vec![4]]);
+        assert!(pool.is_empty());
+        assert_eq!(pool.bytes(), 0);
+    }
+
+    #[test]
+    fn oldest_evicted_first() {
+        let size = Block::serialize(&new_block(1, 0)).len();
+        let mut pool = OrphanPool::new(2, size * 10);
+        (1..4).for_each(|hash| { pool.add(new_block(hash, 0), peer()); });
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&[1]));
+
+        // bounded by memory too
+        let mut pool = OrphanPool::new(10, size * 2);
+        (1..4).for_each(|hash| { pool.add(new_block(hash, 0), peer()); });
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.bytes(), size * 2);
+        assert!(pool.contains(&[3]));
+    }
+}
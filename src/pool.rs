@@ -77,44 +77,44 @@ pub fn put_job(data_arg: DataArg) {
     };
 
     pool.execute(move || {
-        let (addr, path, method, data, headers) = (
+        let result = request(
             &data_arg.addr,
             &data_arg.path,
             &data_arg.method,
-            &data_arg.data,
             &data_arg.headers,
+            &data_arg.data,
         );
-        let addr = format!("http://{}{}", addr, path);
-        //debug!(LOG, "addr => {}", &addr);
-        let mut evloop = Core::new().unwrap();
-
-        let req = if method.as_str() == "GET" {
-            get(&addr)
-        } else {
-            post(&addr)
-        };
-        let future = req.headers(headers.clone()).body(data.to_vec()).send(
-            evloop.handle(),
-        );
-        let result = evloop.run(future);
-        if result.is_err(){
-            error!(LOG, "{:?}", result.err());
-            return;    
-        }
-        let result = result.unwrap();
-        if result.is_success() {
-            let body = result.body();
-            (data_arg.call_back)(body.to_vec());
-        } else {
-            error!(
-                LOG,
-                "send get data fail, URI => {}",
-                addr,
-                );
+        match result {
+            Ok(body) => (data_arg.call_back)(body),
+            Err(e) => error!(LOG, "{}", e),
         }
    });
 }
 
+// blocking http request, returns the body of a successful response
+pub fn request(
+    addr: &str,
+    path: &str,
+    method: &str,
+    headers: &[(String, String)],
+    data: &[u8],
+) -> Result<Vec<u8>, String> {
+    let addr = format!("http://{}{}", addr, path);
+    //debug!(LOG, "addr => {}", &addr);
+    let mut evloop = Core::new().unwrap();
+
+    let req = if method == "GET" { get(&addr) } else { post(&addr) };
+    let future = req.headers(headers.to_vec()).body(data.to_vec()).send(
+        evloop.handle(),
+    );
+    let result = evloop.run(future).map_err(|e| format!("{:?}", e))?;
+    if result.is_success() {
+        Ok(result.body().to_vec())
+    } else {
+        Err(format!("send get data fail, URI => {}", addr))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate time;
@@ -6,85 +6,140 @@ extern crate slog_term;
 use self::bigint::U256;
 
 use super::block::*;
-use super::util;
-use super::log::*;
+use super::chain_params;
 
-use std::iter;
-
-// TODO
 lazy_static!{
-    static ref MAX_NONCE: isize =  1<<60;
+    pub static ref MAX_NONCE: isize =  1<<60;
 }
 
-//const TARGETBITS: usize = 16;
-const TARGETBITS: usize = 16;
+// the pow limit of the network the node runs
+pub fn pow_limit_bits() -> u32 {
+    chain_params::params().pow_limit_bits
+}
 
 pub struct ProofOfWork<'a> {
-    pub block: &'a Block,
+    pub header: &'a BlockHeader,
     pub target: U256,
 }
 
 impl<'a> ProofOfWork<'a> {
-    const TARGET_BITS: i64 = 16;
-    pub fn new_proof_of_work(b: &'a Block) -> ProofOfWork<'a> {
-        let target: U256 = 1.into();
-        // left move 240 bits
-        let target: U256 = target << (256 - TARGETBITS);
-
+    pub fn new_proof_of_work(header: &'a BlockHeader) -> ProofOfWork<'a> {
+        let target = compact_to_target(header.bits);
         ProofOfWork {
-            block: b,
+            header: header,
             target: target,
         }
     }
 
-    // TODO add debug infomation
-    pub fn run(&self) -> (isize, Vec<u8>) {
-        let mut nonce = 0;
-        let mut hash = vec![];
-        
-        for n in 0..*MAX_NONCE {
-            let data = self.prepare_data(n);
-            nonce = n;
-            hash = util::sha256(&data);
-            let hash_int: U256 = util::as_u256(&hash);
-            if hash_int < self.target {
-                break;
-            }
-        }
-        assert!(nonce != *MAX_NONCE);
-        (nonce, hash)
+    // the merkle root is already in the header, no need to hash the transactions per nonce
+    pub fn prepare_data(&self, nonce: isize) -> Vec<u8> {
+        self.header.prepare_data(nonce)
+    }
+}
+
+// bits = |exponent(1 byte)|mantissa(3 bytes)|, target = mantissa * 256^(exponent - 3)
+pub fn compact_to_target(bits: u32) -> U256 {
+    let size = (bits >> 24) as usize;
+    let word = U256::from((bits & 0x007f_ffff) as u64);
+    if size <= 3 {
+        word >> (8 * (3 - size))
+    } else {
+        word << (8 * (size - 3))
     }
+}
 
-    pub fn validate(&self) -> bool {
-        let hash_data = util::sha256(&self.prepare_data(self.block.nonce));
-        let hash_big = util::as_u256(&hash_data);
-        hash_big < self.target
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = (target.bits() + 7) / 8;
+    let mut compact = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (target >> (8 * (size - 3))).low_u64() as u32
+    };
+    // the mantissa is signed, keep the sign bit clear
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
     }
+    compact | ((size as u32) << 24)
+}
 
-    // |prevblock_hash|block_transaction_hash|timestamp|targetbits|nonce|
-    pub fn prepare_data(&self, nonce: isize) -> Vec<u8> {
-        let prev_block_hash = &self.block.prev_block_hash;
-        let prev_block_end = prev_block_hash.len();
-        let hash_transactions = &self.block.hash_transactions();
-        let hash_transactions_end = prev_block_end + hash_transactions.len();
-        let timestamp = &util::write_i32(self.block.timestamp);
-        let timestamp_end = hash_transactions_end + timestamp.len();
-        let target_bits = &util::write_i64(Self::TARGET_BITS);
-        let target_bits_end = timestamp_end + target_bits.len();
-        let nonce = &util::write_i64(nonce as i64);
-        let nonce_end = target_bits_end + nonce.len();
-
-        let buf_size = timestamp.len() + nonce.len() + target_bits.len() +
-            self.block.prev_block_hash.len() + hash_transactions.len();
-
-        let mut buf = Vec::with_capacity(buf_size);
-        buf.extend(iter::repeat(0).take(buf_size));
-        buf[..prev_block_end].clone_from_slice(prev_block_hash);
-        buf[prev_block_end..hash_transactions_end].clone_from_slice(hash_transactions);
-        buf[hash_transactions_end..timestamp_end].clone_from_slice(timestamp);
-        buf[timestamp_end..target_bits_end].clone_from_slice(target_bits);
-        buf[target_bits_end..nonce_end].clone_from_slice(nonce);
-
-        buf
+// expected number of hashes to find a block with the bits, 2^256 / (target + 1)
+pub fn block_work(bits: u32) -> U256 {
+    let target = compact_to_target(bits);
+    if target.is_zero() {
+        return U256::zero();
+    }
+    // 2^256 doesn't fit into U256, (2^256 - target - 1) / (target + 1) + 1 is the same
+    (!target / (target + U256::one())) + U256::one()
+}
+
+// like bitcoin, the actual timespan is clamped into [timespan/4, timespan*4], and the new
+// target never exceeds the pow limit
+pub fn calculate_next_bits(last_bits: u32, first_timestamp: i32, last_timestamp: i32) -> u32 {
+    let params = chain_params::params();
+    let timespan = params.target_timespan();
+    let mut actual_timespan = last_timestamp - first_timestamp;
+    if actual_timespan < timespan / 4 {
+        actual_timespan = timespan / 4;
+    }
+    if actual_timespan > timespan * 4 {
+        actual_timespan = timespan * 4;
+    }
+
+    let pow_limit = compact_to_target(params.pow_limit_bits);
+    let mut target = compact_to_target(last_bits);
+    target = target * U256::from(actual_timespan as u64) / U256::from(timespan as u64);
+    if target > pow_limit {
+        target = pow_limit;
+    }
+    target_to_compact(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chain_params::ChainParams;
+
+    const POW_LIMIT_BITS: u32 = 0x1f01_0000;
+
+    #[test]
+    fn compact_round_trip() {
+        let pow_limit: U256 = U256::from(1u64) << 240;
+        assert_eq!(compact_to_target(POW_LIMIT_BITS), pow_limit);
+        assert_eq!(target_to_compact(pow_limit), POW_LIMIT_BITS);
+        assert_eq!(ChainParams::main().pow_limit_bits, POW_LIMIT_BITS);
+        assert_eq!(target_to_compact(compact_to_target(0x1d00_ffff)), 0x1d00_ffff);
+    }
+
+    #[test]
+    fn retarget_is_clamped() {
+        let timespan = ChainParams::main().target_timespan();
+        let bits = 0x1e00_ffff;
+        let target = compact_to_target(bits);
+        // blocks came too fast, at most 4 times harder
+        let next = calculate_next_bits(bits, 0, 1);
+        assert_eq!(compact_to_target(next), target / U256::from(4u64));
+        // blocks came too slow, at most 4 times easier
+        let next = calculate_next_bits(bits, 0, timespan * 100);
+        assert_eq!(compact_to_target(next), target * U256::from(4u64));
+        // on schedule, unchanged
+        assert_eq!(calculate_next_bits(bits, 0, timespan), bits);
+        // never easier than the pow limit
+        assert_eq!(calculate_next_bits(POW_LIMIT_BITS, 0, timespan * 4), POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn regtest_pow_limit() {
+        let bits = ChainParams::regtest().pow_limit_bits;
+        assert_eq!(target_to_compact(compact_to_target(bits)), bits);
+        // about every second hash solves a block
+        assert_eq!(block_work(bits), U256::from(2u64));
+    }
+
+    #[test]
+    fn harder_bits_more_work() {
+        // 2^256 / (2^240 + 1) rounds down
+        assert_eq!(block_work(POW_LIMIT_BITS), U256::from((1u64 << 16) - 1));
+        assert!(block_work(0x1e00_ffff) > block_work(POW_LIMIT_BITS));
     }
 }
@@ -0,0 +1,125 @@
+use std::cmp;
+
+// the newest blocks always keep their transactions and undo data, reorganizations are never
+// deeper
+pub const MIN_BLOCKS_TO_KEEP: isize = 100;
+// the target is checked again once the best chain grew PRUNE_INTERVAL blocks, measuring the kept
+// blocks reads them. The chain keeps up to PRUNE_INTERVAL blocks more than the target
+pub const PRUNE_INTERVAL: isize = 50;
+const MEGABYTE: usize = 1024 * 1024;
+
+// how much of the best chain keeps its transactions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PruneTarget {
+    // the newest blocks
+    Blocks(isize),
+    // the newest blocks taking at most the megabytes
+    Megabytes(usize),
+}
+
+impl PruneTarget {
+    // "550MB" or "1000blocks", a plain number is a count of blocks
+    pub fn parse(target: &str) -> Result<PruneTarget, String> {
+        let lower = target.trim().to_lowercase();
+        let (number, megabytes) = if lower.ends_with("mb") {
+            (&lower[..lower.len() - "mb".len()], true)
+        } else if lower.ends_with("blocks") {
+            (&lower[..lower.len() - "blocks".len()], false)
+        } else {
+            (&lower[..], false)
+        };
+        let invalid = || format!("invalid prune target {}, use <n>MB or <n>blocks", target);
+        let number = number.trim().parse::<usize>().map_err(|_| invalid())?;
+        if number == 0 || (megabytes && number.checked_mul(MEGABYTE).is_none()) {
+            return Err(invalid());
+        }
+        if megabytes {
+            Ok(PruneTarget::Megabytes(number))
+        } else {
+            Ok(PruneTarget::Blocks(number as isize))
+        }
+    }
+}
+
+// the blocks below the returned height can be pruned. The blocks from pruned_height up to
+// best_height are stored, block_size gives the size of the block at a height
+pub fn prune_height<F>(
+    target: &PruneTarget,
+    best_height: isize,
+    pruned_height: isize,
+    block_size: F,
+) -> isize
+where
+    F: Fn(isize) -> usize,
+{
+    let height = match *target {
+        PruneTarget::Blocks(blocks) => best_height - blocks + 1,
+        PruneTarget::Megabytes(megabytes) => {
+            let (mut height, mut size) = (best_height, 0usize);
+            let max_size = megabytes.saturating_mul(MEGABYTE);
+            while height >= pruned_height {
+                size = size.saturating_add(block_size(height));
+                if size > max_size {
+                    break;
+                }
+                height -= 1;
+            }
+            height + 1
+        }
+    };
+    cmp::min(height, best_height - MIN_BLOCKS_TO_KEEP + 1)
+}
+
+// whether the target is checked at best_height, pruned_at is the best height of the last check.
+// A reorganization to a lower height starts over
+pub fn prune_due(pruned_at: Option<isize>, best_height: isize) -> bool {
+    pruned_at.map_or(true, |height| {
+        best_height < height || best_height >= height + PRUNE_INTERVAL
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_target() {
+        assert_eq!(PruneTarget::parse("550MB"), Ok(PruneTarget::Megabytes(550)));
+        assert_eq!(PruneTarget::parse("2mb"), Ok(PruneTarget::Megabytes(2)));
+        assert_eq!(PruneTarget::parse("1000blocks"), Ok(PruneTarget::Blocks(1000)));
+        assert_eq!(PruneTarget::parse("288"), Ok(PruneTarget::Blocks(288)));
+        assert!(PruneTarget::parse("0").is_err());
+        assert!(PruneTarget::parse("MB").is_err());
+        assert!(PruneTarget::parse("-5blocks").is_err());
+        assert!(PruneTarget::parse(&format!("{}MB", usize::max_value())).is_err());
+    }
+
+    #[test]
+    fn keeps_newest_blocks() {
+        let size = |_| 512 * 1024;
+        // 300 blocks of half a megabyte
+        let blocks = PruneTarget::Blocks(150);
+        assert_eq!(prune_height(&blocks, 299, 0, size), 150);
+        // never fewer than MIN_BLOCKS_TO_KEEP
+        let few = PruneTarget::Blocks(10);
+        assert_eq!(prune_height(&few, 299, 0, size), 300 - MIN_BLOCKS_TO_KEEP);
+        // 50 megabytes hold the newest 100 blocks, 60 the newest 120
+        let megabytes = PruneTarget::Megabytes(60);
+        assert_eq!(prune_height(&megabytes, 299, 0, size), 180);
+        // everything fits
+        let megabytes = PruneTarget::Megabytes(1000);
+        assert_eq!(prune_height(&megabytes, 299, 0, size), 0);
+        assert_eq!(prune_height(&megabytes, 299, 50, size), 50);
+        let huge = PruneTarget::Megabytes(usize::max_value());
+        assert_eq!(prune_height(&huge, 299, 0, size), 0);
+    }
+
+    #[test]
+    fn prune_every_interval() {
+        assert!(prune_due(None, 0));
+        assert!(!prune_due(Some(300), 300));
+        assert!(!prune_due(Some(300), 300 + PRUNE_INTERVAL - 1));
+        assert!(prune_due(Some(300), 300 + PRUNE_INTERVAL));
+        assert!(prune_due(Some(300), 299));
+    }
+}
@@ -1,35 +1,104 @@
 extern crate rocket;
 extern crate io_context;
 extern crate threadpool;
+extern crate bigint;
 
-use blockchain::{BLOCK_PREFIX, BlockChain};
+use self::bigint::U256;
+
+use blockchain::{BLOCK_PREFIX, MAX_BLOCKS, MAX_HEADERS, BlockChain, BlockStatus, ChainTip,
+                 ChainUpdate};
+use addr_index::{AddressOutput, AddressPage, AddressTx};
+use block_template::{self, BlockTemplate, MiningTemplate};
+use chain_params;
+use proof_of_work;
+use timedata;
 use server;
-use transaction::Transaction;
+use transaction::{block_subsidy, issued_supply, Transaction};
 use utxo_set;
 use util;
+use validation::{self, RejectReason};
+use verify;
 use wallet::Wallet;
 use block;
+use mine::Miner;
+use orphan::OrphanPool;
+use in_flight::InFlight;
+use prune::{self, PruneTarget};
 use log::*;
 
+use std::cmp;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+// the pub key hash of addr, None if it's not an address of this network
+fn address_key_hash(addr: &str) -> Option<Vec<u8>> {
+    if !Wallet::validate_address(addr.to_owned()) {
+        return None;
+    }
+    let pub_key_hash = util::decode_base58(addr.to_owned());
+    Some(pub_key_hash[1..(pub_key_hash.len() - 4)].to_vec())
+}
 
 pub struct BlockLock {
     bc: Arc<BlockChain>,
     utxos: Arc<utxo_set::UTXOSet>,
+    // old block bodies are dropped when set
+    prune: Option<PruneTarget>,
+    // the best height of the last prune, None until the first one
+    pruned_at: Mutex<Option<isize>>,
 }
 
 impl BlockLock {
-    fn new(bc: Arc<BlockChain>, utxos: Arc<utxo_set::UTXOSet>) -> BlockLock {
+    fn new(
+        bc: Arc<BlockChain>,
+        utxos: Arc<utxo_set::UTXOSet>,
+        prune: Option<PruneTarget>,
+    ) -> BlockLock {
         BlockLock {
             bc: bc,
             utxos: utxos,
+            prune: prune,
+            pruned_at: Mutex::new(None),
+        }
+    }
+
+    // the chain with its utxo set, recovered from a crash in the middle of a write
+    pub fn load(bc: BlockChain, prune: Option<PruneTarget>) -> Result<BlockLock, String> {
+        let bc = Arc::new(bc);
+        let utxos = utxo_set::UTXOSet::new(Arc::clone(&bc));
+        utxos.recover()?;
+        let bc_lock = BlockLock::new(bc, Arc::new(utxos), prune);
+        bc_lock.activate_best_chain();
+        bc_lock.prune();
+        Ok(bc_lock)
+    }
+
+    // drops the old block bodies the prune target doesn't keep, every PRUNE_INTERVAL blocks
+    pub fn prune(&self) {
+        if let Some(ref target) = self.prune {
+            let best_height = self.best_height();
+            {
+                let mut pruned_at = self.pruned_at.lock().unwrap();
+                if !prune::prune_due(*pruned_at, best_height) {
+                    return;
+                }
+                *pruned_at = Some(best_height);
+            }
+            if let Some(height) = self.bc.prune(target) {
+                info!(LOG, "pruned the blocks below height {}", height);
+            }
         }
     }
 
+    // the best chain's blocks below the height can't be served
+    pub fn pruned_height(&self) -> isize {
+        self.bc.pruned_height()
+    }
+
     pub fn test_block_hashes(&self) -> Vec<String> {
         let db = &self.bc.db;
         let res = db.get_all_with_prefix(*BLOCK_PREFIX);
@@ -44,18 +113,16 @@ impl BlockLock {
         )
     }
 
-    pub fn tx(&self, txid: &str) -> Option<(String, isize, Transaction)> {
+    pub fn tx(&self, txid: &str) -> Result<(String, isize, Transaction), String> {
         let best_height = self.best_height();
-        let block_iter = self.bc.iter();
-        for block in block_iter {
-            for ts in &block.transactions {
-                if util::encode_hex(&ts.id) == txid {
-                    let confirm = best_height - block.height;
-                    return Some((util::encode_hex(block.hash), confirm, ts.clone()));
-                }
-            }
-        }
-        None
+        let id = util::try_decode_hex(txid)
+            .ok_or_else(|| format!("{} is not a transaction id", txid))?;
+        let (block, position) = self.bc
+            .find_transaction_block(&id)?
+            .ok_or_else(|| format!("{} not found", txid))?;
+        let confirm = best_height - block.height;
+        let ts = block.transactions[position].clone();
+        Ok((util::encode_hex(block.hash), confirm, ts))
     }
 
     pub fn block_hashes(&self) -> Vec<String> {
@@ -68,6 +135,7 @@ impl BlockLock {
         from_wallet: &Wallet,
         to: &str,
         amount: isize,
+        fee: isize,
         spend_utxos: Option<HashMap<String, Vec<isize>>>,
     ) -> Result<Transaction, String> {
         let utxos = &self.utxos;
@@ -75,96 +143,196 @@ impl BlockLock {
             from_wallet,
             to.to_owned(),
             amount,
+            fee,
             utxos,
             spend_utxos,
         );
         tx.map_err(|e| format!("{:?}", e))
     }
 
-    pub fn add_new_block(
-        &self,
-        new_block: &block::Block,
-        from_central_node: bool,
-    ) -> Result<bool, String> {
+    pub fn add_new_block(&self, new_block: &block::Block) -> Result<ChainUpdate, RejectReason> {
         let block_hash = &new_block.hash;
         if self.bc.get_block(block_hash).is_some() {
-            return Ok(true);
+            return Ok(ChainUpdate::Exists);
         }
 
-        if from_central_node {
-            warn!(
-                LOG,
-                "may be delete fork block, hash: {}, height: {}",
-                util::encode_hex(block_hash),
-                new_block.height
-            );
-            // clear conflict blocks
-            if let Some(hashes) = self.bc.delete_blocks(&new_block.hash, new_block.height) {
-                self.utxos.reindex();
-                hashes.iter().for_each(|hash| {
-                    debug!(LOG, "conflict block: {}", util::encode_hex(&hash))
-                });
+        // the inputs can only be checked against the utxo set of the parent
+        if util::compare_slice_u8(&new_block.header.prev_block_hash, &self.bc.get_tip()) {
+            validation::check_block(new_block)?;
+            validation::check_block_transactions(new_block, &self.utxos)?;
+        }
+
+        let update = self.bc.add_block(new_block)?;
+        match update {
+            ChainUpdate::Extended => {
+                self.connect(new_block);
+                self.prune();
+            }
+            ChainUpdate::Reorganize { ref fork } => {
+                self.reorganize(fork, new_block)?;
+                self.prune();
+            }
+            ChainUpdate::SideBranch => {
+                info!(
+                    LOG,
+                    "block {} stored on a side branch, height: {}",
+                    util::encode_hex(block_hash),
+                    new_block.height
+                );
             }
+            ChainUpdate::Exists => {}
         }
+        Ok(update)
+    }
 
-        // TODO check new block
-        let verify = new_block.transactions.iter().all(|ts| {
-            // transactions' input should in utxo
-            if ts.is_coinbase() {
-                return true;
+    // switch the best chain to the branch from fork to new_block, which has more work. The
+    // blocks of the old branch are disconnected with their undo data, a reorg of depth k
+    // touches k blocks
+    fn reorganize(&self, fork: &[u8], new_block: &block::Block) -> Result<(), RejectReason> {
+        let old_tip = self.bc.get_tip();
+        let old_branch = self.bc.branch(fork, &old_tip);
+        let branch = self.bc.branch(fork, &new_block.hash);
+        warn!(
+            LOG,
+            "reorganize chain, fork: {}, old tip: {}, new tip: {}, disconnect {} blocks, \
+             connect {} blocks",
+            util::encode_hex(fork),
+            util::encode_hex(&old_tip),
+            util::encode_hex(&new_block.hash),
+            old_branch.len(),
+            branch.len()
+        );
+
+        self.disconnect_to(&old_branch, fork).map_err(|e| {
+            error!(LOG, "reorganize faild, {}, stay at the old tip", &e);
+            RejectReason::ReorganizeFailed { reason: e }
+        })?;
+        for (idx, block) in branch.iter().enumerate() {
+            if let Err(e) = validation::check_block_transactions(block, &self.utxos) {
+                error!(
+                    LOG,
+                    "reorganize faild at block {}, err: {}, back to the old tip",
+                    util::encode_hex(&block.hash),
+                    &e
+                );
+                branch[idx..].iter().for_each(|block| {
+                    self.bc.set_block_status(&block.hash, BlockStatus::Invalid);
+                });
+                if let Err(e) = self.disconnect_to(&branch[..idx], fork) {
+                    error!(LOG, "can't go back to the old tip, {}", &e);
+                    return Err(RejectReason::ReorganizeFailed { reason: e });
+                }
+                old_branch.iter().for_each(|block| self.connect(block));
+                return Err(e);
             }
+            self.connect(block);
+        }
+        Ok(())
+    }
 
-            debug!(
-                LOG,
-                "check transaction, txid: {}, block: {}",
-                util::encode_hex(&ts.id),
-                util::encode_hex(&new_block.hash)
-            );
-            ts.vin.iter().all(|vin| {
-                let oupts = self.utxos.utxo(&vin.txid);
-                oupts.map_or_else(
-                    || {
-                        debug!(
-                            LOG,
-                            "transaction not found, txid: {}",
-                            util::encode_hex(&vin.txid)
-                        );
-                        false
-                    },
-                    |outputs| {
-                        let out = outputs.outputs.get(&vin.vout);
-                        if out.is_none() {
-                            debug!(
-                                LOG,
-                                "out:{} not found, txid: {}",
-                                &vin.vout,
-                                util::encode_hex(&vin.txid)
-                            );
-                            debug!(LOG, "outinfo: {:?}", out);
-                            return false;
-                        }
-                        true
-                    },
-                )
-            })
-        });
-        if !verify {
-            return Err("block is invalid".to_string());
+    // moves the tip to the block and applies it to the utxo set
+    fn connect(&self, block: &block::Block) {
+        self.bc.connect_block(block, &self.utxos);
+    }
+
+    pub fn chain_tips(&self) -> Vec<ChainTip> {
+        self.bc.chain_tips()
+    }
+
+    pub fn verify_chain(&self, level: usize, depth: isize) -> verify::VerifyReport {
+        verify::verify_chain(&self.bc, &self.utxos, level, depth)
+    }
+
+    // a crash after a block was stored but before it was connected, or in the middle of a
+    // reorganization, leaves the tip behind a valid branch with more work, it's connected on
+    // startup
+    pub fn activate_best_chain(&self) {
+        let tip = self.bc.get_tip();
+        let best = self.bc
+            .chain_tips()
+            .into_iter()
+            .filter(|tip| tip.status == "valid-fork" || tip.status == "valid-headers")
+            .map(|tip| util::decode_hex(&tip.hash))
+            .max_by_key(|hash| self.bc.chain_work(hash));
+        let best = match best.and_then(|hash| self.bc.get_block(&hash)) {
+            Some(best) => best,
+            None => return,
+        };
+        if self.bc.chain_work(&best.hash) <= self.bc.chain_work(&tip) {
+            return;
+        }
+        let fork = match self.bc.find_fork(&tip, &best.hash) {
+            Some(fork) => fork,
+            None => return,
+        };
+        warn!(LOG, "the tip is behind branch {}, connect it", util::encode_hex(&best.hash));
+        if let Err(e) = self.reorganize(&fork, &best) {
+            error!(LOG, "connect branch {} fail, {}", util::encode_hex(&best.hash), e);
         }
-        self.bc.add_block(new_block).map(|_| false)
     }
 
+    // disconnects the connected blocks after fork, newest first, and moves the tip to fork.
+    // Blocks connected without undo data are only undone by a full reindex, a pruned chain
+    // can't be reindexed so the disconnected blocks are connected again and the tip stays
+    fn disconnect_to(&self, blocks: &[block::Block], fork: &[u8]) -> Result<(), String> {
+        for (idx, block) in blocks.iter().enumerate().rev() {
+            if let Err(e) = self.bc.disconnect_block(block, &self.utxos) {
+                if self.bc.pruned_height() > 0 {
+                    blocks[idx + 1..].iter().for_each(|block| self.connect(block));
+                    return Err(format!("{}, can't reindex a pruned chain", e));
+                }
+                warn!(LOG, "{}, reindex the utxo set", e);
+                self.bc.set_tip(fork);
+                return self.utxos.reindex();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_block(&self, hash: &[u8]) -> bool {
+        self.bc.get_block(hash).is_some()
+    }
+
+    // None if hash isn't hex or the block isn't stored
     pub fn block(&self, hash: &str) -> Option<block::Block> {
-        self.bc.get_block(&util::decode_hex(hash))
+        self.bc.get_block(&util::try_decode_hex(hash)?)
     }
+
+    pub fn header(&self, hash: &str) -> Option<block::BlockHeader> {
+        self.bc.get_header(&util::try_decode_hex(hash)?)
+    }
+
+    // headers of the best chain after the block `from`, None if from isn't hex
+    pub fn headers_after(&self, from: &str) -> Option<Vec<block::BlockHeader>> {
+        let from = util::try_decode_hex(from)?;
+        Some(self.bc.headers_after(&from, MAX_HEADERS))
+    }
+
     pub fn block_with_height(&self, height: isize) -> Option<block::Block> {
-        let block_iter = self.bc.iter();
-        for block in block_iter {
-            if block.height == height {
-                return Some(block);
-            }
+        self.bc.block_at(height)
+    }
+
+    // the best chain's blocks from height from, at most count and MAX_BLOCKS
+    pub fn blocks(&self, from: isize, count: usize) -> Result<Vec<block::Block>, String> {
+        if from < 0 {
+            return Err(format!("bad height {}", from));
         }
-        None
+        let pruned_height = self.bc.pruned_height();
+        if from < pruned_height {
+            return Err(format!("the blocks below height {} are pruned", pruned_height));
+        }
+        let count = cmp::min(count, MAX_BLOCKS) as isize;
+        Ok(self.bc.blocks(from..from.saturating_add(count)).collect())
+    }
+
+    // hashes of the best chain's blocks this node can send, the newest first
+    pub fn served_block_hashes(&self) -> Vec<String> {
+        let pruned_height = self.bc.pruned_height();
+        self.bc
+            .iter()
+            .take_while(|block| block.height >= pruned_height)
+            .map(|block| util::encode_hex(&block.hash))
+            .collect()
     }
 
     pub fn download_blocks(&self) -> Vec<block::Block> {
@@ -175,46 +343,14 @@ impl BlockLock {
         self.bc.get_best_height()
     }
 
-    pub fn conflict(&self, remote_hashes: &[Vec<u8>]) {
-        let height = self.best_height();
-        if height + 1 >= remote_hashes.len() as isize {
-            return;
-        }
-        let mut idx = (height + 1) as usize;
-        let remote_hashes_len = remote_hashes.len();
-        let block_iter = self.bc.iter();
-        let mut prev_hash = vec![];
-        let mut hash = None;
-        for block in block_iter {
-            let index = remote_hashes_len - idx;
-            if !util::compare_slice_u8(&block.hash, &remote_hashes[index]) {
-                prev_hash = block.prev_block_hash;
-                hash = Some((block.height, block.hash));
-                break;
-            }
-            idx -= 1;
-        }
-        if let Some((height, hash)) = hash {
-            warn!(
-                LOG,
-                "conflict block:{}, height:{}",
-                util::encode_hex(hash),
-                height
-            );
-            // delete conflict block
-            self.bc.delete_conflict(height, prev_hash);
-            self.utxos.reindex();
-        }
+    pub fn best_work(&self) -> U256 {
+        self.bc.get_best_work()
     }
 
     pub fn balance(&self, addr: &str) -> HashMap<String, String> {
-        let mut balance = 0;
         let pub_key_hash = util::decode_base58(addr.to_owned());
         let pub_key_hash = &pub_key_hash[1..(pub_key_hash.len() - 4)];
-        let utxos = self.utxos.find_utxo(pub_key_hash);
-        for out in utxos {
-            balance += out.value;
-        }
+        let (balance, immature) = self.utxos.balance(pub_key_hash);
         let mut res: HashMap<String, String> = HashMap::new();
         res.entry("addr".to_owned()).or_insert_with(
             || addr.to_string(),
@@ -222,9 +358,42 @@ impl BlockLock {
         res.entry("balance".to_owned()).or_insert_with(
             || balance.to_string(),
         );
+        res.entry("immature".to_owned()).or_insert_with(
+            || immature.to_string(),
+        );
         res
     }
 
+    // mempool acceptance of a transaction relayed by other nodes
+    pub fn check_mempool_tx(&self, tx: &Transaction) -> Result<(), RejectReason> {
+        validation::check_maturity(tx, &self.utxos, self.bc.get_best_height() + 1)
+    }
+
+    // coins issued by the schedule and the coins actually spendable at the tip
+    pub fn supply(&self) -> HashMap<String, isize> {
+        let height = self.bc.get_best_height();
+        let mut supply = HashMap::new();
+        supply.insert("height".to_owned(), height);
+        supply.insert("issued".to_owned(), issued_supply(height));
+        supply.insert("circulating".to_owned(), self.utxos.total_value());
+        supply.insert("max_supply".to_owned(), chain_params::params().max_supply);
+        supply
+    }
+
+    // a page of the unspent outputs of addr, None if addr is invalid
+    pub fn address_utxos(&self, addr: &str, page: usize) -> Option<AddressPage<AddressOutput>> {
+        let pub_key_hash = address_key_hash(addr)?;
+        let (total, utxos) = self.utxos.address_outputs_page(&pub_key_hash, page);
+        Some(AddressPage::new(addr, total, utxos, page))
+    }
+
+    // a page of the confirmed transactions of addr, the newest first
+    pub fn address_txs(&self, addr: &str, page: usize) -> Option<AddressPage<AddressTx>> {
+        let pub_key_hash = address_key_hash(addr)?;
+        let (total, txs) = self.utxos.address_txs(&pub_key_hash, page);
+        Some(AddressPage::new(addr, total, txs, page))
+    }
+
     pub fn unspend_utxo(&self) -> Vec<String> {
         let utxos = self.bc.db.get_all_with_prefix("utxo-");
         utxos
@@ -233,30 +402,125 @@ impl BlockLock {
             .collect()
     }
 
+    // the mempool transactions for the block at height, ordered by fee rate
+    pub fn block_template(
+        &self,
+        mem_pool: &HashMap<String, Transaction>,
+        height: isize,
+    ) -> BlockTemplate {
+        let utxos = &self.utxos;
+        let lookup = |txid: &[u8], vout: isize| {
+            utxos.utxo(txid).and_then(|outs| if outs.is_mature(height) {
+                outs.outputs.get(&vout).cloned()
+            } else {
+                None
+            })
+        };
+
+        // inputs may spend the utxo set or other mempool transactions
+        let candidates: HashMap<String, Transaction> = mem_pool
+            .iter()
+            .filter(|&(_, tx)| {
+                let prev_outs = tx.vin
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, vin)| {
+                        mem_pool
+                            .get(&util::encode_hex(&vin.txid))
+                            .and_then(|parent| parent.vout.get(vin.vout as usize).cloned())
+                            .or_else(|| lookup(&vin.txid, vin.vout))
+                            .map(|out| (idx as isize, out))
+                    })
+                    .collect();
+                tx.verify(&prev_outs)
+            })
+            .map(|(txid, tx)| (txid.clone(), tx.clone()))
+            .collect();
+        block_template::build_template(
+            &candidates,
+            chain_params::params().max_block_size - block_template::COINBASE_RESERVED_SIZE,
+            lookup,
+        )
+    }
+
+    // the next block for external miners, paying to address
+    pub fn mining_template(
+        &self,
+        mem_pool: &HashMap<String, Transaction>,
+        address: &str,
+    ) -> MiningTemplate {
+        let tip = self.bc.get_block(&self.bc.get_tip()).unwrap();
+        let height = tip.height + 1;
+        let template = self.block_template(mem_pool, height);
+        let bits = self.bc.next_bits(&tip);
+        let target = proof_of_work::compact_to_target(bits);
+        MiningTemplate {
+            version: block::BLOCK_VERSION,
+            height: height,
+            prev_block_hash: util::encode_hex(&tip.hash),
+            timestamp: self.bc.next_block_time(&tip, timedata::adjusted_time() as i32),
+            min_timestamp: self.bc.median_time_past(&tip) + 1,
+            bits: bits,
+            target: util::encode_hex(&util::u256_to_vec(&target)),
+            coinbase_address: address.to_owned(),
+            coinbase_value: block_subsidy(height) + template.fees,
+            transactions: template.transactions,
+        }
+    }
+
+    // mines n blocks on the tip right away and connects them, the first ones take the
+    // mempool transactions
+    pub fn generate(
+        &self,
+        n: usize,
+        mine_addr: &str,
+        mem_pool: &HashMap<String, Transaction>,
+    ) -> Result<Vec<block::Block>, String> {
+        let miner = Miner::new(1);
+        let mut mem_pool = mem_pool.clone();
+        let mut blocks = vec![];
+        for _ in 0..n {
+            let height = self.bc.get_best_height() + 1;
+            let template = self.block_template(&mem_pool, height);
+            let reward = block_subsidy(height) + template.fees;
+            let cbtx = Transaction::new_coinbase_tx(mine_addr.to_owned(), "".to_owned(), reward);
+            let mut txs = template.transactions;
+            txs.insert(0, cbtx);
+
+            let new_block = self.bc.mine_block2(&txs, &miner)?;
+            self.add_new_block(&new_block).map_err(|e| e.to_string())?;
+            new_block.transactions.iter().for_each(|tx| {
+                mem_pool.remove(&util::encode_hex(&tx.id));
+            });
+            blocks.push(new_block);
+        }
+        Ok(blocks)
+    }
+
     // TODO Opz mining step
     pub fn mine_new_block2(
         &self,
         mine_addr: String,
         mem_pool: &HashMap<String, Transaction>,
+        miner: Arc<Miner>,
     ) -> Result<Receiver<block::Block>, String> {
-        let mut txs = vec![];
-        let cbtx = Transaction::new_coinbase_tx(mine_addr, "".to_owned());
-        txs.push(cbtx);
-        for ts in mem_pool.values() {
-            if self.bc.verify_transaction(ts) {
-                txs.push(ts.clone());
-            }
-        }
-        if txs.len() <= 1 {
+        let height = self.bc.get_best_height() + 1;
+        let template = self.block_template(mem_pool, height);
+        if template.transactions.is_empty() {
             return Err("no transactions".to_string());
         }
+        let reward = block_subsidy(height) + template.fees;
+        let cbtx = Transaction::new_coinbase_tx(mine_addr, "".to_owned(), reward);
+        let mut txs = template.transactions;
+        txs.insert(0, cbtx);
 
         // start mine thread backend
+        miner.new_job(height);
         let bc = Arc::clone(&self.bc);
         let (send, recv) = channel();
 
         thread::spawn(move || {
-            let new_block = bc.mine_block2(&txs);
+            let new_block = bc.mine_block2(&txs, &miner);
             if new_block.is_err() {
                 error!(
                     LOG,
@@ -278,10 +542,6 @@ impl BlockLock {
         Ok(recv)
     }
 
-    pub fn update_utxo(&self, new_block: &block::Block) {
-        self.utxos.update(new_block);
-    }
-
     pub fn block_chain(&self) -> Arc<BlockChain> {
         Arc::clone(&self.bc)
     }
@@ -291,10 +551,17 @@ pub struct BlockState {
     pub bc: Arc<Mutex<BlockLock>>,
     pub known_nodes: Arc<Mutex<Vec<String>>>,
     pub mining_address: Arc<String>,
-    pub block_in_transit: Arc<Mutex<Vec<Vec<u8>>>>,
+    // blocks waiting for their parents
+    pub orphans: Arc<Mutex<OrphanPool>>,
+    // blocks requested from peers and not received yet
+    pub in_flight: Arc<Mutex<InFlight>>,
     pub mem_pool: Arc<Mutex<HashMap<String, Transaction>>>,
     pub run_mining: Arc<AtomicBool>,
+    pub miner: Arc<Miner>,
     pub local_node: Arc<String>,
+    // addresses of the peers that sent blocks conflicting with the checkpoints, the address a
+    // peer declares in its messages can't be trusted
+    pub banned_nodes: Arc<Mutex<HashSet<IpAddr>>>,
 }
 
 impl BlockState {
@@ -303,29 +570,53 @@ impl BlockState {
         local_node: String,
         central_node: &str,
         mining_address: String,
-    ) -> BlockState {
-
-        let bc = Arc::new(bc);
-        let utxos = utxo_set::UTXOSet::new(Arc::clone(&bc));
-        utxos.reindex();
+        mining_threads: usize,
+        prune: Option<PruneTarget>,
+    ) -> Result<BlockState, String> {
 
         let mut known_nodes = vec![central_node.to_string()];
         if known_nodes[0] != local_node.clone() {
             known_nodes.push(local_node.clone());
         }
 
-        let bc_lock = BlockLock::new(Arc::clone(&bc), Arc::new(utxos));
+        let bc_lock = BlockLock::load(bc, prune)?;
 
-        BlockState {
+        Ok(BlockState {
             bc: Arc::new(Mutex::new(bc_lock)),
             known_nodes: Arc::new(Mutex::new(known_nodes)),
             mining_address: Arc::new(mining_address),
-            block_in_transit: Arc::new(Mutex::new(vec![])),
+            orphans: Arc::new(Mutex::new(OrphanPool::default())),
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
             mem_pool: Arc::new(Mutex::new(HashMap::new())),
             run_mining: Arc::new(AtomicBool::new(false)),
+            miner: Arc::new(Miner::new(mining_threads)),
             local_node: Arc::new(local_node),
+            banned_nodes: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    // forget the peers at the address and ignore everything they send from now on
+    pub fn ban_node(&self, remote: IpAddr) {
+        if node_ip(&self.local_node) == Some(remote) {
+            return;
         }
+        self.banned_nodes.lock().unwrap().insert(remote);
+        self.known_nodes.lock().unwrap().retain(|known| node_ip(known) != Some(remote));
     }
+
+    pub fn is_banned(&self, remote: IpAddr) -> bool {
+        self.banned_nodes.lock().unwrap().contains(&remote)
+    }
+
+    // a node announced by a peer, the ones at a banned address aren't connected
+    pub fn is_banned_node(&self, node: &str) -> bool {
+        node_ip(node).map_or(false, |ip| self.is_banned(ip))
+    }
+}
+
+// the ip of a node address like 127.0.0.1:3001, None for a host name
+fn node_ip(node: &str) -> Option<IpAddr> {
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
 }
 
 pub fn init_router(addr: &str, port: u16, block_chain: BlockState) {
@@ -338,6 +629,11 @@ pub fn init_router(addr: &str, port: u16, block_chain: BlockState) {
         .mount("/", routes![server::index])
         .mount("/", routes![server::handle_node_list])
         .mount("/", routes![server::handle_mempool_list])
+        .mount("/", routes![server::handle_mining_info])
+        .mount("/", routes![server::handle_mining_template])
+        .mount("/", routes![server::handle_mining_submit])
+        .mount("/", routes![server::handle_generate])
+        .mount("/", routes![server::handle_generate_to])
         .mount("/", routes![server::handle_list_block])
         .mount("/", routes![server::handle_addr])
         .mount("/", routes![server::handle_get_blocks])
@@ -350,8 +646,16 @@ pub fn init_router(addr: &str, port: u16, block_chain: BlockState) {
         .mount("/", routes![server::handle_valid_pubkey])
         .mount("/", routes![server::handle_transfer])
         .mount("/", routes![server::handle_balance])
+        .mount("/", routes![server::handle_address_utxos])
+        .mount("/", routes![server::handle_address_txs])
         .mount("/", routes![server::handle_unspend_utxos])
+        .mount("/", routes![server::handle_supply])
+        .mount("/", routes![server::handle_chain_tips])
+        .mount("/", routes![server::handle_verify_chain])
         .mount("/", routes![server::handle_info_block])
+        .mount("/", routes![server::handle_info_header])
+        .mount("/", routes![server::handle_headers])
+        .mount("/", routes![server::handle_blocks])
         .mount("/", routes![server::handle_tx_info])
         .mount("/", routes![server::handle_get_heigt_block_data])
         .mount("/", routes![server::handle_test_list_block])
@@ -360,3 +664,247 @@ pub fn init_router(addr: &str, port: u16, block_chain: BlockState) {
         .mount("/", routes![server::handle_test_download_blocks])
         .launch();
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use self::tempdir::TempDir;
+    use blockchain::tests::{child, coinbase, new_chain};
+    use chain_params::Checkpoint;
+    use consensus;
+
+    #[test]
+    fn reorganize_utxo_set() {
+        let dir = TempDir::new("reorganize").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = bc_lock.bc.get_block(&bc_lock.bc.get_tip()).unwrap();
+
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        assert_eq!(bc_lock.add_new_block(&a1), Ok(ChainUpdate::Extended));
+        let b1 = child(&genesis, vec![coinbase(1, 2)]);
+        assert_eq!(bc_lock.add_new_block(&b1), Ok(ChainUpdate::SideBranch));
+        let b2 = child(&b1, vec![coinbase(2, 2)]);
+        assert_eq!(
+            bc_lock.add_new_block(&b2),
+            Ok(ChainUpdate::Reorganize { fork: genesis.hash.clone() })
+        );
+
+        assert_eq!(bc_lock.bc.get_tip(), b2.hash);
+        let report = bc_lock.verify_chain(verify::MAX_VERIFY_LEVEL, 0);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let utxos = bc_lock.bc.db.get_all_with_prefix(utxo_set::UTXO_BLOCK_PREFIX);
+        assert!(utxos.iter().all(|(txid, _)| *txid != a1.transactions[0].id));
+        assert_eq!(utxos.len(), 3);
+    }
+
+    #[test]
+    fn fork_before_checkpoint() {
+        let dir = TempDir::new("checkpoint_fork").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = bc_lock.bc.get_block(&bc_lock.bc.get_tip()).unwrap();
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        let a2 = child(&a1, vec![coinbase(2, 1)]);
+        // a branch stored before the checkpoints were configured
+        let b1 = child(&genesis, vec![coinbase(1, 2)]);
+        let b2 = child(&b1, vec![coinbase(2, 2)]);
+        for block in &[&a1, &a2, &b1, &b2] {
+            assert!(bc_lock.add_new_block(block).is_ok());
+        }
+        assert_eq!(bc_lock.bc.get_tip(), a2.hash);
+
+        let mut params = chain_params::params();
+        params.checkpoints = vec![Checkpoint { height: 2, hash: util::encode_hex(&a2.hash) }];
+        chain_params::tests::set_thread_params(params);
+
+        let c1 = child(&genesis, vec![coinbase(1, 3)]);
+        assert_eq!(
+            bc_lock.add_new_block(&c1),
+            Err(RejectReason::ForkBeforeCheckpoint { height: 1, checkpoint: 2 })
+        );
+        // b3 gives the old branch more work, the reorganization would undo the checkpoint
+        let b3 = child(&b2, vec![coinbase(3, 2)]);
+        assert_eq!(
+            bc_lock.add_new_block(&b3),
+            Err(RejectReason::ForkBeforeCheckpoint { height: 0, checkpoint: 2 })
+        );
+        assert!(bc_lock.bc.get_block(&b3.hash).is_none());
+        assert_eq!(bc_lock.bc.get_tip(), a2.hash);
+
+        let a3 = child(&a2, vec![coinbase(3, 1)]);
+        assert_eq!(bc_lock.add_new_block(&a3), Ok(ChainUpdate::Extended));
+    }
+
+    #[test]
+    fn chain_tips_status() {
+        let dir = TempDir::new("chain_tips").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = bc_lock.bc.get_block(&bc_lock.bc.get_tip()).unwrap();
+        // a1 is connected, then b2 reorganizes it away
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        let b1 = child(&genesis, vec![coinbase(1, 2)]);
+        let b2 = child(&b1, vec![coinbase(2, 2)]);
+        // stored on a side branch, never connected
+        let c2 = child(&b1, vec![coinbase(2, 3)]);
+        // marked invalid like a failed reorganization does
+        let d1 = child(&genesis, vec![coinbase(1, 4)]);
+        let d2 = child(&d1, vec![coinbase(2, 4)]);
+        for block in &[&a1, &b1, &b2, &c2, &d1, &d2] {
+            assert!(bc_lock.add_new_block(block).is_ok());
+        }
+        for block in &[&d1, &d2] {
+            bc_lock.bc.set_block_status(&block.hash, BlockStatus::Invalid);
+        }
+        let d3 = child(&d2, vec![coinbase(3, 4)]);
+        assert_eq!(
+            bc_lock.add_new_block(&d3),
+            Err(RejectReason::InvalidParent { hash: util::encode_hex(&d2.hash) })
+        );
+
+        let tips = bc_lock.chain_tips();
+        let tip = |block: &block::Block| {
+            let hash = util::encode_hex(&block.hash);
+            let tip = tips.iter().find(|tip| tip.hash == hash).unwrap();
+            (tip.status.clone(), tip.branch_len)
+        };
+        assert_eq!(tips.len(), 4);
+        assert_eq!(tips[0].hash, util::encode_hex(&b2.hash));
+        assert_eq!(tip(&b2), ("active".to_owned(), 0));
+        assert_eq!(tip(&a1), ("valid-fork".to_owned(), 1));
+        assert_eq!(tip(&c2), ("valid-headers".to_owned(), 1));
+        assert_eq!(tip(&d2), ("invalid".to_owned(), 2));
+        // the others by height
+        assert!(tips.windows(2).skip(1).all(|pair| pair[0].height >= pair[1].height));
+        assert_eq!(tips[3].hash, util::encode_hex(&a1.hash));
+    }
+
+    #[test]
+    fn transfer_pays_fee_to_miner() {
+        let dir = TempDir::new("transfer_fee").unwrap();
+        let wallet = Wallet::new();
+        let pub_key = util::public_key_to_vec(&wallet.public_key, false);
+        let mut genesis_coinbase = coinbase(0, 0);
+        genesis_coinbase.vout[0].pub_key_hash = Wallet::hash_pubkey(&pub_key);
+        genesis_coinbase.id = genesis_coinbase.hash();
+        let acc = genesis_coinbase.vout[0].value;
+        let genesis = block::Block::new_genesis_block(genesis_coinbase);
+        let bc = BlockChain::from_genesis(genesis, dir.path().to_str().unwrap().to_owned());
+        let bc_lock = BlockLock::load(bc, None).unwrap();
+
+        let to = Wallet::new().get_address();
+        let max = isize::max_value();
+        assert!(bc_lock.create_new_utxo_transaction(&wallet, &to, max, 1, None).is_err());
+        let tx = bc_lock.create_new_utxo_transaction(&wallet, &to, 3, 2, None).unwrap();
+        assert_eq!(tx.vout.len(), 2);
+        assert_eq!(tx.vout[0].value, 3);
+        assert_eq!(tx.vout[1].value, acc - 3 - 2);
+        assert_eq!(tx.vout[1].pub_key_hash, Wallet::hash_pubkey(&pub_key));
+
+        let mem_pool: HashMap<String, Transaction> =
+            vec![(util::encode_hex(&tx.id), tx.clone())].into_iter().collect();
+        let template = bc_lock.mining_template(&mem_pool, &Wallet::new().get_address());
+        assert_eq!(template.coinbase_value, block_subsidy(1) + 2);
+        let mut block = template.new_block(0);
+        assert!(consensus::engine().seal(&mut block, &Miner::new(1)));
+        assert_eq!(bc_lock.add_new_block(&block), Ok(ChainUpdate::Extended));
+        let reward: isize = block.transactions[0].vout.iter().map(|out| out.value).sum();
+        assert_eq!(reward, block_subsidy(1) + 2);
+        assert_eq!(block.transactions[1].id, tx.id);
+    }
+
+    #[test]
+    fn mined_template_connects() {
+        let dir = TempDir::new("mined_template").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let template = bc_lock.mining_template(&HashMap::new(), &Wallet::new().get_address());
+        assert_eq!(template.height, 1);
+        // the next extranonce once the nonces are exhausted
+        let mut block = template.new_block(1);
+        assert!(consensus::engine().seal(&mut block, &Miner::new(1)));
+        assert_eq!(bc_lock.add_new_block(&block), Ok(ChainUpdate::Extended));
+        assert_eq!(bc_lock.bc.get_tip(), block.hash);
+    }
+
+    // the chain in dir opened again, like a restart
+    fn reopen(dir: &TempDir) -> BlockLock {
+        let bc = BlockChain::new_blockchain(dir.path().to_str().unwrap().to_owned());
+        BlockLock::load(bc, None).unwrap()
+    }
+
+    fn assert_recovered(bc_lock: &BlockLock, tip: &[u8]) {
+        assert_eq!(bc_lock.bc.get_tip(), tip);
+        assert_eq!(bc_lock.utxos.best_block(), Some(tip.to_vec()));
+        let report = bc_lock.verify_chain(verify::MAX_VERIFY_LEVEL, 0);
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn recover_unconnected_block() {
+        let dir = TempDir::new("recover_unconnected").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = bc_lock.bc.get_block(&bc_lock.bc.get_tip()).unwrap();
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        assert_eq!(bc_lock.add_new_block(&a1), Ok(ChainUpdate::Extended));
+        // stored, then the node stopped before connecting it
+        let a2 = child(&a1, vec![coinbase(2, 1)]);
+        assert_eq!(bc_lock.bc.add_block(&a2), Ok(ChainUpdate::Extended));
+        // a branch with more work that failed to connect
+        let b1 = child(&genesis, vec![coinbase(1, 2)]);
+        let b2 = child(&b1, vec![coinbase(2, 2)]);
+        let b3 = child(&b2, vec![coinbase(3, 2)]);
+        for block in &[&b1, &b2, &b3] {
+            assert!(bc_lock.bc.add_block(block).is_ok());
+        }
+        bc_lock.bc.set_block_status(&b3.hash, BlockStatus::Invalid);
+        assert_eq!(bc_lock.bc.get_tip(), a1.hash);
+        drop(bc_lock);
+
+        let bc_lock = reopen(&dir);
+        assert_recovered(&bc_lock, &a2.hash);
+    }
+
+    #[test]
+    fn recover_stale_utxo_set() {
+        let dir = TempDir::new("recover_stale_utxos").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = bc_lock.bc.get_block(&bc_lock.bc.get_tip()).unwrap();
+        let a1 = child(&genesis, vec![coinbase(1, 1)]);
+        assert_eq!(bc_lock.add_new_block(&a1), Ok(ChainUpdate::Extended));
+        // the tip moved but the utxo set stayed at a1
+        let a2 = child(&a1, vec![coinbase(2, 1)]);
+        assert_eq!(bc_lock.bc.add_block(&a2), Ok(ChainUpdate::Extended));
+        bc_lock.bc.set_tip(&a2.hash);
+        assert_eq!(bc_lock.utxos.best_block(), Some(a1.hash.clone()));
+        drop(bc_lock);
+
+        let bc_lock = reopen(&dir);
+        assert_recovered(&bc_lock, &a2.hash);
+        let utxos = bc_lock.bc.db.get_all_with_prefix(utxo_set::UTXO_BLOCK_PREFIX);
+        assert!(utxos.iter().any(|(txid, _)| *txid == a2.transactions[0].id));
+    }
+
+    #[test]
+    fn blocks_from_height() {
+        let dir = TempDir::new("blocks_from").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        assert_eq!(bc_lock.blocks(0, 10).unwrap().len(), 1);
+        assert!(bc_lock.blocks(-1, 10).is_err());
+        assert!(bc_lock.blocks(isize::max_value(), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hashes_from_users() {
+        let dir = TempDir::new("user_hashes").unwrap();
+        let bc_lock = BlockLock::load(new_chain(&dir), None).unwrap();
+        let genesis = util::encode_hex(&bc_lock.bc.get_tip());
+        assert!(bc_lock.block(&genesis).is_some());
+        assert!(bc_lock.header(&genesis).is_some());
+        assert_eq!(bc_lock.headers_after(&genesis).map(|headers| headers.len()), Some(0));
+        for bad in &["zz", "abc", "0x00"] {
+            assert!(bc_lock.block(bad).is_none());
+            assert!(bc_lock.header(bad).is_none());
+            assert!(bc_lock.headers_after(bad).is_none());
+        }
+    }
+}
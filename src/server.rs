@@ -13,19 +13,27 @@ use std::thread;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::Ordering;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
 use transaction::Transaction;
 use log::*;
-use blockchain::BlockChain;
+use blockchain::{BlockChain, ChainUpdate};
 use command::*;
 use router;
 use util;
 use wallet;
 use pool;
+use timedata;
 use block;
+use chain_params;
+use consensus;
+use validation::RejectReason;
+use verify;
 
 const MINING_SIZE: usize = 1;
 const MEMPOOL_SIZE: usize = 10000000;
+// at most MAX_GENERATE blocks are mined by one generate request, the chain is locked meanwhile
+const MAX_GENERATE: usize = 1000;
 
 #[get("/node/list")]
 pub fn handle_node_list(state: rocket::State<router::BlockState>) -> Json<Value> {
@@ -40,6 +48,71 @@ pub fn handle_mempool_list(state: rocket::State<router::BlockState>) -> Json<Val
     ok_data_json!(mem_pool)
 }
 
+#[get("/mining/info")]
+pub fn handle_mining_info(state: rocket::State<router::BlockState>) -> Json<Value> {
+    let miner = &state.miner;
+    ok_data_json!(json!({
+        "mining": miner.is_mining(),
+        "height": miner.mining_height(),
+        "threads": miner.threads(),
+        "hash_rate": miner.hash_rate(),
+    }))
+}
+
+// block template for external miners, the coinbase pays to addr
+#[get("/mining/template/<addr>")]
+pub fn handle_mining_template(
+    state: rocket::State<router::BlockState>,
+    addr: String,
+) -> Json<Value> {
+    if consensus::engine().name() != "pow" {
+        return bad_data_json!("external mining needs proof of work");
+    }
+    if !wallet::Wallet::validate_address(addr.clone()) {
+        return bad_data_json!(format!("{} is invalid btc address", addr));
+    }
+    let bc = &state.bc.lock().unwrap();
+    let mem_pool = state.mem_pool.lock().unwrap().clone();
+    ok_data_json!(bc.mining_template(&mem_pool, &addr))
+}
+
+// a block solved by an external miner
+#[post("/mining/submit", format = "application/json", data = "<new_block>")]
+pub fn handle_mining_submit(
+    state: rocket::State<router::BlockState>,
+    remote: SocketAddr,
+    new_block: Json<block::Block>,
+) -> Json<Value> {
+    let new_block = new_block.into_inner();
+    let block_hash = util::encode_hex(&new_block.hash);
+    let bc = &state.bc.lock().unwrap();
+    let update = match bc.add_new_block(&new_block) {
+        Err(e) => {
+            warn!(LOG, "reject submitted block {}, {}", &block_hash, e);
+            return bad_data_json!(e.to_string());
+        }
+        Ok(ChainUpdate::Exists) => return bad_data_json!(format!("{} has exists", block_hash)),
+        Ok(update) => update,
+    };
+    // like a block from a peer, the orphans waiting for it follow
+    block_added(&state, &new_block, &update, remote.ip());
+    connect_orphans(&state, bc, &new_block.hash);
+    if update == ChainUpdate::SideBranch {
+        info!(LOG, "submitted block {} is on a side branch", &block_hash);
+        return ok_data_json!(block_hash);
+    }
+
+    info!(LOG, "🔨 submitted block {} is connected", &block_hash);
+    let known_nodes = state.known_nodes.lock().unwrap().clone();
+    known_nodes
+        .iter()
+        .filter(|node| *node != &*state.local_node)
+        .for_each(|node| {
+            send_block(&state.known_nodes, node, &state.local_node, &new_block);
+        });
+    ok_data_json!(block_hash)
+}
+
 #[get("/test/download")]
 pub fn handle_test_download_blocks(state: rocket::State<router::BlockState>) -> Json<Value> {
     let bc = &state.bc.lock().unwrap();
@@ -75,30 +148,125 @@ pub fn handle_balance(state: rocket::State<router::BlockState>, addr: String) ->
     ok_data_json!(bc.balance(&addr))
 }
 
+// the unspent outputs of an address, page by page from 0
+#[get("/address/<addr>/utxos/<page>")]
+pub fn handle_address_utxos(
+    state: rocket::State<router::BlockState>,
+    addr: String,
+    page: usize,
+) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    bc.address_utxos(&addr, page).map_or(
+        bad_data_json!(format!("invalid address {}", addr)),
+        |utxos| ok_data_json!(utxos),
+    )
+}
+
+// the confirmed transactions of an address, the newest first
+#[get("/address/<addr>/txs/<page>")]
+pub fn handle_address_txs(
+    state: rocket::State<router::BlockState>,
+    addr: String,
+    page: usize,
+) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    bc.address_txs(&addr, page).map_or(
+        bad_data_json!(format!("invalid address {}", addr)),
+        |txs| ok_data_json!(txs),
+    )
+}
+
 #[get("/wallet/info/tx/<id>")]
 pub fn handle_tx_info(state: rocket::State<router::BlockState>, id: String) -> Json<Value> {
     let bc = &state.bc.lock().unwrap();
-    bc.tx(&id).map_or(
-        bad_data_json!(format!("{} not found", id)),
-        |ts| ok_data_json!(ts),
-    )
+    match bc.tx(&id) {
+        Ok(ts) => ok_data_json!(ts),
+        Err(e) => bad_data_json!(e),
+    }
 }
 
 #[get("/wallet/info/block/<id>")]
 pub fn handle_info_block(state: rocket::State<router::BlockState>, id: String) -> Json<Value> {
+    if util::try_decode_hex(&id).is_none() {
+        return bad_data_json!(format!("{} is not a block hash", id));
+    }
+    let bc = &state.bc.lock().unwrap();
+    match bc.block(&id) {
+        Some(ref block) if block.is_pruned() => bad_data_json!(format!("block {} is pruned", id)),
+        Some(block) => ok_data_json!(block),
+        None => bad_data_json!(format!("{} not found", id)),
+    }
+}
+
+#[get("/wallet/info/header/<id>")]
+pub fn handle_info_header(state: rocket::State<router::BlockState>, id: String) -> Json<Value> {
+    if util::try_decode_hex(&id).is_none() {
+        return bad_data_json!(format!("{} is not a block hash", id));
+    }
     let bc = &state.bc.lock().unwrap();
-    bc.block(&id).map_or(
+    bc.header(&id).map_or(
         bad_data_json!(format!("{} not found", id)),
-        |block| ok_data_json!(block),
+        |header| ok_data_json!(header),
     )
 }
 
+// headers first sync, the headers after the block `from` on the best chain
+#[get("/headers/<from>")]
+pub fn handle_headers(state: rocket::State<router::BlockState>, from: String) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    bc.headers_after(&from).map_or(
+        bad_data_json!(format!("{} is not a block hash", from)),
+        |headers| ok_data_json!(headers),
+    )
+}
+
+// count blocks of the best chain from height from, for explorers and sync
+#[get("/blocks/<from>/<count>")]
+pub fn handle_blocks(
+    state: rocket::State<router::BlockState>,
+    from: isize,
+    count: usize,
+) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    match bc.blocks(from, count) {
+        Ok(blocks) => ok_data_json!(blocks),
+        Err(e) => bad_data_json!(e),
+    }
+}
+
 #[get("/wallet/utxos/unspend")]
 pub fn handle_unspend_utxos(state: rocket::State<router::BlockState>) -> Json<Value> {
     let bc = &state.bc.lock().unwrap();
     ok_data_json!(bc.unspend_utxo())
 }
 
+// the tips of every branch, like getchaintips
+#[get("/chain/tips")]
+pub fn handle_chain_tips(state: rocket::State<router::BlockState>) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    ok_data_json!(bc.chain_tips())
+}
+
+// checks the newest depth blocks of the chain and the indexes, see verify::verify_chain
+#[get("/chain/verify/<level>/<depth>")]
+pub fn handle_verify_chain(
+    state: rocket::State<router::BlockState>,
+    level: usize,
+    depth: isize,
+) -> Json<Value> {
+    if level > verify::MAX_VERIFY_LEVEL {
+        return bad_data_json!(format!("level {} is not in 0..{}", level, verify::MAX_VERIFY_LEVEL));
+    }
+    let bc = &state.bc.lock().unwrap();
+    ok_data_json!(bc.verify_chain(level, depth))
+}
+
+#[get("/chain/supply")]
+pub fn handle_supply(state: rocket::State<router::BlockState>) -> Json<Value> {
+    let bc = &state.bc.lock().unwrap();
+    ok_data_json!(bc.supply())
+}
+
 #[get("/wallet/blocks")]
 pub fn handle_list_block(state: rocket::State<router::BlockState>) -> Json<Value> {
     let block = &state.bc.lock().unwrap();
@@ -153,6 +321,7 @@ pub fn handle_transfer(
     }
 
     let (to, amount) = (&transfer.to, transfer.amount as isize);
+    let fee = transfer.fee.unwrap_or(0) as isize;
     let bc = &state.bc.lock().unwrap();
     let mem_pool = &state.mem_pool.lock().unwrap();
     let mut spend_utxos = HashMap::new();
@@ -166,7 +335,7 @@ pub fn handle_transfer(
             );
         })
     });
-    let tx = bc.create_new_utxo_transaction(&from_wallet, to, amount, Some(spend_utxos));
+    let tx = bc.create_new_utxo_transaction(&from_wallet, to, amount, fee, Some(spend_utxos));
     if tx.is_err() {
         return bad_data_json!(tx.err().unwrap());
     }
@@ -190,13 +359,20 @@ pub fn handle_transfer(
 }
 
 #[post("/addr", format = "application/json", data = "<addrs>")]
-pub fn handle_addr(state: rocket::State<router::BlockState>, addrs: Json<Addr>) -> Json<Value> {
+pub fn handle_addr(
+    state: rocket::State<router::BlockState>,
+    remote: SocketAddr,
+    addrs: Json<Addr>,
+) -> Json<Value> {
+    if state.is_banned(remote.ip()) {
+        return bad_data_json!("banned".to_owned());
+    }
     let local_node = &state.local_node;
     let addr_list = &addrs.addr_list;
     {
         let known_nodes_lock = Arc::clone(&state.known_nodes);
         let mut known_nodes = known_nodes_lock.lock().unwrap();
-        addr_list.iter().for_each(|addr| {
+        addr_list.iter().filter(|addr| !state.is_banned_node(addr)).for_each(|addr| {
             let exist = known_nodes.clone().into_iter().all(|node| {
                 debug!(LOG, "{} {}", &node, local_node);
                 node != *addr
@@ -219,6 +395,9 @@ pub fn handle_get_heigt_block_data(
     let local_node = &state.local_node;
     let res = bc.block_with_height(height_data.height);
     if let Some(block) = res {
+        if block.is_pruned() {
+            return bad_data_json!(format!("block {} is pruned", height_data.height));
+        }
         send_block(
             &state.known_nodes,
             &height_data.add_from,
@@ -252,6 +431,9 @@ pub fn handle_get_block_data(
         if block.is_none() {
             return bad_json!();
         }
+        if block.as_ref().unwrap().is_pruned() {
+            return bad_data_json!(format!("block {} is pruned", block_hash));
+        }
         send_block(
             &state.known_nodes,
             &block_data.add_from,
@@ -281,6 +463,64 @@ pub fn handle_get_block_data(
     ok_json!()
 }
 
+// mines n blocks to addr right away, regtest only
+#[post("/mining/generate/<n>/<addr>")]
+pub fn handle_generate_to(
+    state: rocket::State<router::BlockState>,
+    n: usize,
+    addr: String,
+) -> Json<Value> {
+    generate(&state, n, &addr)
+}
+
+// like handle_generate_to, the coinbases pay to the mining address of the node
+#[post("/mining/generate/<n>")]
+pub fn handle_generate(state: rocket::State<router::BlockState>, n: usize) -> Json<Value> {
+    let addr = state.mining_address.to_string();
+    generate(&state, n, &addr)
+}
+
+fn generate(state: &router::BlockState, n: usize, addr: &str) -> Json<Value> {
+    if !chain_params::params().on_demand_mining {
+        return bad_data_json!("generate is only available on regtest");
+    }
+    if n > MAX_GENERATE {
+        return bad_data_json!(format!("can't generate more than {} blocks at once", MAX_GENERATE));
+    }
+    if addr.is_empty() || !wallet::Wallet::validate_address(addr.to_owned()) {
+        return bad_data_json!(format!("{} is invalid btc address", addr));
+    }
+    let mem_pool = state.mem_pool.lock().unwrap().clone();
+    let blocks = match state.bc.lock().unwrap().generate(n, addr, &mem_pool) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            warn!(LOG, "generate {} blocks fail, {}", n, e);
+            return bad_data_json!(e);
+        }
+    };
+
+    let known_nodes = state.known_nodes.lock().unwrap().clone();
+    let mut hashes = vec![];
+    for new_block in &blocks {
+        info!(LOG, "🔨 generated block {}", util::encode_hex(&new_block.hash));
+        state.miner.abort_stale(new_block.height);
+        {
+            let mut mem_pool = state.mem_pool.lock().unwrap();
+            new_block.transactions.iter().for_each(|ts| {
+                mem_pool.remove(&util::encode_hex(&ts.id));
+            });
+        }
+        known_nodes
+            .iter()
+            .filter(|node| *node != &*state.local_node)
+            .for_each(|node| {
+                send_block(&state.known_nodes, node, &state.local_node, new_block);
+            });
+        hashes.push(util::encode_hex(&new_block.hash));
+    }
+    ok_data_json!(hashes)
+}
+
 // Notic, it may be cause mining ...
 #[post("/tx", format = "application/json", data = "<tx>")]
 pub fn handle_tx(state: rocket::State<router::BlockState>, tx: Json<TX>) -> Json<Value> {
@@ -289,6 +529,10 @@ pub fn handle_tx(state: rocket::State<router::BlockState>, tx: Json<TX>) -> Json
     let txid = util::encode_hex(&ts.id);
 
     debug!(LOG, "🎩 get a transaction, txid: {}", &txid);
+    if let Err(e) = state.bc.lock().unwrap().check_mempool_tx(&ts) {
+        warn!(LOG, "reject transaction {}, {}", &txid, e);
+        return bad_data_json!(e.to_string());
+    }
     // add new transaction into mempool
     {
         let mut mem_pool = state.mem_pool.lock().unwrap();
@@ -333,6 +577,7 @@ pub fn handle_tx(state: rocket::State<router::BlockState>, tx: Json<TX>) -> Json
         let mem_pool = Arc::clone(&state.mem_pool);
         let local_node = Arc::clone(&state.local_node);
         let known_nodes = Arc::clone(&state.known_nodes);
+        let miner = Arc::clone(&state.miner);
         thread::spawn(move || {
             info!(LOG, "🚡 {} start to mining...", &local_node);
             loop {
@@ -345,7 +590,7 @@ pub fn handle_tx(state: rocket::State<router::BlockState>, tx: Json<TX>) -> Json
                 }
                 let res = bc.lock()
                     .unwrap()
-                    .mine_new_block2(mining_addr.to_string(), &mem_pool_copy)
+                    .mine_new_block2(mining_addr.to_string(), &mem_pool_copy, Arc::clone(&miner))
                     .or_else(Err);
                 let res = res.and_then(|recv| recv.recv().map_err(|e| format!("{:?}", e)))
                     .and_then(|new_block| {
@@ -393,7 +638,8 @@ pub fn handle_get_blocks(
     blocks: Json<GetBlocks>,
 ) -> Json<Value> {
     let bc = &state.bc.lock().unwrap();
-    let hashes: Vec<String> = bc.block_hashes();
+    // a pruned node only announces the blocks it can send
+    let hashes: Vec<String> = bc.served_block_hashes();
     let hashes_vec: Vec<Vec<u8>> = hashes.iter().map(util::decode_hex).collect();
     send_inv(
         &state.known_nodes,
@@ -408,15 +654,47 @@ pub fn handle_get_blocks(
 #[post("/version", format = "application/json", data = "<version>")]
 pub fn handle_version(
     state: rocket::State<router::BlockState>,
+    remote: SocketAddr,
     version: Json<Version>,
 ) -> Json<Value> {
+    if state.is_banned(remote.ip()) {
+        return bad_data_json!("banned".to_owned());
+    }
+    let magic = chain_params::params().magic;
+    if version.magic != magic {
+        warn!(
+            LOG,
+            "{} is on another network, magic: {:x}, expected: {:x}",
+            &version.addr_from,
+            version.magic,
+            magic
+        );
+        return bad_data_json!("another network".to_owned());
+    }
     let bc = &state.bc.lock().unwrap();
-    let my_best_height = bc.best_height();
-    let foreigner_best_height = version.best_height;
+    if version.best_work.len() > 32 {
+        return bad_data_json!("invalid chain work".to_owned());
+    }
+    if version.timestamp != 0 {
+        timedata::add_time_sample(remote.ip(), version.timestamp);
+    }
+    // follow the chain with the most work, whoever has it
+    let my_best_work = bc.best_work();
+    let foreigner_best_work = util::as_u256(&version.best_work);
     let local_node = &state.local_node;
-    if my_best_height < foreigner_best_height {
-        send_get_block(&state.known_nodes, &version.addr_from, local_node);
-    } else if my_best_height > foreigner_best_height {
+    if my_best_work < foreigner_best_work {
+        // the blocks we miss were pruned by the peer
+        if version.pruned_height > bc.block_chain().get_best_height() + 1 {
+            warn!(
+                LOG,
+                "{} pruned the blocks below height {}, can't sync from it",
+                &version.addr_from,
+                version.pruned_height
+            );
+        } else {
+            send_get_block(&state.known_nodes, &version.addr_from, local_node);
+        }
+    } else if my_best_work > foreigner_best_work {
         send_version(
             &state.known_nodes,
             &version.addr_from,
@@ -429,71 +707,56 @@ pub fn handle_version(
 }
 
 #[post("/inv", format = "application/json", data = "<inv>")]
-pub fn handle_inv(state: rocket::State<router::BlockState>, inv: Json<Inv>) -> Json<Value> {
+pub fn handle_inv(
+    state: rocket::State<router::BlockState>,
+    remote: SocketAddr,
+    inv: Json<Inv>,
+) -> Json<Value> {
     info!(
         LOG,
         "Received inventory with {} {}",
         inv.items.len(),
         inv.inv_type
     );
+    if state.is_banned(remote.ip()) {
+        return bad_data_json!("banned".to_owned());
+    }
     let bc = &state.bc.lock().unwrap();
 
     let inv_type = &inv.inv_type;
     let add_from = &inv.add_from;
     let local_node = &state.local_node;
     if inv_type == "block" {
-        let block_in_transit = &state.block_in_transit;
-        let mut block_in_transit = block_in_transit.lock().unwrap();
-        // TODO add conflict logic
-        bc.conflict(&inv.items);
-
-        let last_height = bc.best_height() as usize;
-        if last_height > (inv.items.len() - 1) {
-            return ok_json!();
-        }
-
-        inv.items.iter().for_each(|item| {
+        // items are the remote best chain from tip to genesis, download the blocks we miss
+        // from the oldest one. They may arrive out of order, the orphans wait for their parents.
+        // A batch is requested at a time, the next inventory is asked for when it's received
+        let orphans = state.orphans.lock().unwrap();
+        let mut in_flight = state.in_flight.lock().unwrap();
+        let missing = inv.items
+            .iter()
+            .rev()
+            .filter(|item| !bc.has_block(item) && !orphans.contains(item));
+        for item in missing {
+            if in_flight.is_full() {
+                break;
+            }
+            if !in_flight.request(item) {
+                continue;
+            }
             debug!(
                 LOG,
                 "addr_from:{}, block item:{}",
                 add_from,
                 util::encode_hex(item)
             );
-        });
-
-        let step = inv.items.len() - last_height - 1;
-        if step == 0 {
-            return ok_json!();
-        }
-        let mut items = inv.items[0..step].to_vec();
-        // reverse it
-        items.reverse();
-        let block_hash = items[0].clone();
-        *block_in_transit = items;
-        block_in_transit.iter().for_each(|item| {
-            println!(
-                "addr_from:{}, block item:{}",
+            send_get_data(
+                &state.known_nodes,
                 add_from,
-                util::encode_hex(item)
+                local_node,
+                "block".to_owned(),
+                item,
             );
-        });
-
-        send_get_data(
-            &state.known_nodes,
-            add_from,
-            local_node,
-            "block".to_owned(),
-            &block_hash,
-        );
-
-        let mut new_in_transit: Vec<Vec<u8>> = vec![];
-        for b in &*block_in_transit {
-            if !util::compare_slice_u8(b, &block_hash) {
-                new_in_transit.push(b.clone());
-            }
         }
-        // reset blocks in transit
-        *block_in_transit = new_in_transit;
     }
     if inv_type == "tx" {
         let txid = inv.items[0].clone();
@@ -518,75 +781,131 @@ pub fn handle_inv(state: rocket::State<router::BlockState>, inv: Json<Inv>) -> J
     ok_json!()
 }
 
-// TODO before add block, check it's valid
 #[post("/block", format = "application/json", data = "<block_data>")]
 pub fn handle_block(
     state: rocket::State<router::BlockState>,
+    remote: SocketAddr,
     block_data: Json<Block>,
 ) -> Json<Value> {
     info!(LOG, "do block handle");
+    let add_from = &block_data.add_from;
+    if state.is_banned(remote.ip()) {
+        return bad_data_json!("banned".to_owned());
+    }
     let bc = &state.bc.lock().unwrap();
-    let local_node = &state.local_node;
-    let central_node = {
-        state.known_nodes.lock().unwrap()[0].to_string()
-    };
     let new_block = block::Block::try_deserialize_block(&block_data.block);
     if new_block.is_err() {
         return bad_data_json!(new_block.err().unwrap());
     }
     let new_block = new_block.unwrap();
     let block_hash = new_block.hash.clone();
-    let res = bc.add_new_block(&new_block, block_data.add_from == central_node);
-    if let Err(e) = res {
-        error!(LOG, "add block faild, err:{:?}", e);
-        return bad_data_json!(e);
-    } else if let Ok(true) = res {
-        info!(
+    {
+        // the last block of the batch, ask the sender for the next one
+        let mut in_flight = state.in_flight.lock().unwrap();
+        if in_flight.received(&block_hash) && in_flight.is_empty() {
+            send_get_block(&state.known_nodes, add_from, &state.local_node);
+        }
+    }
+    match bc.add_new_block(&new_block) {
+        Err(RejectReason::UnknownParent { .. }) => {
+            let mut orphans = state.orphans.lock().unwrap();
+            orphans.add(new_block, remote.ip());
+            // ask the sender for the oldest missing ancestor
+            if let Some(parent) = orphans.missing_parent(&block_hash) {
+                info!(
+                    LOG,
+                    "block {} is an orphan, request {} from {}, {} orphans",
+                    util::encode_hex(&block_hash),
+                    util::encode_hex(&parent),
+                    add_from,
+                    orphans.len()
+                );
+                send_get_data(
+                    &state.known_nodes,
+                    add_from,
+                    &state.local_node,
+                    "block".to_owned(),
+                    &parent,
+                );
+            }
+            return ok_json!();
+        }
+        Err(e) => {
+            reject_block(&state, remote.ip(), &block_hash, &e);
+            return bad_data_json!(e);
+        }
+        Ok(ChainUpdate::Exists) => {
+            info!(
+                LOG,
+                "{} has exists, ignore it",
+                util::encode_hex(block_hash)
+            );
+            return ok_json!();
+        }
+        Ok(update) => block_added(&state, &new_block, &update, remote.ip()),
+    }
+
+    connect_orphans(&state, bc, &block_hash);
+    ok_data_json!("")
+}
+
+// peers sending blocks against the checkpoints are banned
+fn reject_block(state: &router::BlockState, from: IpAddr, hash: &[u8], e: &RejectReason) {
+    error!(LOG, "add block {} faild, err:{:?}", util::encode_hex(hash), e);
+    if e.violates_checkpoint() {
+        crit!(
             LOG,
-            "{} has exists, ignore it",
-            util::encode_hex(block_hash)
+            "🚨 {} sent block {} conflicting with the checkpoints, ban it, {}",
+            from,
+            util::encode_hex(hash),
+            e
         );
-        return ok_json!();
+        state.ban_node(from);
+    }
+}
+
+fn block_added(
+    state: &router::BlockState,
+    block: &block::Block,
+    update: &ChainUpdate,
+    from: IpAddr,
+) {
+    if *update == ChainUpdate::SideBranch {
+        return;
     }
+    // the local miner can't win the same height any more
+    state.miner.abort_stale(block.height);
 
     info!(
         LOG,
         "added block successfully, block source:{}, block hash: {} ",
-        &block_data.add_from,
-        util::encode_hex(&block_hash)
+        from,
+        util::encode_hex(&block.hash)
     );
 
-    info!(
-        LOG,
-        "prepare to update utxos, the new block is {}",
-        util::encode_hex(&block_hash)
-    );
-    bc.update_utxo(&new_block);
-    info!(LOG, "update utxos successfully.");
-
-    new_block.transactions.into_iter().for_each(|ts| {
-        state.mem_pool.lock().unwrap().remove(
-            &util::encode_hex(&ts.id),
-        );
+    let mut mem_pool = state.mem_pool.lock().unwrap();
+    block.transactions.iter().for_each(|ts| {
+        mem_pool.remove(&util::encode_hex(&ts.id));
     });
-    // TODO why do it in that.
-    let block_in_transit = Arc::clone(&state.block_in_transit);
-    {
-        let mut bc_in_transit = block_in_transit.lock().unwrap();
-        if bc_in_transit.len() > 0 {
-            let block_hash = bc_in_transit[0].clone();
-            send_get_data(
-                &state.known_nodes,
-                &block_data.add_from,
-                local_node,
-                "block".to_owned(),
-                &block_hash,
-            );
-            *bc_in_transit = bc_in_transit[1..].to_vec();
+}
+
+// connects the orphans waiting for the block with hash, then the orphans waiting for them
+fn connect_orphans(state: &router::BlockState, bc: &router::BlockLock, hash: &[u8]) {
+    let mut parents = vec![hash.to_vec()];
+    while let Some(parent) = parents.pop() {
+        let children = state.orphans.lock().unwrap().take_children(&parent);
+        for orphan in children {
+            match bc.add_new_block(&orphan.block) {
+                Ok(update) => {
+                    if update != ChainUpdate::Exists {
+                        block_added(state, &orphan.block, &update, orphan.from);
+                    }
+                    parents.push(orphan.block.hash);
+                }
+                Err(e) => reject_block(state, orphan.from, &orphan.block.hash, &e),
+            }
         }
     }
-
-    ok_data_json!("")
 }
 
 
@@ -640,7 +959,9 @@ pub fn send_version(
     bc: &Arc<BlockChain>,
 ) {
     let best_height = bc.get_best_height();
-    let version = Version::new(NODE_VERSION, best_height, local_node.to_owned());
+    let best_work = util::u256_to_vec(&bc.get_best_work());
+    let mut version = Version::new(NODE_VERSION, best_height, best_work, local_node.to_owned());
+    version.pruned_height = bc.pruned_height();
     let data = &serde_json::to_vec(&version).unwrap();
     do_post_request(known_nodes, addr, path, data);
 }
@@ -0,0 +1,119 @@
+extern crate time;
+
+use super::log::*;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// keep the offsets of at most MAX_TIME_SAMPLES peers
+const MAX_TIME_SAMPLES: usize = 200;
+// the network offset is only used once enough peers reported their time
+const MIN_TIME_SAMPLES: usize = 5;
+// never adjust the local clock more than 70 minutes
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+lazy_static! {
+    pub static ref TIME_DATA: Mutex<TimeData> = Mutex::new(TimeData::new());
+}
+
+// TimeData collects the clock offsets of the peers, the median of them adjusts the local time
+pub struct TimeData {
+    // peer ip => peer time - local time, the ip the peer connects from and not the node it
+    // declares, which it could change for every message
+    samples: HashMap<IpAddr, i64>,
+    offset: i64,
+}
+
+impl TimeData {
+    pub fn new() -> TimeData {
+        TimeData {
+            samples: HashMap::new(),
+            offset: 0,
+        }
+    }
+
+    // a peer reported its time in the version message, the first report of a peer wins
+    pub fn add_sample(&mut self, peer: IpAddr, peer_time: i64, now: i64) {
+        if self.samples.len() >= MAX_TIME_SAMPLES || self.samples.contains_key(&peer) {
+            return;
+        }
+        self.samples.insert(peer, peer_time - now);
+        if self.samples.len() < MIN_TIME_SAMPLES {
+            return;
+        }
+
+        let mut offsets: Vec<i64> = self.samples.values().cloned().collect();
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() <= MAX_TIME_ADJUSTMENT {
+            self.offset = median;
+        } else {
+            warn!(
+                LOG,
+                "peers' time offset {}s is too far, check the local clock",
+                median
+            );
+            self.offset = 0;
+        }
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+impl Default for TimeData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn add_time_sample(peer: IpAddr, peer_time: i64) {
+    TIME_DATA.lock().unwrap().add_sample(
+        peer,
+        peer_time,
+        time::get_time().sec,
+    );
+}
+
+// the local time adjusted by the median offset of the peers
+pub fn adjusted_time() -> i64 {
+    time::get_time().sec + TIME_DATA.lock().unwrap().offset()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn peer(idx: usize) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, idx as u8))
+    }
+
+    #[test]
+    fn median_offset_after_enough_samples() {
+        let mut data = TimeData::new();
+        for (idx, offset) in [10, -5, 30, 20].iter().enumerate() {
+            data.add_sample(peer(idx), 1000 + offset, 1000);
+        }
+        assert_eq!(data.offset(), 0);
+
+        data.add_sample(peer(4), 1015, 1000);
+        // sorted offsets: -5, 10, 15, 20, 30
+        assert_eq!(data.offset(), 15);
+
+        // a peer can't move the offset twice
+        data.add_sample(peer(4), 9000, 1000);
+        assert_eq!(data.offset(), 15);
+    }
+
+    #[test]
+    fn large_offset_is_ignored() {
+        let mut data = TimeData::new();
+        for idx in 0..MIN_TIME_SAMPLES {
+            data.add_sample(peer(idx), MAX_TIME_ADJUSTMENT + 1, 0);
+        }
+        assert_eq!(data.offset(), 0);
+    }
+}
@@ -21,11 +21,45 @@ use self::prettytable::cell::Cell;
 
 use super::util;
 use super::log::*;
+use super::chain_params::{self, ChainParams};
 use super::wallet::{Wallet, ADDRESS_CHECKSUM_LEN};
+use std::cmp;
 use std::collections::HashMap;
 use super::utxo_set::UTXOSet;
 
-const SUBSIDY: isize = 10;
+fn scheduled_subsidy(params: &ChainParams, height: isize) -> isize {
+    let halvings = height / params.halving_interval;
+    if halvings >= 63 {
+        return 0;
+    }
+    params.initial_subsidy >> halvings
+}
+
+// new coins the coinbase of the block at height may claim, fees not included
+pub fn block_subsidy(height: isize) -> isize {
+    let params = chain_params::params();
+    let issued = if height > 0 { supply_at(&params, height - 1) } else { 0 };
+    cmp::min(scheduled_subsidy(&params, height), params.max_supply - issued)
+}
+
+// coins issued by the blocks from genesis up to and including height
+pub fn issued_supply(height: isize) -> isize {
+    supply_at(&chain_params::params(), height)
+}
+
+fn supply_at(params: &ChainParams, height: isize) -> isize {
+    let (mut supply, mut era_start) = (0, 0);
+    while era_start <= height {
+        let subsidy = scheduled_subsidy(params, era_start);
+        if subsidy == 0 {
+            break;
+        }
+        let era_end = cmp::min(era_start + params.halving_interval - 1, height);
+        supply += subsidy * (era_end - era_start + 1);
+        era_start += params.halving_interval;
+    }
+    cmp::min(supply, params.max_supply)
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
@@ -35,8 +69,8 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    // creates a new coinbase transaction
-    pub fn new_coinbase_tx(to: String, data: String) -> Transaction {
+    // creates a new coinbase transaction, reward is the subsidy plus the fees of the block
+    pub fn new_coinbase_tx(to: String, data: String, reward: isize) -> Transaction {
         let data = if data.len() == 0 {
             let mut randon_msg = [0u8; 32];
             thread_rng().fill_bytes(&mut randon_msg);
@@ -46,7 +80,7 @@ impl Transaction {
         };
 
         let txin = TXInput::new(vec![], -1, vec![], data.into_bytes());
-        let txout = TXOutput::new(SUBSIDY, to);
+        let txout = TXOutput::new(reward, to);
         let mut tx = Transaction {
             id: vec![],
             vin: vec![txin],
@@ -58,10 +92,12 @@ impl Transaction {
         tx
     }
 
+    // the inputs pay amount to `to` and fee to the miner, the rest goes back to the wallet
     pub fn new_utxo_transaction(
         wallet: &Wallet,
         to: String,
         amount: isize,
+        fee: isize,
         utxoset: &UTXOSet,
         spend_utxos: Option<HashMap<String, Vec<isize>>>,
     ) -> Result<Transaction, String> {
@@ -69,9 +105,16 @@ impl Transaction {
         let pub_key = util::public_key_to_vec(&wallet.public_key, false);
         let pub_key_hash = Wallet::hash_pubkey(&pub_key);
         // find the account unspend utxo from utxoset
+        if fee < 0 {
+            return Err("ERROR: fee must not be negative".to_owned());
+        }
+        let total = match amount.checked_add(fee) {
+            Some(total) => total,
+            None => return Err("ERROR: amount and fee overflow".to_owned()),
+        };
         let (acc, valid_outputs) =
-            utxoset.find_spend_able_outputs(&pub_key_hash, amount, spend_utxos);
-        if acc < amount {
+            utxoset.find_spend_able_outputs(&pub_key_hash, total, spend_utxos);
+        if acc < total {
             return Err("ERROR: Not enough founds".to_owned());
         }
 
@@ -91,8 +134,8 @@ impl Transaction {
         }*/
         // Build a list of outputs
         outputs.push(TXOutput::new(amount, to));
-        if acc > amount {
-            outputs.push(TXOutput::new(acc - amount, wallet.get_address()));
+        if acc > total {
+            outputs.push(TXOutput::new(acc - total, wallet.get_address()));
         }
 
         let mut tx = Transaction {
@@ -132,27 +175,27 @@ impl Transaction {
         hasher.result().to_vec()
     }
 
-    pub fn sign(&mut self, secret_key: &SecretKey, prev_txs: &HashMap<isize, Transaction>) {
+    // prev_outs are the outputs spent by the inputs, by input index
+    pub fn sign(&mut self, secret_key: &SecretKey, prev_outs: &HashMap<isize, TXOutput>) {
         if self.is_coinbase() {
             return;
         }
 
         // check input wether reference some pre block output
         self.vin.iter().fold(0, |acc, _| {
-            assert!(prev_txs.get(&acc).is_some());
+            assert!(prev_outs.get(&acc).is_some());
             acc + 1
         });
 
         let mut tx_copy = self.trimmed_copy();
         let mut sign_vec = Vec::new();
 
-        self.vin.iter().fold(0, |acc, tx_input| {
-            let prev_tx: &Transaction = prev_txs.get(&acc).unwrap();
+        self.vin.iter().fold(0, |acc, _| {
+            let prev_out: &TXOutput = prev_outs.get(&acc).unwrap();
             // reset signation
             tx_copy.vin[acc as usize].signature = vec![];
             // set reference's output public key
-            tx_copy.vin[acc as usize].pub_key =
-                prev_tx.vout[tx_input.vout as usize].pub_key_hash.clone();
+            tx_copy.vin[acc as usize].pub_key = prev_out.pub_key_hash.clone();
 
             let origin_data_to_sign = util::packet_sign_content(&tx_copy);
             let origin_data_to_sign = util::double_sha256(origin_data_to_sign);
@@ -243,35 +286,35 @@ impl Transaction {
     }
 
     #[inline]
-    // Verify verifies signatures of Transaction inputs
+    // Verify verifies signatures of Transaction inputs, prev_outs are the outputs referenced
+    // by the inputs, indexed by the input's idx
     // tx_input = |txid|vout|sig|pkey| ==> |txid = 0| vout| sig = "" | pkey = reference pkey|
     // ==> sign(vout, pkey)
-    pub fn verify(&self, prev_txs: &HashMap<isize, Transaction>) -> bool {
+    pub fn verify(&self, prev_outs: &HashMap<isize, TXOutput>) -> bool {
         if self.is_coinbase() {
             return true;
         }
 
-        // check input wether reference some pre block output
-        for (idx, _) in self.vin.iter().enumerate(){
-            assert!(prev_txs.get(&(idx as isize)).is_some());
-        }
-
         let tx_copy = &mut self.trimmed_copy();
-        let mut verify = true;
         for (idx, tx_input) in self.vin.iter().enumerate() {
-            if !verify {
-               break; 
+            let prev_out = match prev_outs.get(&(idx as isize)) {
+                Some(prev_out) => prev_out,
+                None => return false,
+            };
+            // the input's public key must own the referenced output
+            if !tx_input.uses_key_hash(&prev_out.pub_key_hash) {
+                return false;
             }
-            let prev_tx: &Transaction = prev_txs.get(&(idx as isize)).unwrap();
             tx_copy.vin[idx as usize].signature = vec![];
-            tx_copy.vin[idx as usize].pub_key =
-                prev_tx.vout[tx_input.vout as usize].pub_key_hash.clone();
+            tx_copy.vin[idx as usize].pub_key = prev_out.pub_key_hash.clone();
 
             let origin_data_to_sign = util::packet_sign_content(&tx_copy);
-            verify = util::verify(&tx_input.pub_key, &tx_input.signature, origin_data_to_sign);
+            if !util::verify(&tx_input.pub_key, &tx_input.signature, origin_data_to_sign) {
+                return false;
+            }
             tx_copy.vin[idx as usize].pub_key = vec![];
         }
-        verify
+        true
     }
 }
 
@@ -302,10 +345,14 @@ impl TXInput {
     pub fn uses_key(&self, pub_key: &[u8]) -> bool {
         util::compare_slice_u8(&self.pub_key, pub_key)
     }
+
+    pub fn uses_key_hash(&self, pub_key_hash: &[u8]) -> bool {
+        util::compare_slice_u8(&Wallet::hash_pubkey(&self.pub_key), pub_key_hash)
+    }
 }
 
 // TODO add signature script instead of pub_key_hash
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
 pub struct TXOutput {
     // value is the output's source counts
     pub value: isize,
@@ -334,14 +381,37 @@ impl TXOutput {
     }
 }
 
+// whether outputs created at height can be spent by a block at spend_height, the genesis
+// coinbase funds the chain and is spendable at once
+pub fn is_mature(height: isize, is_coinbase: bool, spend_height: isize) -> bool {
+    if !is_coinbase || height == 0 {
+        return true;
+    }
+    spend_height - height >= chain_params::params().coinbase_maturity
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TXOutputs {
     pub outputs: Box<HashMap<isize, TXOutput>>,
+    // height of the block creating the outputs
+    #[serde(default)]
+    pub height: isize,
+    #[serde(default)]
+    pub is_coinbase: bool,
 }
 
 impl TXOutputs {
-    pub fn new(outputs: HashMap<isize, TXOutput>) -> TXOutputs {
-        TXOutputs { outputs: Box::new(outputs) }
+    pub fn new(outputs: HashMap<isize, TXOutput>, height: isize, is_coinbase: bool) -> TXOutputs {
+        TXOutputs {
+            outputs: Box::new(outputs),
+            height: height,
+            is_coinbase: is_coinbase,
+        }
+    }
+
+    // whether the outputs can be spent by a block at spend_height
+    pub fn is_mature(&self, spend_height: isize) -> bool {
+        is_mature(self.height, self.is_coinbase, spend_height)
     }
     // TODO
     pub fn serialize(txo: &TXOutputs) -> Vec<u8> {
@@ -367,7 +437,7 @@ mod tests {
         let out_idx = 0;
         let mut outputs = HashMap::new();
         outputs.insert(out_idx, coin_base);
-        let outputs = super::TXOutputs::new(outputs);
+        let outputs = super::TXOutputs::new(outputs, 1, true);
 
         // deserialize, serialize
         {
@@ -380,4 +450,35 @@ mod tests {
 
     #[test]
     fn txoutputs() {}
+
+    #[test]
+    fn coinbase_maturity() {
+        use super::{TXOutputs, ChainParams};
+        let maturity = ChainParams::main().coinbase_maturity;
+        let coinbase = TXOutputs::new(HashMap::new(), 5, true);
+        assert!(!coinbase.is_mature(5 + maturity - 1));
+        assert!(coinbase.is_mature(5 + maturity));
+        // the genesis coinbase and normal outputs are always spendable
+        assert!(TXOutputs::new(HashMap::new(), 0, true).is_mature(1));
+        assert!(TXOutputs::new(HashMap::new(), 5, false).is_mature(6));
+    }
+
+    #[test]
+    fn subsidy_halving() {
+        use super::{block_subsidy, issued_supply, ChainParams};
+        let params = ChainParams::main();
+        let (subsidy, interval) = (params.initial_subsidy, params.halving_interval);
+        assert_eq!(block_subsidy(0), subsidy);
+        assert_eq!(block_subsidy(interval - 1), subsidy);
+        assert_eq!(block_subsidy(interval), subsidy / 2);
+        assert_eq!(block_subsidy(interval * 2), subsidy / 4);
+        assert_eq!(block_subsidy(interval * 64), 0);
+
+        assert_eq!(issued_supply(0), subsidy);
+        assert_eq!(issued_supply(interval), subsidy * interval + 5);
+        // the sum of the subsidies never passes the cap
+        let total: isize = (0..interval * 10).map(block_subsidy).sum();
+        assert_eq!(total, issued_supply(interval * 10));
+        assert!(total <= params.max_supply);
+    }
 }
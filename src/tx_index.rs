@@ -0,0 +1,75 @@
+extern crate serde_json;
+
+use super::block::Block;
+
+// txid => TxLocation of the transactions on the best chain, only kept when the index is enabled
+pub const TX_INDEX_PREFIX: &'static str = "txindex-";
+
+// where a transaction is stored, block.transactions[position] of the block with block_hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_hash: Vec<u8>,
+    pub position: usize,
+}
+
+impl TxLocation {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn deserialize(data: &[u8]) -> TxLocation {
+        serde_json::from_slice(data).unwrap()
+    }
+}
+
+// the index entries of the block's transactions
+pub fn block_entries(block: &Block) -> Vec<(Vec<u8>, Vec<u8>)> {
+    block
+        .transactions
+        .iter()
+        .enumerate()
+        .map(|(position, tx)| {
+            let location = TxLocation {
+                block_hash: block.hash.clone(),
+                position: position,
+            };
+            (tx.id.clone(), location.serialize())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::{Transaction, TXOutput};
+
+    #[test]
+    fn entries_by_position() {
+        let txs: Vec<Transaction> = (0..3)
+            .map(|value| {
+                let mut tx = Transaction {
+                    id: vec![],
+                    vin: vec![],
+                    vout: vec![TXOutput { value: value, pub_key_hash: vec![1; 20] }],
+                };
+                tx.id = tx.hash();
+                tx
+            })
+            .collect();
+        let block = Block {
+            header: Default::default(),
+            transactions: txs.clone(),
+            hash: vec![7; 32],
+            height: 1,
+        };
+
+        let entries = block_entries(&block);
+        assert_eq!(entries.len(), 3);
+        for (position, &(ref txid, ref location)) in entries.iter().enumerate() {
+            assert_eq!(txid, &txs[position].id);
+            let location = TxLocation::deserialize(location);
+            assert_eq!(location.block_hash, block.hash);
+            assert_eq!(location.position, position);
+        }
+    }
+}
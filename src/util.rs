@@ -145,10 +145,21 @@ pub fn decode_hex<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
     hex::decode(data).unwrap()
 }
 
+// for hex from users and peers, None if it isn't hex
+pub fn try_decode_hex<T: AsRef<[u8]>>(data: T) -> Option<Vec<u8>> {
+    hex::decode(data).ok()
+}
+
 pub fn as_u256(data: &[u8]) -> U256 {
     U256::from_big_endian(data)
 }
 
+pub fn u256_to_vec(num: &U256) -> Vec<u8> {
+    let mut buf = [0u8; 32];
+    num.to_big_endian(&mut buf);
+    buf.to_vec()
+}
+
 pub fn encode_ripemd160(text: &[u8]) -> Vec<u8> {
     let mut sh = ripemd160::Ripemd160::new();
     let mut out = [0u8; 20];
@@ -213,11 +224,24 @@ pub fn sign(msg: &Message, secret_key: &SecretKey) -> Vec<u8> {
     sig.serialize_der(&full)
 }
 
+// whether the bytes are a secp256k1 public key
+pub fn is_public_key(pub_key: &[u8]) -> bool {
+    let full = Secp256k1::with_caps(ContextFlag::Full);
+    PublicKey::from_slice(&full, pub_key).is_ok()
+}
+
 pub fn verify(pub_key: &[u8], sig_str: &[u8], origin_data_to_sign: String) -> bool {
     let data_to_sign = double_sha256(origin_data_to_sign);
     let full = Secp256k1::with_caps(ContextFlag::Full);
-    let recover_sig = Signature::from_der(&full, sig_str).unwrap();
-    let recover_pub_key = PublicKey::from_slice(&full, pub_key).unwrap();
+    // signatures and keys come from the network, malformed ones just fail
+    let recover_sig = match Signature::from_der(&full, sig_str) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    let recover_pub_key = match PublicKey::from_slice(&full, pub_key) {
+        Ok(pub_key) => pub_key,
+        Err(_) => return false,
+    };
     full.verify(
         &Message::from_slice(&data_to_sign).unwrap(),
         &recover_sig,
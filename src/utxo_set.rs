@@ -1,9 +1,12 @@
 extern crate slog;
 extern crate slog_term;
+extern crate serde_json;
 
+use super::addr_index::{self, AddressOutput, AddressTx, IndexUpdate};
 use super::block;
 use super::transaction::*;
-use super::blockchain::BlockChain;
+use super::blockchain::{BlockChain, BlockStatus, BLOCK_STATUS_PREFIX, LAST_BLOCK_HASH_PREFIX};
+use super::db::DBBatch;
 use super::util;
 use super::log::*;
 
@@ -15,6 +18,37 @@ pub struct UTXOSet {
 }
 
 pub const UTXO_BLOCK_PREFIX: &'static str = "utxo-";
+// block hash => BlockUndo, written when the block is connected
+pub const UNDO_PREFIX: &'static str = "undo-";
+// the hash of the block the utxo set was built up to, in the "l-" store. Written in the same
+// batch as the utxo changes, see recover
+pub const UTXO_BEST_KEY: &'static [u8] = b"utxo-best";
+
+// an output spent by a block, with what is needed to put it back into the utxo set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub txid: Vec<u8>,
+    pub vout: isize,
+    pub output: TXOutput,
+    pub height: isize,
+    pub is_coinbase: bool,
+}
+
+// the outputs spent by a block, in the order of its inputs
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
+impl BlockUndo {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn deserialize(data: &[u8]) -> BlockUndo {
+        serde_json::from_slice(data).unwrap()
+    }
+}
 
 impl UTXOSet {
     pub fn new(blockchain: Arc<BlockChain>) -> UTXOSet {
@@ -30,29 +64,27 @@ impl UTXOSet {
     ) -> (isize, HashMap<String, Vec<isize>>) {
         let mut unspent_outs: HashMap<String, Vec<isize>> = HashMap::new();
         let mut accumulated = 0;
-        let db = self.blockchain.db.clone();
         let spend_outs = spend_outs.unwrap_or_default();
+        let spend_height = self.blockchain.get_best_height() + 1;
 
-        let kvs = db.get_all_with_prefix(UTXO_BLOCK_PREFIX);
-        for kv in &kvs {
-            let txid = util::encode_hex(&kv.0);
-            let outs = TXOutputs::deserialize_outputs(&kv.1);
-            for (out_idx, out) in &*outs.outputs {
-                // check wether including spend_outs
-                let flag = if let Some(items) = spend_outs.get(&txid) {
-                    items.into_iter().any(|elem| elem == out_idx)
-                } else {
-                    false
-                };
+        for utxo in self.address_outputs(pubkey_hash) {
+            if accumulated >= amout {
+                break;
+            }
+            if !utxo.is_mature(spend_height) {
+                continue;
+            }
+            let txid = util::encode_hex(&utxo.txid);
+            // check wether including spend_outs
+            let flag = if let Some(items) = spend_outs.get(&txid) {
+                items.into_iter().any(|elem| *elem == utxo.vout)
+            } else {
+                false
+            };
 
-                if !flag {
-                    if out.is_locked_with_key(pubkey_hash) && accumulated < amout {
-                        accumulated += out.value;
-                        unspent_outs.entry(txid.clone()).or_insert(vec![]).push(
-                            *out_idx,
-                        );
-                    }
-                }
+            if !flag {
+                accumulated += utxo.output.value;
+                unspent_outs.entry(txid).or_insert(vec![]).push(utxo.vout);
             }
         }
 
@@ -61,27 +93,62 @@ impl UTXOSet {
 
     // |netenv|pub_key_hash|checksum|
     pub fn find_utxo(&self, pubkey_hash: &[u8]) -> Vec<TXOutput> {
-        let mut utxos = Vec::<TXOutput>::new();
-        let db = &self.blockchain.db.clone();
-        let kvs = db.get_all_with_prefix(UTXO_BLOCK_PREFIX);
-        if kvs.len() == 0 {
-            warn!(LOG, "no utxo in blockchain({})", UTXO_BLOCK_PREFIX);
-        }
-        for kv in &kvs {
-            let outs = TXOutputs::deserialize_outputs(&kv.1);
-            for (out_idx, out) in &*outs.outputs {
-                if !out.is_locked_with_key(pubkey_hash) {
-                    continue;
-                }
-                info!(
-                    LOG,
-                    "Find a utxo =============> {:?}",
-                    util::encode_hex(&pubkey_hash)
-                );
-                utxos.push(out.clone());
+        self.address_outputs(pubkey_hash)
+            .into_iter()
+            .map(|utxo| utxo.output)
+            .collect()
+    }
+
+    // the unspent outputs locked with pubkey_hash, from the address index
+    pub fn address_outputs(&self, pubkey_hash: &[u8]) -> Vec<AddressOutput> {
+        let db = &self.blockchain.db;
+        db.get_all_with_key_prefix(pubkey_hash, addr_index::ADDR_UTXO_PREFIX)
+            .iter()
+            .map(|kv| AddressOutput::deserialize(&kv.1))
+            .collect()
+    }
+
+    // the number of unspent outputs locked with pubkey_hash, and the page-th page of them
+    pub fn address_outputs_page(
+        &self,
+        pubkey_hash: &[u8],
+        page: usize,
+    ) -> (usize, Vec<AddressOutput>) {
+        let (total, kvs) = self.blockchain.db.page_with_key_prefix(
+            pubkey_hash,
+            addr_index::ADDR_UTXO_PREFIX,
+            addr_index::page_offset(page),
+            addr_index::ADDR_PAGE_SIZE,
+            false,
+        );
+        (total, kvs.iter().map(|kv| AddressOutput::deserialize(&kv.1)).collect())
+    }
+
+    // the number of confirmed transactions paying to or spending from pubkey_hash, and the
+    // page-th page of them, the newest first
+    pub fn address_txs(&self, pubkey_hash: &[u8], page: usize) -> (usize, Vec<AddressTx>) {
+        let (total, kvs) = self.blockchain.db.page_with_key_prefix(
+            pubkey_hash,
+            addr_index::ADDR_TX_PREFIX,
+            addr_index::page_offset(page),
+            addr_index::ADDR_PAGE_SIZE,
+            true,
+        );
+        (total, kvs.iter().map(|kv| AddressTx::deserialize(&kv.1)).collect())
+    }
+
+    // (spendable, immature coinbase) value of the outputs locked with pubkey_hash
+    pub fn balance(&self, pubkey_hash: &[u8]) -> (isize, isize) {
+        let (mut balance, mut immature) = (0, 0);
+        let spend_height = self.blockchain.get_best_height() + 1;
+        for utxo in self.address_outputs(pubkey_hash) {
+            if utxo.is_mature(spend_height) {
+                balance += utxo.output.value;
+            } else {
+                immature += utxo.output.value;
             }
         }
-        utxos
+        (balance, immature)
     }
 
     pub fn utxo(&self, txid_in: &[u8]) -> Option<TXOutputs> {
@@ -91,49 +158,134 @@ impl UTXOSet {
             .map(|out_bytes| TXOutputs::deserialize_outputs(&out_bytes))
     }
 
+    // sum of all unspent outputs
+    pub fn total_value(&self) -> isize {
+        let db = &self.blockchain.db.clone();
+        let kvs = db.get_all_with_prefix(UTXO_BLOCK_PREFIX);
+        kvs.iter()
+            .map(|kv| {
+                let outs = TXOutputs::deserialize_outputs(&kv.1);
+                outs.outputs.values().map(|out| out.value).sum::<isize>()
+            })
+            .sum()
+    }
+
     pub fn count_transactions(&self) -> usize {
         let db = &self.blockchain.db.clone();
         let kvs = db.get_all_with_prefix(UTXO_BLOCK_PREFIX);
         kvs.len()
     }
 
-    pub fn reindex(&self) {
-        let db = self.blockchain.db.clone();
-        let kvs = db.get_all_with_prefix(UTXO_BLOCK_PREFIX);
-        if kvs.len() == 0 {
-            warn!(LOG, "no utxo in db");
+    // the hash of the block the utxo set was built up to
+    pub fn best_block(&self) -> Option<Vec<u8>> {
+        self.blockchain.db.get_with_prefix(UTXO_BEST_KEY, *LAST_BLOCK_HASH_PREFIX)
+    }
+
+    // a utxo set that doesn't match the tip was left by a crash before blocks were connected
+    // in one batch, or by an older version, it's rebuilt
+    pub fn recover(&self) -> Result<(), String> {
+        let tip = self.blockchain.get_tip();
+        match self.best_block() {
+            Some(ref best) if util::compare_slice_u8(best, &tip) => Ok(()),
+            best => {
+                warn!(
+                    LOG,
+                    "utxo set at {:?} but the tip is {}, reindex the utxo set",
+                    best.map(util::encode_hex),
+                    util::encode_hex(&tip)
+                );
+                self.reindex()
+            }
         }
-        for kv in &kvs {
-            db.delete(&kv.0, UTXO_BLOCK_PREFIX);
+    }
+
+    // rebuilds the utxo set and the address index from the best chain in one batch
+    pub fn reindex(&self) -> Result<(), String> {
+        // the transactions of the pruned blocks are gone
+        if self.blockchain.pruned_height() > 0 {
+            return Err("can't reindex a pruned chain".to_owned());
+        }
+        let db = self.blockchain.db.clone();
+        let mut batch = DBBatch::default();
+        let prefixes = [
+            UTXO_BLOCK_PREFIX,
+            addr_index::ADDR_UTXO_PREFIX,
+            addr_index::ADDR_TX_PREFIX,
+        ];
+        for prefix in &prefixes {
+            for kv in db.get_all_with_prefix(prefix) {
+                batch.delete(&kv.0, prefix);
+            }
         }
 
-        let utxos = self.blockchain.find_utxo();
-        if utxos.is_none() {
+        let mut update = IndexUpdate::default();
+        let utxos = self.blockchain.find_utxo().unwrap_or_default();
+        if utxos.is_empty() {
             warn!(LOG, "all output are spend");
-            return;
         }
-
-        for kv in &utxos.unwrap() {
-            info!(LOG, "unspend utxo: {}", &util::encode_hex(&kv.0));
-            db.put_with_prefix(
-                &util::decode_hex(&kv.0),
-                &TXOutputs::serialize(&kv.1),
-                UTXO_BLOCK_PREFIX,
-            );
+        for (txid, outs) in &utxos {
+            info!(LOG, "unspend utxo: {}", txid);
+            let txid = util::decode_hex(txid);
+            batch.put_with_prefix(&txid, &TXOutputs::serialize(outs), UTXO_BLOCK_PREFIX);
+            for (out_idx, out) in &*outs.outputs {
+                let utxo = AddressOutput {
+                    txid: txid.clone(),
+                    vout: *out_idx,
+                    output: out.clone(),
+                    height: outs.height,
+                    is_coinbase: outs.is_coinbase,
+                };
+                update.utxo_puts.insert(
+                    addr_index::utxo_key(&out.pub_key_hash, &txid, *out_idx),
+                    utxo.serialize(),
+                );
+            }
+        }
+        let best_height = self.blockchain.get_best_height();
+        for block in self.blockchain.blocks(0..best_height + 1) {
+            update.tx_puts.extend(IndexUpdate::connect(&block, &[]).tx_puts);
+            // the tip may have moved to blocks never connected before
+            let connected = [BlockStatus::Connected as u8];
+            batch.put_with_prefix(&block.hash, &connected, *BLOCK_STATUS_PREFIX);
         }
+        update.write(&mut batch);
+        batch.put_with_prefix(UTXO_BEST_KEY, &self.blockchain.get_tip(), *LAST_BLOCK_HASH_PREFIX);
+        db.write(batch);
+        Ok(())
     }
 
     // 增加新块，新块的交易输入可能包含了当前的“未花费”输出，这些输出需要清理掉
-    pub fn update(&self, block: &block::Block) {
+    // the spent outputs are kept in the undo record of the block, see disconnect. The changes
+    // are collected in batch
+    pub fn update(&self, block: &block::Block, batch: &mut DBBatch) {
         assert_eq!(self.blockchain.get_block(&block.hash).is_some(), true);
         let db = self.blockchain.db.clone();
+        let mut undo = BlockUndo::default();
         for tx in &block.transactions {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
                     // store the unspend outputs
-                    let mut update_outs = TXOutputs::new(HashMap::new());
-                    let out_bytes = db.get_with_prefix(&vin.txid, UTXO_BLOCK_PREFIX).unwrap();
+                    let out_bytes =
+                        batch.get_with_prefix(&db, &vin.txid, UTXO_BLOCK_PREFIX).unwrap();
                     let outputs = TXOutputs::deserialize_outputs(&out_bytes);
+                    // the block is validated, a missing output would leave the undo record short
+                    let out = match outputs.outputs.get(&vin.vout) {
+                        Some(out) => out.clone(),
+                        None => panic!(
+                            "output {}:{} is not in the utxo set",
+                            util::encode_hex(&vin.txid),
+                            vin.vout
+                        ),
+                    };
+                    undo.spent.push(SpentOutput {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        output: out,
+                        height: outputs.height,
+                        is_coinbase: outputs.is_coinbase,
+                    });
+                    let mut update_outs =
+                        TXOutputs::new(HashMap::new(), outputs.height, outputs.is_coinbase);
 
                     for (out_idx, out) in &*outputs.outputs {
                         if *out_idx != vin.vout {
@@ -143,11 +295,11 @@ impl UTXOSet {
                     if update_outs.outputs.len() == 0 {
                         // the txid's outputs all spend, delete it from db
                         debug!(LOG, "delete old utxo {}", util::encode_hex(&vin.txid));
-                        db.delete(&vin.txid, UTXO_BLOCK_PREFIX);
+                        batch.delete(&vin.txid, UTXO_BLOCK_PREFIX);
                     } else {
                         // update the outputs
                         debug!(LOG, "update utxo {}", util::encode_hex(&vin.txid));
-                        db.put_with_prefix(
+                        batch.put_with_prefix(
                             &vin.txid,
                             &TXOutputs::serialize(&update_outs),
                             UTXO_BLOCK_PREFIX,
@@ -156,18 +308,139 @@ impl UTXOSet {
                 }
             }
 
-            let mut new_outputs = TXOutputs::new(HashMap::new());
+            let mut new_outputs = TXOutputs::new(HashMap::new(), block.height, tx.is_coinbase());
             let mut out_idx = 0;
             for out in &*tx.vout {
                 new_outputs.outputs.insert(out_idx, out.clone());
                 out_idx += 1;
             }
             //debug!(LOG, "增加新的UTXO {}", util::encode_hex(&tx.id));
-            db.put_with_prefix(
+            batch.put_with_prefix(
                 &tx.id,
                 &TXOutputs::serialize(&new_outputs),
                 UTXO_BLOCK_PREFIX,
             );
         }
+        batch.put_with_prefix(&block.hash, &undo.serialize(), UNDO_PREFIX);
+        IndexUpdate::connect(block, &undo.spent).write(batch);
+        batch.put_with_prefix(UTXO_BEST_KEY, &block.hash, *LAST_BLOCK_HASH_PREFIX);
+    }
+
+    // undo update, block must be the tip of the utxo set. The outputs created by the block are
+    // removed and the ones it spent are restored from its undo record
+    pub fn disconnect(&self, block: &block::Block, batch: &mut DBBatch) -> Result<(), String> {
+        let db = self.blockchain.db.clone();
+        let mut undo = match batch.get_with_prefix(&db, &block.hash, UNDO_PREFIX) {
+            Some(data) => BlockUndo::deserialize(&data),
+            None => return Err(format!("no undo data of block {}", util::encode_hex(&block.hash))),
+        };
+        let spent = undo.spent.clone();
+
+        // later transactions may spend the outputs of earlier ones in the same block
+        for tx in block.transactions.iter().rev() {
+            batch.delete(&tx.id, UTXO_BLOCK_PREFIX);
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.vin.iter().rev() {
+                let spent = match undo.spent.pop() {
+                    Some(spent) => spent,
+                    None => {
+                        return Err(format!(
+                            "undo data of block {} is short",
+                            util::encode_hex(&block.hash)
+                        ))
+                    }
+                };
+                debug_assert!(util::compare_slice_u8(&spent.txid, &vin.txid));
+                let mut outputs = batch
+                    .get_with_prefix(&db, &spent.txid, UTXO_BLOCK_PREFIX)
+                    .map(|out_bytes| TXOutputs::deserialize_outputs(&out_bytes))
+                    .unwrap_or_else(|| {
+                        TXOutputs::new(HashMap::new(), spent.height, spent.is_coinbase)
+                    });
+                outputs.outputs.insert(spent.vout, spent.output);
+                batch.put_with_prefix(
+                    &spent.txid,
+                    &TXOutputs::serialize(&outputs),
+                    UTXO_BLOCK_PREFIX,
+                );
+            }
+        }
+        batch.delete(&block.hash, UNDO_PREFIX);
+        IndexUpdate::disconnect(block, &spent).write(batch);
+        let parent = &block.header.prev_block_hash;
+        batch.put_with_prefix(UTXO_BEST_KEY, parent, *LAST_BLOCK_HASH_PREFIX);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use self::tempdir::TempDir;
+    use addr_index::{ADDR_TX_PREFIX, ADDR_UTXO_PREFIX};
+    use blockchain::tests::{child, coinbase, new_chain};
+    use verify;
+
+    // spends vout of prev to [tag; 20], the signature isn't checked when connecting
+    fn spend(prev: &Transaction, vout: isize, tag: u8) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(prev.id.clone(), vout, vec![], vec![tag])],
+            vout: vec![TXOutput {
+                value: prev.vout[vout as usize].value,
+                pub_key_hash: vec![tag; 20],
+            }],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    fn utxos_of(bc: &BlockChain) -> HashMap<String, TXOutputs> {
+        bc.db
+            .get_all_with_prefix(UTXO_BLOCK_PREFIX)
+            .into_iter()
+            .map(|(txid, outs)| (util::encode_hex(&txid), TXOutputs::deserialize_outputs(&outs)))
+            .collect()
+    }
+
+    fn addr_index_of(bc: &BlockChain) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = bc.db.get_all_with_prefix(ADDR_UTXO_PREFIX);
+        entries.extend(bc.db.get_all_with_prefix(ADDR_TX_PREFIX));
+        entries
+    }
+
+    #[test]
+    fn connect_disconnect() {
+        let dir = TempDir::new("utxo_undo").unwrap();
+        let bc = Arc::new(new_chain(&dir));
+        let utxos = UTXOSet::new(bc.clone());
+        utxos.reindex().unwrap();
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+        let before = utxos_of(&bc);
+        let index_before = addr_index_of(&bc);
+
+        // the second transaction spends an output of the first in the same block
+        let first = spend(&genesis.transactions[0], 0, 5);
+        let second = spend(&first, 0, 6);
+        let block = child(&genesis, vec![coinbase(1, 1), first.clone(), second.clone()]);
+        bc.add_block(&block).unwrap();
+        bc.connect_block(&block, &utxos);
+        let connected = utxos_of(&bc);
+        let genesis_txid = util::encode_hex(&genesis.transactions[0].id);
+        assert!(!connected.contains_key(&genesis_txid));
+        assert!(!connected.contains_key(&util::encode_hex(&first.id)));
+        assert!(connected.contains_key(&util::encode_hex(&second.id)));
+        assert!(verify::compare_utxos(&connected, &bc.find_utxo().unwrap()).is_empty());
+
+        bc.disconnect_block(&block, &utxos).unwrap();
+        assert_eq!(bc.get_tip(), genesis.hash);
+        assert_eq!(utxos.best_block(), Some(genesis.hash.clone()));
+        assert!(verify::compare_utxos(&utxos_of(&bc), &before).is_empty());
+        assert_eq!(addr_index_of(&bc), index_before);
+        assert!(bc.db.get_with_prefix(&block.hash, UNDO_PREFIX).is_none());
     }
 }
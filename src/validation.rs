@@ -0,0 +1,526 @@
+use super::block::Block;
+use super::chain_params;
+use super::consensus;
+use super::transaction::{self, Transaction, TXOutput};
+use super::utxo_set::UTXOSet;
+use super::util;
+
+use std::collections::{HashMap, HashSet};
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum RejectReason {
+        UnknownParent { hash: String } {
+            display("block's parent {} not found", hash)
+        }
+        InvalidParent { hash: String } {
+            display("block's parent {} has invalid transactions", hash)
+        }
+        BadHeight { height: isize, expected: isize } {
+            display("block's height:'{} != {}' not follow the parent", height, expected)
+        }
+        TimeTooOld { timestamp: i32, min: i32 } {
+            display("block's time {} is not after the median time past, min {}", timestamp, min)
+        }
+        TimeTooNew { timestamp: i32, max: i32 } {
+            display("block's time {} is too far in the future, max {}", timestamp, max)
+        }
+        BadBits { bits: u32, expected: u32 } {
+            display("block's bits:'{:x} != {:x}' not match the retarget", bits, expected)
+        }
+        BadProofOfWork {
+            display("block's proof of work is invalid")
+        }
+        BadSeal { height: isize } {
+            display("block {}'s authority seal is invalid", height)
+        }
+        CheckpointMismatch { height: isize, hash: String, expected: String } {
+            display("block {} at height {} conflicts with checkpoint {}", hash, height, expected)
+        }
+        ForkBeforeCheckpoint { height: isize, checkpoint: isize } {
+            display("fork at height {} is before the checkpoint at height {}", height, checkpoint)
+        }
+        ForkBeforePruned { height: isize, pruned_height: isize } {
+            display("fork at height {} is below the pruned height {}", height, pruned_height)
+        }
+        // the utxo set can't follow the reorganization, the block itself may be valid
+        ReorganizeFailed { reason: String } {
+            display("can't reorganize, {}", reason)
+        }
+        BadHash { hash: String, expected: String } {
+            display("block's hash {} != recomputed {}", hash, expected)
+        }
+        BadMerkleRoot { root: String, expected: String } {
+            display("block's merkle root {} != recomputed {}", root, expected)
+        }
+        OversizedBlock { size: usize, max: usize } {
+            display("block size tool big, more than max size, {} > {}", size, max)
+        }
+        FirstNotCoinbase {
+            display("the first transaction is not a coinbase")
+        }
+        MultipleCoinbase { txid: String } {
+            display("transaction {} is a second coinbase", txid)
+        }
+        BadTransactionId { txid: String } {
+            display("transaction {} id not match its hash", txid)
+        }
+        EmptyTransaction { txid: String } {
+            display("transaction {} has no inputs or outputs", txid)
+        }
+        NegativeOutput { txid: String } {
+            display("transaction {} has a negative output", txid)
+        }
+        OutputTooLarge { txid: String, value: isize, max: isize } {
+            display("transaction {} has an output {} over the max supply {}", txid, value, max)
+        }
+        ValueOverflow { txid: String } {
+            display("the values of transaction {} overflow", txid)
+        }
+        DoubleSpend { txid: String, vout: isize } {
+            display("output {}:{} is spent twice in the block", txid, vout)
+        }
+        MissingInput { txid: String, vout: isize } {
+            display("output {}:{} not found in the utxo set", txid, vout)
+        }
+        BadSignature { txid: String } {
+            display("transaction {} has an invalid signature", txid)
+        }
+        ImmatureCoinbase { txid: String, height: isize } {
+            display("coinbase {} of block {} is not mature yet", txid, height)
+        }
+        BadCoinbaseValue { value: isize, max: isize } {
+            display("coinbase claims {} more than subsidy and fees {}", value, max)
+        }
+        InsufficientInputs { txid: String, input: isize, output: isize } {
+            display("transaction {} spends {} more than its inputs {}", txid, output, input)
+        }
+    }
+}
+
+impl RejectReason {
+    // the peer follows a chain the checkpoints rule out
+    pub fn violates_checkpoint(&self) -> bool {
+        match *self {
+            RejectReason::CheckpointMismatch { .. } |
+            RejectReason::ForkBeforeCheckpoint { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+// the block at a checkpoint height must be the checkpointed one
+pub fn check_checkpoint(height: isize, hash: &[u8]) -> Result<(), RejectReason> {
+    check_checkpoint_in(&chain_params::params(), height, hash)
+}
+
+fn check_checkpoint_in(
+    params: &chain_params::ChainParams,
+    height: isize,
+    hash: &[u8],
+) -> Result<(), RejectReason> {
+    match params.checkpoint(height) {
+        // the hashes of a chain spec may be upper case
+        Some(checkpoint) if !checkpoint.matches(hash) => {
+            Err(RejectReason::CheckpointMismatch {
+                height: height,
+                hash: util::encode_hex(hash),
+                expected: checkpoint.hash.clone(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// a block's time must be after the median time of the previous MEDIAN_TIME_SPAN blocks
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// and not more than MAX_FUTURE_BLOCK_TIME seconds after the network adjusted time
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+// median of the timestamps of the last MEDIAN_TIME_SPAN blocks, newest first
+pub fn median_time_past(timestamps: &[i32]) -> i32 {
+    let mut timestamps: Vec<i32> = timestamps.iter().take(MEDIAN_TIME_SPAN).cloned().collect();
+    if timestamps.is_empty() {
+        return 0;
+    }
+    timestamps.sort();
+    timestamps[timestamps.len() / 2]
+}
+
+// prev_timestamps are the timestamps of the parent and its ancestors, newest first
+pub fn check_block_time(
+    timestamp: i32,
+    prev_timestamps: &[i32],
+    adjusted_time: i64,
+) -> Result<(), RejectReason> {
+    let median = median_time_past(prev_timestamps);
+    if timestamp <= median {
+        return Err(RejectReason::TimeTooOld {
+            timestamp: timestamp,
+            min: median + 1,
+        });
+    }
+    let max = adjusted_time + MAX_FUTURE_BLOCK_TIME;
+    if i64::from(timestamp) > max {
+        return Err(RejectReason::TimeTooNew {
+            timestamp: timestamp,
+            max: max as i32,
+        });
+    }
+    Ok(())
+}
+
+// checks need nothing but the block itself
+pub fn check_block(block: &Block) -> Result<(), RejectReason> {
+    let hash = block.header.hash();
+    if !util::compare_slice_u8(&hash, &block.hash) {
+        return Err(RejectReason::BadHash {
+            hash: util::encode_hex(&block.hash),
+            expected: util::encode_hex(&hash),
+        });
+    }
+    consensus::engine().verify_seal(block)?;
+
+    let size = Block::serialize(block).len();
+    let max_block_size = chain_params::params().max_block_size;
+    if size > max_block_size {
+        return Err(RejectReason::OversizedBlock {
+            size: size,
+            max: max_block_size,
+        });
+    }
+
+    check_transactions(&block.transactions)?;
+
+    let merkle_root = block.hash_transactions();
+    if !util::compare_slice_u8(&merkle_root, &block.header.merkle_root) {
+        return Err(RejectReason::BadMerkleRoot {
+            root: util::encode_hex(&block.header.merkle_root),
+            expected: util::encode_hex(&merkle_root),
+        });
+    }
+    Ok(())
+}
+
+// exactly one coinbase in the first place, well formed transactions, no output is spent twice
+pub fn check_transactions(transactions: &[Transaction]) -> Result<(), RejectReason> {
+    if transactions.is_empty() || !transactions[0].is_coinbase() {
+        return Err(RejectReason::FirstNotCoinbase);
+    }
+
+    let mut spent = HashSet::new();
+    for (idx, tx) in transactions.iter().enumerate() {
+        let txid = util::encode_hex(&tx.id);
+        if idx > 0 && tx.is_coinbase() {
+            return Err(RejectReason::MultipleCoinbase { txid: txid });
+        }
+        if !util::compare_slice_u8(&tx.id, &tx.hash()) {
+            return Err(RejectReason::BadTransactionId { txid: txid });
+        }
+        if tx.vin.is_empty() || tx.vout.is_empty() {
+            return Err(RejectReason::EmptyTransaction { txid: txid });
+        }
+        if tx.vout.iter().any(|out| out.value < 0) {
+            return Err(RejectReason::NegativeOutput { txid: txid });
+        }
+        let max = chain_params::params().max_supply;
+        if let Some(out) = tx.vout.iter().find(|out| out.value > max) {
+            return Err(RejectReason::OutputTooLarge {
+                txid: txid,
+                value: out.value,
+                max: max,
+            });
+        }
+        if tx.is_coinbase() {
+            continue;
+        }
+        for vin in &tx.vin {
+            if !spent.insert((vin.txid.clone(), vin.vout)) {
+                return Err(RejectReason::DoubleSpend {
+                    txid: util::encode_hex(&vin.txid),
+                    vout: vin.vout,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// checks the inputs against the utxo set of the parent and the coinbase against the subsidy
+// and fees. Returns the fees of the block.
+pub fn check_block_transactions(block: &Block, utxos: &UTXOSet) -> Result<isize, RejectReason> {
+    let fees = check_inputs(&block.transactions, utxos, block.height)?;
+    let coinbase = &block.transactions[0];
+    let overflow = || RejectReason::ValueOverflow { txid: util::encode_hex(&coinbase.id) };
+    let value = output_value(coinbase).ok_or_else(overflow)?;
+    let max = transaction::block_subsidy(block.height).checked_add(fees).ok_or_else(overflow)?;
+    if value > max {
+        return Err(RejectReason::BadCoinbaseValue {
+            value: value,
+            max: max,
+        });
+    }
+    Ok(fees)
+}
+
+// mempool acceptance, a transaction must not spend a coinbase that is immature in the next block.
+// Inputs not in the utxo set may spend other mempool transactions, which are never coinbases
+pub fn check_maturity(
+    tx: &Transaction,
+    utxos: &UTXOSet,
+    height: isize,
+) -> Result<(), RejectReason> {
+    for vin in &tx.vin {
+        if let Some(outs) = utxos.utxo(&vin.txid) {
+            if !outs.is_mature(height) {
+                return Err(RejectReason::ImmatureCoinbase {
+                    txid: util::encode_hex(&vin.txid),
+                    height: outs.height,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// checks the inputs of the transactions spent by a block at height against the utxo set, an
+// input may also spend an output created by an earlier transaction in the list. Returns the fees
+// of the transactions.
+pub fn check_inputs(
+    transactions: &[Transaction],
+    utxos: &UTXOSet,
+    height: isize,
+) -> Result<isize, RejectReason> {
+    // (txid, vout) => (output, created by a coinbase), created by the transactions
+    let mut block_outputs: HashMap<(Vec<u8>, isize), (TXOutput, bool)> = HashMap::new();
+    let mut fees: isize = 0;
+
+    for tx in transactions {
+        let txid = util::encode_hex(&tx.id);
+        if !tx.is_coinbase() {
+            let mut prev_outs: HashMap<isize, TXOutput> = HashMap::new();
+            let mut input: isize = 0;
+            for (idx, vin) in tx.vin.iter().enumerate() {
+                let prev_out = match block_outputs.remove(&(vin.txid.clone(), vin.vout)) {
+                    Some((_, true)) => {
+                        return Err(RejectReason::ImmatureCoinbase {
+                            txid: util::encode_hex(&vin.txid),
+                            height: height,
+                        })
+                    }
+                    Some((out, false)) => Some(out),
+                    None => {
+                        match utxos.utxo(&vin.txid) {
+                            Some(ref outs) if !outs.is_mature(height) => {
+                                return Err(RejectReason::ImmatureCoinbase {
+                                    txid: util::encode_hex(&vin.txid),
+                                    height: outs.height,
+                                })
+                            }
+                            Some(outs) => outs.outputs.get(&vin.vout).cloned(),
+                            None => None,
+                        }
+                    }
+                };
+                let prev_out = match prev_out {
+                    Some(out) => out,
+                    None => {
+                        return Err(RejectReason::MissingInput {
+                            txid: util::encode_hex(&vin.txid),
+                            vout: vin.vout,
+                        })
+                    }
+                };
+                input = match input.checked_add(prev_out.value) {
+                    Some(input) => input,
+                    None => return Err(RejectReason::ValueOverflow { txid: txid }),
+                };
+                prev_outs.insert(idx as isize, prev_out);
+            }
+
+            if !tx.verify(&prev_outs) {
+                return Err(RejectReason::BadSignature { txid: txid });
+            }
+            let output = match output_value(tx) {
+                Some(output) => output,
+                None => return Err(RejectReason::ValueOverflow { txid: txid }),
+            };
+            if input < output {
+                return Err(RejectReason::InsufficientInputs {
+                    txid: txid,
+                    input: input,
+                    output: output,
+                });
+            }
+            fees = match fees.checked_add(input - output) {
+                Some(fees) => fees,
+                None => return Err(RejectReason::ValueOverflow { txid: txid }),
+            };
+        }
+
+        for (idx, out) in tx.vout.iter().enumerate() {
+            block_outputs.insert(
+                (tx.id.clone(), idx as isize),
+                (out.clone(), tx.is_coinbase()),
+            );
+        }
+    }
+    Ok(fees)
+}
+
+// the sum of the outputs of tx, None if it overflows
+fn output_value(tx: &Transaction) -> Option<isize> {
+    tx.vout.iter().fold(Some(0), |sum, out| sum.and_then(|sum| sum.checked_add(out.value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::TXInput;
+    use proof_of_work::pow_limit_bits;
+
+    fn new_tx(vin: Vec<TXInput>, value: isize) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vin,
+            vout: vec![TXOutput { value: value, pub_key_hash: vec![1; 20] }],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    fn coinbase() -> Transaction {
+        new_tx(vec![TXInput::new(vec![], -1, vec![], b"coinbase".to_vec())], 10)
+    }
+
+    #[test]
+    fn upper_case_checkpoint() {
+        let mut params = chain_params::ChainParams::main();
+        params.checkpoints = vec![
+            chain_params::Checkpoint {
+                height: 5,
+                hash: "00FFAB".to_owned(),
+            },
+        ];
+        assert_eq!(check_checkpoint_in(&params, 5, &[0x00, 0xff, 0xab]), Ok(()));
+        assert_eq!(check_checkpoint_in(&params, 4, &[0x01]), Ok(()));
+        let err = check_checkpoint_in(&params, 5, &[0x01]).unwrap_err();
+        assert!(err.violates_checkpoint());
+    }
+
+    #[test]
+    fn coinbase_must_be_first_and_unique() {
+        let spend = new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 1);
+        assert_eq!(
+            check_transactions(&[spend.clone(), coinbase()]),
+            Err(RejectReason::FirstNotCoinbase)
+        );
+        assert!(match check_transactions(&[coinbase(), coinbase()]) {
+            Err(RejectReason::MultipleCoinbase { .. }) => true,
+            _ => false,
+        });
+        assert_eq!(check_transactions(&[coinbase(), spend]), Ok(()));
+    }
+
+    #[test]
+    fn double_spend_in_block() {
+        let a = new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 1);
+        let b = new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 2);
+        assert_eq!(
+            check_transactions(&[coinbase(), a, b]),
+            Err(RejectReason::DoubleSpend {
+                txid: util::encode_hex(&[7; 32]),
+                vout: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn huge_outputs() {
+        let mut spend = new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 1);
+        spend.vout = [isize::max_value(), isize::max_value(), 12]
+            .iter()
+            .map(|value| TXOutput { value: *value, pub_key_hash: vec![1; 20] })
+            .collect();
+        spend.id = spend.hash();
+        assert_eq!(output_value(&spend), None);
+        let txid = util::encode_hex(&spend.id);
+        assert_eq!(
+            check_transactions(&[coinbase(), spend]),
+            Err(RejectReason::OutputTooLarge {
+                txid: txid,
+                value: isize::max_value(),
+                max: chain_params::params().max_supply,
+            })
+        );
+    }
+
+    #[test]
+    fn tampered_transaction_id() {
+        let mut spend = new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 1);
+        spend.vout[0].value = 100;
+        assert!(match check_transactions(&[coinbase(), spend]) {
+            Err(RejectReason::BadTransactionId { .. }) => true,
+            _ => false,
+        });
+    }
+
+    // timestamps of a synthetic chain, one block every 60s from 1000, newest first
+    fn chain_times(len: i32) -> Vec<i32> {
+        (0..len).rev().map(|h| 1000 + h * 60).collect()
+    }
+
+    #[test]
+    fn median_time_past_rule() {
+        // tip at 1600, the median of the last 11 blocks is 1300
+        let times = &chain_times(11);
+        assert_eq!(median_time_past(times), 1300);
+
+        // earlier than the parent but after the median is fine
+        assert_eq!(check_block_time(1301, times, 1600), Ok(()));
+        assert_eq!(
+            check_block_time(1300, times, 1600),
+            Err(RejectReason::TimeTooOld { timestamp: 1300, min: 1301 })
+        );
+
+        // a slow block is never too old
+        assert_eq!(check_block_time(1600 + 3600, times, 1600 + 3600), Ok(()));
+    }
+
+    #[test]
+    fn median_time_past_out_of_order() {
+        // clock skewed miners, median of [5, 1, 9, 3, 7] is 5
+        assert_eq!(median_time_past(&[5, 1, 9, 3, 7]), 5);
+        // only the last MEDIAN_TIME_SPAN blocks count
+        let mut times = vec![100; MEDIAN_TIME_SPAN];
+        times.extend(vec![1_000_000; 20]);
+        assert_eq!(median_time_past(&times), 100);
+        // genesis has no ancestor
+        assert_eq!(median_time_past(&[]), 0);
+    }
+
+    #[test]
+    fn future_drift_rule() {
+        let times = chain_times(11);
+        let now = 2000;
+        let max = now + MAX_FUTURE_BLOCK_TIME;
+        assert_eq!(check_block_time(max as i32, &times, now), Ok(()));
+        assert_eq!(
+            check_block_time(max as i32 + 1, &times, now),
+            Err(RejectReason::TimeTooNew { timestamp: max as i32 + 1, max: max as i32 })
+        );
+    }
+
+    #[test]
+    fn tampered_merkle_root() {
+        let mut block = Block::new(vec![coinbase()], vec![], 0, pow_limit_bits());
+        assert_eq!(check_block(&block), Ok(()));
+
+        // the header hash still matches, the transactions no longer do
+        block.transactions.push(new_tx(vec![TXInput::new(vec![7; 32], 0, vec![], vec![])], 1));
+        assert!(match check_block(&block) {
+            Err(RejectReason::BadMerkleRoot { .. }) => true,
+            _ => false,
+        });
+    }
+}
@@ -0,0 +1,406 @@
+use super::addr_index;
+use super::block::Block;
+use super::blockchain::{BlockChain, BlockStatus};
+use super::consensus;
+use super::transaction::{TXOutput, TXOutputs};
+use super::util;
+use super::log::*;
+use super::utxo_set::{BlockUndo, UTXOSet, UNDO_PREFIX, UTXO_BLOCK_PREFIX};
+
+use std::collections::{BTreeSet, HashMap};
+
+// every level checks what the lower ones do and:
+// 0: the tip pointer, the linkage of the blocks, the height index and the stored headers
+// 1: the proof of work or seal, the bits and the merkle roots
+// 2: the signatures of the transactions
+// 3: the stored utxo set against one rebuilt from the blocks
+// 4: the address index against the rebuilt utxo set
+// The newest depth blocks are walked, a depth of 0 walks the whole chain
+pub const MAX_VERIFY_LEVEL: usize = 4;
+
+// one thing found wrong, height and hash are set when it's about a block
+#[derive(Serialize, Debug, Clone)]
+pub struct Problem {
+    pub height: Option<isize>,
+    pub hash: Option<String>,
+    pub problem: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VerifyReport {
+    pub level: usize,
+    pub depth: isize,
+    pub checked_blocks: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn chain_problem(&mut self, problem: String) {
+        self.problems.push(Problem {
+            height: None,
+            hash: None,
+            problem: problem,
+        });
+    }
+
+    fn block_problem(&mut self, height: isize, hash: &[u8], problem: String) {
+        self.problems.push(Problem {
+            height: Some(height),
+            hash: Some(util::encode_hex(hash)),
+            problem: problem,
+        });
+    }
+}
+
+pub fn verify_chain(bc: &BlockChain, utxos: &UTXOSet, level: usize, depth: isize) -> VerifyReport {
+    let mut report = VerifyReport {
+        level: level,
+        depth: depth,
+        checked_blocks: 0,
+        problems: vec![],
+    };
+    let tip = bc.get_tip();
+    if !verify_tip(bc, &tip, &mut report) {
+        return report;
+    }
+    verify_blocks(bc, &tip, level, depth, &mut report);
+    if level >= 3 {
+        verify_utxos(bc, utxos, &tip, level, &mut report);
+    }
+    report
+}
+
+// the tip pointer under last_block must be a connected block on top of the height index
+fn verify_tip(bc: &BlockChain, tip: &[u8], report: &mut VerifyReport) -> bool {
+    match bc.stored_tip() {
+        Some(ref stored) if util::compare_slice_u8(stored, tip) => {}
+        Some(stored) => report.chain_problem(format!(
+            "the tip pointer is {}, the node's tip is {}",
+            util::encode_hex(&stored),
+            util::encode_hex(tip)
+        )),
+        None => report.chain_problem("the tip pointer is missing".to_owned()),
+    }
+    let block = match bc.get_block(tip) {
+        Some(block) => block,
+        None => {
+            let problem = format!("the tip block {} is not stored", util::encode_hex(tip));
+            report.chain_problem(problem);
+            return false;
+        }
+    };
+    if bc.block_status(tip) != Some(BlockStatus::Connected) {
+        report.block_problem(block.height, tip, "the tip is not connected".to_owned());
+    }
+    if bc.block_hash_at(block.height + 1).is_some() {
+        let problem = "the height index goes past the tip".to_owned();
+        report.block_problem(block.height, tip, problem);
+    }
+    true
+}
+
+// walks the best chain back from the tip by the parent hashes
+fn verify_blocks(
+    bc: &BlockChain,
+    tip: &[u8],
+    level: usize,
+    depth: isize,
+    report: &mut VerifyReport,
+) {
+    let pruned_height = bc.pruned_height();
+    let mut hash = tip.to_vec();
+    let mut expected_height = None;
+    let mut unchecked = 0;
+    loop {
+        let block = match bc.get_block(&hash) {
+            Some(block) => block,
+            None => {
+                let height = expected_height.unwrap_or(-1);
+                report.block_problem(height, &hash, "the block is not stored".to_owned());
+                return;
+            }
+        };
+        let height = block.height;
+        let expected = expected_height.unwrap_or(height);
+        if height != expected {
+            report.block_problem(height, &hash, format!("expected at height {}", expected));
+        }
+        if !util::compare_slice_u8(&block.header.hash(), &hash) {
+            report.block_problem(height, &hash, "the hash doesn't match the header".to_owned());
+        }
+        if bc.block_hash_at(height).map_or(true, |indexed| indexed != hash) {
+            let problem = "the height index points to another block".to_owned();
+            report.block_problem(height, &hash, problem);
+        }
+        let header = bc.get_header(&hash).map(|header| header.hash());
+        if header.map_or(true, |header| header != hash) {
+            let problem = "the stored header doesn't match the block".to_owned();
+            report.block_problem(height, &hash, problem);
+        }
+
+        if level >= 1 {
+            if block.header.bits != bc.expected_bits(&block) {
+                report.block_problem(height, &hash, "bad bits".to_owned());
+            }
+            if let Err(e) = consensus::engine().verify_seal(&block) {
+                report.block_problem(height, &hash, e.to_string());
+            }
+            if !block.is_pruned() && block.hash_transactions() != block.header.merkle_root {
+                report.block_problem(height, &hash, "bad merkle root".to_owned());
+            }
+        }
+        if level >= 2 && !verify_signatures(bc, &block, report) {
+            unchecked += 1;
+        }
+        if block.is_pruned() && height >= pruned_height {
+            let problem = "the transactions are missing above the pruned height".to_owned();
+            report.block_problem(height, &hash, problem);
+        }
+        report.checked_blocks += 1;
+
+        let done = height == 0 || (depth > 0 && report.checked_blocks as isize >= depth);
+        if done {
+            if height == 0 && !block.header.prev_block_hash.is_empty() {
+                report.block_problem(height, &hash, "the genesis block has a parent".to_owned());
+            }
+            if unchecked > 0 {
+                let warning = "blocks have no undo record, their signatures are not checked";
+                warn!(LOG, "{} {}", unchecked, warning);
+            }
+            return;
+        }
+        hash = block.header.prev_block_hash;
+        expected_height = Some(height - 1);
+    }
+}
+
+// the outputs spent by the block are in its undo record, with the ones created and spent in
+// the block. Blocks connected by a reindex have none, their outputs are looked up in the
+// transaction index, or they are not checked without it. False if they are not checked
+fn verify_signatures(bc: &BlockChain, block: &Block, report: &mut VerifyReport) -> bool {
+    if block.transactions.iter().all(|tx| tx.is_coinbase()) {
+        return true;
+    }
+    let spent: Option<HashMap<(Vec<u8>, isize), TXOutput>> =
+        match bc.db.get_with_prefix(&block.hash, UNDO_PREFIX) {
+            Some(data) => Some(
+                BlockUndo::deserialize(&data)
+                    .spent
+                    .into_iter()
+                    .map(|spent| ((spent.txid, spent.vout), spent.output))
+                    .collect(),
+            ),
+            None if bc.tx_index_enabled() && bc.pruned_height() == 0 => None,
+            None => return false,
+        };
+    for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+        let verified = match spent {
+            Some(ref spent) => {
+                let prev_outs: Option<HashMap<isize, TXOutput>> = tx.vin
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, vin)| {
+                        let prev_out = spent.get(&(vin.txid.clone(), vin.vout));
+                        prev_out.map(|prev_out| (idx as isize, prev_out.clone()))
+                    })
+                    .collect();
+                prev_outs.map_or(false, |prev_outs| tx.verify(&prev_outs))
+            }
+            None => bc.verify_transaction(tx),
+        };
+        if !verified {
+            let txid = util::encode_hex(&tx.id);
+            let problem = format!("bad signature of transaction {}", txid);
+            report.block_problem(block.height, &block.hash, problem);
+        }
+    }
+    true
+}
+
+// the utxo set needs every block to be rebuilt
+fn verify_utxos(
+    bc: &BlockChain,
+    utxos: &UTXOSet,
+    tip: &[u8],
+    level: usize,
+    report: &mut VerifyReport,
+) {
+    if utxos.best_block().map_or(true, |best| best != tip) {
+        report.chain_problem("the utxo set is not at the tip".to_owned());
+    }
+    if bc.pruned_height() > 0 {
+        warn!(LOG, "can't rebuild the utxo set of a pruned chain, skip it");
+        return;
+    }
+    let rebuilt = bc.find_utxo().unwrap_or_default();
+    let stored: HashMap<String, TXOutputs> = bc.db
+        .get_all_with_prefix(UTXO_BLOCK_PREFIX)
+        .into_iter()
+        .map(|(txid, outs)| (util::encode_hex(&txid), TXOutputs::deserialize_outputs(&outs)))
+        .collect();
+    for problem in compare_utxos(&stored, &rebuilt) {
+        report.chain_problem(problem);
+    }
+
+    if level >= 4 {
+        let expected: BTreeSet<Vec<u8>> = rebuilt
+            .iter()
+            .flat_map(|(txid, outs)| {
+                let txid = util::decode_hex(txid);
+                outs.outputs
+                    .iter()
+                    .map(|(vout, out)| addr_index::utxo_key(&out.pub_key_hash, &txid, *vout))
+                    .collect::<Vec<Vec<u8>>>()
+            })
+            .collect();
+        let indexed: BTreeSet<Vec<u8>> = bc.db
+            .get_all_with_prefix(addr_index::ADDR_UTXO_PREFIX)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let missing = expected.difference(&indexed).count();
+        let extra = indexed.difference(&expected).count();
+        if missing > 0 || extra > 0 {
+            report.chain_problem(format!(
+                "the address index misses {} unspent outputs and has {} spent ones",
+                missing,
+                extra
+            ));
+        }
+    }
+}
+
+// the differences of the stored utxo set from the rebuilt one, by txid
+pub fn compare_utxos(
+    stored: &HashMap<String, TXOutputs>,
+    rebuilt: &HashMap<String, TXOutputs>,
+) -> Vec<String> {
+    let txids: BTreeSet<&String> = stored.keys().chain(rebuilt.keys()).collect();
+    let mut problems = vec![];
+    for txid in txids {
+        let problem = match (stored.get(txid), rebuilt.get(txid)) {
+            (Some(_), None) => "is stored but spent",
+            (None, Some(_)) => "is unspent but not stored",
+            (Some(stored), Some(rebuilt)) => {
+                if stored.height != rebuilt.height || stored.is_coinbase != rebuilt.is_coinbase {
+                    "is stored with another height"
+                } else if stored.outputs != rebuilt.outputs {
+                    "is stored with other outputs"
+                } else {
+                    continue;
+                }
+            }
+            (None, None) => continue,
+        };
+        problems.push(format!("utxo {} {}", txid, problem));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use self::tempdir::TempDir;
+    use blockchain::BLOCK_PREFIX;
+    use blockchain::tests::{child, coinbase, new_chain};
+    use transaction::{TXInput, Transaction};
+    use wallet::Wallet;
+
+    use std::sync::Arc;
+
+    fn outputs(vouts: &[isize], height: isize) -> TXOutputs {
+        let outputs = vouts
+            .iter()
+            .map(|vout| (*vout, TXOutput { value: 10, pub_key_hash: vec![1; 20] }))
+            .collect();
+        TXOutputs::new(outputs, height, false)
+    }
+
+    #[test]
+    fn utxo_differences() {
+        let mut stored = HashMap::new();
+        stored.insert("aa".to_owned(), outputs(&[0, 1], 1));
+        stored.insert("bb".to_owned(), outputs(&[0], 2));
+        stored.insert("cc".to_owned(), outputs(&[0], 3));
+        let mut rebuilt = HashMap::new();
+        rebuilt.insert("aa".to_owned(), outputs(&[0, 1], 1));
+        rebuilt.insert("bb".to_owned(), outputs(&[1], 2));
+        rebuilt.insert("dd".to_owned(), outputs(&[0], 4));
+
+        assert!(compare_utxos(&rebuilt, &rebuilt).is_empty());
+        assert_eq!(
+            compare_utxos(&stored, &rebuilt),
+            vec![
+                "utxo bb is stored with other outputs",
+                "utxo cc is stored but spent",
+                "utxo dd is unspent but not stored",
+            ]
+        );
+    }
+
+    fn problems(bc: &BlockChain, level: usize, depth: isize) -> Vec<(Option<isize>, String)> {
+        let mut report = VerifyReport {
+            level: level,
+            depth: depth,
+            checked_blocks: 0,
+            problems: vec![],
+        };
+        verify_blocks(bc, &bc.get_tip(), level, depth, &mut report);
+        report.problems.into_iter().map(|problem| (problem.height, problem.problem)).collect()
+    }
+
+    #[test]
+    fn corrupted_chain() {
+        let dir = TempDir::new("verify_chain").unwrap();
+        let bc = Arc::new(new_chain(&dir));
+        let utxos = UTXOSet::new(bc.clone());
+        utxos.reindex().unwrap();
+        let genesis = bc.get_block(&bc.get_tip()).unwrap();
+
+        // block 2 spends the reward of block 1
+        let (secret_key, pub_key) = Wallet::new_key_pair();
+        let mut reward = coinbase(1, 1);
+        reward.vout[0].pub_key_hash = Wallet::hash_pubkey(&pub_key);
+        reward.id = reward.hash();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(reward.id.clone(), 0, vec![], pub_key)],
+            vout: vec![TXOutput { value: reward.vout[0].value, pub_key_hash: vec![2; 20] }],
+        };
+        tx.id = tx.hash();
+        let prev_outs = vec![(0, reward.vout[0].clone())].into_iter().collect();
+        tx.sign(&secret_key, &prev_outs);
+        let b1 = child(&genesis, vec![reward]);
+        let b2 = child(&b1, vec![coinbase(2, 1), tx.clone()]);
+        for block in &[&b1, &b2] {
+            bc.add_block(block).unwrap();
+            bc.connect_block(block, &utxos);
+        }
+        assert_eq!(problems(&bc, MAX_VERIFY_LEVEL, 0), vec![]);
+        assert!(verify_chain(&bc, &utxos, MAX_VERIFY_LEVEL, 0).is_ok());
+
+        // the spent output doesn't belong to the key of the input
+        let undo_data = bc.db.get_with_prefix(&b2.hash, UNDO_PREFIX).unwrap();
+        let mut undo = BlockUndo::deserialize(&undo_data);
+        undo.spent[0].output.pub_key_hash = vec![3; 20];
+        bc.db.put_with_prefix(&b2.hash, &undo.serialize(), UNDO_PREFIX);
+        let bad_signature = format!("bad signature of transaction {}", util::encode_hex(&tx.id));
+        assert_eq!(problems(&bc, 1, 0), vec![]);
+        assert_eq!(problems(&bc, 2, 0), vec![(Some(2), bad_signature)]);
+        bc.db.put_with_prefix(&b2.hash, &undo_data, UNDO_PREFIX);
+
+        // another block stored under the hash of block 1
+        let other = child(&genesis, vec![coinbase(1, 2)]);
+        bc.db.put_with_prefix(&b1.hash, &Block::serialize(&other), *BLOCK_PREFIX);
+        let mismatch = "the hash doesn't match the header".to_owned();
+        assert_eq!(problems(&bc, 0, 0), vec![(Some(1), mismatch)]);
+        assert_eq!(problems(&bc, 0, 1), vec![]);
+        assert!(!verify_chain(&bc, &utxos, MAX_VERIFY_LEVEL, 0).is_ok());
+    }
+}
@@ -13,14 +13,13 @@ use self::rand::thread_rng;
 
 use super::log::*;
 use super::util;
+use super::chain_params;
 
 use std::collections::HashMap;
 
-const NET_ENV: u8 = 0u8;
-
 pub const ADDRESS_CHECKSUM_LEN: usize = 4;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Wallet {
     pub secret_key: SecretKey,
     #[serde(default)]
@@ -69,7 +68,7 @@ impl Wallet {
     pub fn get_address(&self) -> String {
         // rimpemd160 20bytes
         let mut public_key = Self::hash_pubkey(&util::public_key_to_vec(&self.public_key, false));
-        let version_payload = util::write_u8(NET_ENV);
+        let version_payload = util::write_u8(chain_params::params().address_version);
         // 0x00x1|rimpemd160
         let mut version_payload_clone = version_payload.clone();
         {
@@ -106,8 +105,9 @@ impl Wallet {
             return false;
         }
         let net_env = util::read_u8(&public_key[..1]);
-        if net_env != NET_ENV {
-            warn!(LOG, "address version is valid, {:?}, {:?}", net_env, NET_ENV);
+        let address_version = chain_params::params().address_version;
+        if net_env != address_version {
+            warn!(LOG, "address version is valid, {:?}, {:?}", net_env, address_version);
             return false;
         }
         true